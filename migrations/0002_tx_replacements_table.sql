@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS tx_replacements (
+    original_txid TEXT,
+    replacement_txid TEXT,
+    fee_rate INT,
+    fee INT
+);
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS script_addresses (
+    p2tr_address TEXT,
+    descriptor TEXT
+);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS musig_addresses (
+    bip32_index INT,
+    client_seckey BLOB,
+    client_pubkey BLOB,
+    participants TEXT,
+    aggregate_pubkey BLOB,
+    p2tr_address TEXT,
+    fingerprint TEXT,
+    derivation_path TEXT
+);
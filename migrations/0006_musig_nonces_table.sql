@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS musig_nonces (
+    session_id BLOB,
+    txid TEXT,
+    input_index INT,
+    client_pubkey BLOB,
+    secnonce BLOB,
+    pubnonce BLOB
+);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS cosigned_addresses (
+    bip32_index INT,
+    client_seckey BLOB,
+    client_pubkey BLOB,
+    server_pubkey BLOB,
+    key_id TEXT,
+    aggregate_pubkey BLOB,
+    p2tr_address TEXT,
+    derivation_path TEXT
+);
@@ -0,0 +1,25 @@
+CREATE TABLE IF NOT EXISTS statecoins (
+    statechain_id TEXT,
+    client_seckey BLOB,
+    client_pubkey BLOB,
+    server_pubkey BLOB,
+    aggregate_pubkey BLOB,
+    p2tr_address TEXT,
+    funding_txid TEXT,
+    funding_vout INT,
+    amount INT,
+    status TEXT
+);
+
+CREATE TABLE IF NOT EXISTS statecoin_backup_txs (
+    statechain_id TEXT,
+    tx_n INT,
+    tx BLOB,
+    lock_time INT
+);
+
+CREATE TABLE IF NOT EXISTS statecoin_transfer_keys (
+    client_seckey BLOB,
+    client_pubkey BLOB,
+    backup_address TEXT
+);
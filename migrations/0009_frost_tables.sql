@@ -0,0 +1,27 @@
+CREATE TABLE IF NOT EXISTS frost_dkg_sessions (
+    session_id TEXT,
+    identifier INT,
+    min_signers INT,
+    max_signers INT,
+    round1_secret TEXT,
+    round1_packages TEXT,
+    round2_secret TEXT
+);
+
+CREATE TABLE IF NOT EXISTS frost_keys (
+    identifier INT,
+    min_signers INT,
+    max_signers INT,
+    key_package BLOB,
+    public_key_package BLOB,
+    internal_key BLOB,
+    p2tr_address TEXT
+);
+
+CREATE TABLE IF NOT EXISTS frost_nonces (
+    txid TEXT,
+    input_index INT,
+    internal_key BLOB,
+    commitments BLOB,
+    nonces TEXT
+);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS dlcs (
+    contract_id TEXT,
+    role TEXT,
+    bip32_index INT,
+    funding_seckey BLOB,
+    offer TEXT,
+    accept TEXT,
+    counterparty_signatures TEXT,
+    status TEXT
+);
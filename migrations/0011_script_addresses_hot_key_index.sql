@@ -0,0 +1 @@
+ALTER TABLE script_addresses ADD COLUMN hot_key_index INT;
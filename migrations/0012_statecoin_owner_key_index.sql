@@ -0,0 +1,3 @@
+ALTER TABLE statecoins ADD COLUMN bip32_index INT;
+
+ALTER TABLE statecoin_transfer_keys ADD COLUMN bip32_index INT;
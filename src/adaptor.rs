@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+
+use bitcoin::{OutPoint, Transaction, hashes::Hash, key::TapTweak, psbt::Psbt, taproot};
+use secp256k1_zkp::{Secp256k1, SecretKey, PublicKey, XOnlyPublicKey, KeyPair, Parity, Scalar, schnorr};
+use serde::{Serialize, Deserialize};
+
+use crate::{bip340::challenge, signer};
+
+/// A BIP340 Schnorr signature (R, s') encrypted under the adaptor point T = tG: s'G = R - T + eP.
+/// Anyone can check it against T, but it only becomes a valid signature (R, s' + t) with the
+/// secret t, and that signature reveals t to whoever holds the adaptor signature.
+pub struct AdaptorSignature {
+    /// R, always with an even Y like a BIP340 nonce.
+    pub nonce: XOnlyPublicKey,
+    pub s: SecretKey,
+}
+
+impl AdaptorSignature {
+    pub fn serialize(&self) -> [u8; 64] {
+        let mut bytes = [0u8; 64];
+        bytes[..32].copy_from_slice(&self.nonce.serialize());
+        bytes[32..].copy_from_slice(&self.s.secret_bytes());
+        bytes
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<AdaptorSignature, Box<dyn std::error::Error>> {
+        if bytes.len() != 64 {
+            return Err("An adaptor signature is 64 bytes".into());
+        }
+
+        Ok(AdaptorSignature {
+            nonce: XOnlyPublicKey::from_slice(&bytes[..32])?,
+            s: SecretKey::from_slice(&bytes[32..])?,
+        })
+    }
+}
+
+/// Adaptor signatures of a PSBT's inputs, as exchanged between the parties of a swap.
+#[derive(Serialize, Deserialize)]
+pub struct AdaptorSignatures {
+    pub adaptor_point: String,
+    pub signatures: Vec<InputAdaptorSignature>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InputAdaptorSignature {
+    pub input: usize,
+    pub outpoint: String,
+    pub adaptor_sig: String,
+}
+
+/// Creates an adaptor signature of `msg` with `keypair` under `adaptor_point`.
+pub fn create(keypair: &KeyPair, msg: &[u8; 32], adaptor_point: &PublicKey) -> Result<AdaptorSignature, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let (public_key, parity) = keypair.x_only_public_key();
+
+    // BIP340 signs for the even-Y key.
+    let secret_key = match parity {
+        Parity::Even => keypair.secret_key(),
+        Parity::Odd => keypair.secret_key().negate(),
+    };
+
+    // R = kG + T must have an even Y, as the completed signature only carries its X.
+    let (k, nonce) = loop {
+        let k = SecretKey::new(&mut rand::thread_rng());
+        let nonce = k.public_key(&secp).combine(adaptor_point)?;
+
+        if nonce.x_only_public_key().1 == Parity::Even {
+            break (k, nonce.x_only_public_key().0);
+        }
+    };
+
+    let e = challenge(&nonce, &public_key, msg)?;
+
+    // s' = k + ed
+    let s = secret_key.mul_tweak(&e)?.add_tweak(&Scalar::from(k))?;
+
+    Ok(AdaptorSignature { nonce, s })
+}
+
+/// Checks that `adaptor_sig` becomes a valid signature of `msg` by `public_key` once completed
+/// with the discrete log of `adaptor_point`: s'G + T = R + eP.
+pub fn verify(public_key: &XOnlyPublicKey, msg: &[u8; 32], adaptor_point: &PublicKey, adaptor_sig: &AdaptorSignature) -> Result<(), Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let e = challenge(&adaptor_sig.nonce, public_key, msg)?;
+
+    let left = adaptor_sig.s.public_key(&secp).combine(adaptor_point)?;
+
+    let nonce = adaptor_sig.nonce.public_key(Parity::Even);
+    let right = nonce.combine(&public_key.public_key(Parity::Even).mul_tweak(&secp, &e)?)?;
+
+    if left != right {
+        return Err("Invalid adaptor signature".into());
+    }
+
+    Ok(())
+}
+
+/// Decrypts `adaptor_sig` with the adaptor secret into a BIP340 signature (R, s' + t).
+pub fn complete(adaptor_sig: &AdaptorSignature, secret: &SecretKey) -> Result<schnorr::Signature, Box<dyn std::error::Error>> {
+    let s = adaptor_sig.s.add_tweak(&Scalar::from(*secret))?;
+
+    let mut bytes = [0u8; 64];
+    bytes[..32].copy_from_slice(&adaptor_sig.nonce.serialize());
+    bytes[32..].copy_from_slice(&s.secret_bytes());
+
+    Ok(schnorr::Signature::from_slice(&bytes)?)
+}
+
+/// Recovers the adaptor secret t = s - s' from the completed `signature` of `adaptor_sig`.
+pub fn extract(adaptor_sig: &AdaptorSignature, signature: &schnorr::Signature, adaptor_point: &PublicKey) -> Result<SecretKey, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let bytes = &signature[..];
+
+    if bytes[..32] != adaptor_sig.nonce.serialize() {
+        return Err("The signature was not completed from this adaptor signature".into());
+    }
+
+    let s = SecretKey::from_slice(&bytes[32..])?;
+    let secret = s.add_tweak(&Scalar::from(adaptor_sig.s.negate()))?;
+
+    if secret.public_key(&secp) != *adaptor_point {
+        return Err("The extracted secret doesn't match the adaptor point".into());
+    }
+
+    Ok(secret)
+}
+
+/// Output key and key-path sighash message of input `index`.
+fn input_message(psbt: &Psbt, index: usize) -> Result<(XOnlyPublicKey, [u8; 32]), Box<dyn std::error::Error>> {
+    let (hash, _) = signer::key_spend_sighash(psbt, index)?;
+
+    let script_pubkey = &psbt.inputs[index].witness_utxo.as_ref().ok_or("Missing witness_utxo")?.script_pubkey;
+    if !script_pubkey.is_v1_p2tr() {
+        return Err(format!("Input {} is not P2TR", index).into());
+    }
+
+    Ok((XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..34])?, hash.to_byte_array()))
+}
+
+/// Creates adaptor signatures under `adaptor_point` for every key-path input whose internal key
+/// is in `secret_keys`, instead of signing them.
+pub fn sign_psbt(psbt: &Psbt, secret_keys: &HashMap<XOnlyPublicKey, SecretKey>, adaptor_point: &PublicKey) -> Result<AdaptorSignatures, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let mut signatures = Vec::<InputAdaptorSignature>::new();
+
+    for (index, input) in psbt.inputs.iter().enumerate() {
+        let secret_key = match input.tap_internal_key.and_then(|internal_key| secret_keys.get(&internal_key)) {
+            Some(secret_key) => secret_key,
+            None => continue,
+        };
+
+        let keypair = KeyPair::from_secret_key(&secp, secret_key)
+            .tap_tweak(&secp, input.tap_merkle_root)
+            .to_inner();
+
+        let (_, msg) = input_message(psbt, index)?;
+
+        let adaptor_sig = create(&keypair, &msg, adaptor_point)?;
+
+        signatures.push(InputAdaptorSignature {
+            input: index,
+            outpoint: psbt.unsigned_tx.input[index].previous_output.to_string(),
+            adaptor_sig: hex::encode(adaptor_sig.serialize()),
+        });
+    }
+
+    Ok(AdaptorSignatures { adaptor_point: adaptor_point.to_string(), signatures })
+}
+
+/// Verifies every adaptor signature against the output key its input spends, and that it was
+/// made for the outpoint that input spends. Every input in `expected_inputs` must have one.
+pub fn verify_psbt(psbt: &Psbt, adaptor_sigs: &AdaptorSignatures, expected_inputs: &[usize]) -> Result<(), Box<dyn std::error::Error>> {
+    let adaptor_point: PublicKey = adaptor_sigs.adaptor_point.parse()?;
+
+    if adaptor_sigs.signatures.is_empty() {
+        return Err("There are no adaptor signatures".into());
+    }
+
+    for index in expected_inputs {
+        if !adaptor_sigs.signatures.iter().any(|signature| signature.input == *index) {
+            return Err(format!("Input {} has no adaptor signature", index).into());
+        }
+    }
+
+    for signature in &adaptor_sigs.signatures {
+        let txin = psbt.unsigned_tx.input.get(signature.input).ok_or_else(|| format!("No input {}", signature.input))?;
+
+        if signature.outpoint.parse::<OutPoint>()? != txin.previous_output {
+            return Err(format!("Input {}: the adaptor signature is for another outpoint", signature.input).into());
+        }
+
+        let (public_key, msg) = input_message(psbt, signature.input)?;
+
+        let adaptor_sig = AdaptorSignature::from_slice(&hex::decode(&signature.adaptor_sig)?)?;
+
+        verify(&public_key, &msg, &adaptor_point, &adaptor_sig).map_err(|e| format!("Input {}: {}", signature.input, e))?;
+    }
+
+    Ok(())
+}
+
+/// Completes the adaptor signatures with `secret` into the `tap_key_sig` of their inputs.
+/// Returns the indexes of the inputs that were signed.
+pub fn complete_psbt(psbt: &mut Psbt, adaptor_sigs: &AdaptorSignatures, secret: &SecretKey) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    verify_psbt(psbt, adaptor_sigs, &[])?;
+
+    let adaptor_point: PublicKey = adaptor_sigs.adaptor_point.parse()?;
+    if secret.public_key(&Secp256k1::new()) != adaptor_point {
+        return Err("The secret doesn't match the adaptor point".into());
+    }
+
+    let mut signed_inputs = Vec::<usize>::new();
+
+    for signature in &adaptor_sigs.signatures {
+        let adaptor_sig = AdaptorSignature::from_slice(&hex::decode(&signature.adaptor_sig)?)?;
+
+        let sig = complete(&adaptor_sig, secret)?;
+        let (_, hash_ty) = signer::key_spend_sighash(psbt, signature.input)?;
+
+        psbt.inputs[signature.input].tap_key_sig = Some(taproot::Signature { sig, hash_ty });
+
+        signed_inputs.push(signature.input);
+    }
+
+    Ok(signed_inputs)
+}
+
+/// Finds an input of `tx` completed from one of the adaptor signatures and recovers the secret.
+pub fn extract_from_tx(tx: &Transaction, adaptor_sigs: &AdaptorSignatures) -> Result<SecretKey, Box<dyn std::error::Error>> {
+    let adaptor_point: PublicKey = adaptor_sigs.adaptor_point.parse()?;
+
+    for signature in &adaptor_sigs.signatures {
+        let outpoint: OutPoint = signature.outpoint.parse()?;
+
+        let input = match tx.input.iter().find(|input| input.previous_output == outpoint) {
+            Some(input) => input,
+            None => continue,
+        };
+
+        let sig = input.witness.nth(0).ok_or("The input has no signature")?;
+        let sig = taproot::Signature::from_slice(sig)?.sig;
+
+        let adaptor_sig = AdaptorSignature::from_slice(&hex::decode(&signature.adaptor_sig)?)?;
+
+        return extract(&adaptor_sig, &sig, &adaptor_point);
+    }
+
+    Err("The transaction doesn't spend any of the adaptor-signed inputs".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{ScriptBuf, TxIn, TxOut, absolute};
+    use secp256k1_zkp::Message;
+
+    use super::*;
+
+    #[test]
+    fn adaptor_signature_round_trip() {
+        let secp = Secp256k1::new();
+
+        // Random keys, so that both parities of the signing key come up.
+        for _ in 0..16 {
+            let keypair = KeyPair::new(&secp, &mut rand::thread_rng());
+            let (secret, adaptor_point) = secp.generate_keypair(&mut rand::thread_rng());
+            let msg = [7u8; 32];
+
+            let (public_key, _) = keypair.x_only_public_key();
+
+            let adaptor_sig = create(&keypair, &msg, &adaptor_point).unwrap();
+            verify(&public_key, &msg, &adaptor_point, &adaptor_sig).unwrap();
+
+            let (_, other_point) = secp.generate_keypair(&mut rand::thread_rng());
+            assert!(verify(&public_key, &msg, &other_point, &adaptor_sig).is_err());
+
+            let sig = complete(&adaptor_sig, &secret).unwrap();
+            assert!(secp.verify_schnorr(&sig, &Message::from_slice(&msg).unwrap(), &public_key).is_ok());
+
+            assert_eq!(extract(&adaptor_sig, &sig, &adaptor_point).unwrap(), secret);
+        }
+    }
+
+    #[test]
+    fn verify_psbt_checks_outpoints_and_inputs() {
+        let secp = Secp256k1::new();
+
+        let secret_key = SecretKey::new(&mut rand::thread_rng());
+        let (internal_key, _) = secret_key.x_only_public_key(&secp);
+        let (output_key, _) = internal_key.tap_tweak(&secp, None);
+
+        let tx = Transaction {
+            version: 2,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn { previous_output: OutPoint { txid: bitcoin::Txid::all_zeros(), vout: 1 }, ..Default::default() }],
+            output: vec![TxOut { value: 1000, script_pubkey: ScriptBuf::new() }],
+        };
+
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut { value: 2000, script_pubkey: ScriptBuf::new_v1_p2tr_tweaked(output_key) });
+        psbt.inputs[0].tap_internal_key = Some(internal_key);
+
+        let (secret, adaptor_point) = secp.generate_keypair(&mut rand::thread_rng());
+        let secret_keys = HashMap::from([(internal_key, secret_key)]);
+
+        let mut adaptor_sigs = sign_psbt(&psbt, &secret_keys, &adaptor_point).unwrap();
+        verify_psbt(&psbt, &adaptor_sigs, &[0]).unwrap();
+        assert!(verify_psbt(&psbt, &adaptor_sigs, &[0, 1]).is_err());
+
+        let mut completed = psbt.clone();
+        assert_eq!(complete_psbt(&mut completed, &adaptor_sigs, &secret).unwrap(), vec![0]);
+        let sig = completed.inputs[0].tap_key_sig.unwrap().sig;
+        let (_, msg) = input_message(&psbt, 0).unwrap();
+        assert!(secp.verify_schnorr(&sig, &Message::from_slice(&msg).unwrap(), &output_key.to_inner()).is_ok());
+
+        adaptor_sigs.signatures[0].outpoint = OutPoint { txid: bitcoin::Txid::all_zeros(), vout: 2 }.to_string();
+        assert!(verify_psbt(&psbt, &adaptor_sigs, &[]).is_err());
+
+        adaptor_sigs.signatures.clear();
+        assert!(verify_psbt(&psbt, &adaptor_sigs, &[]).is_err());
+    }
+}
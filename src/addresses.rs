@@ -37,6 +37,13 @@ pub async fn generate_or_get_seed(pool: &sqlx::Pool<Sqlite>) -> [u8; 32] {
     }   
 }
 
+/// Master extended private key of the wallet seed.
+pub async fn get_root_key(pool: &sqlx::Pool<Sqlite>, network: Network) -> ExtendedPrivKey {
+    let seed = generate_or_get_seed(pool).await;
+
+    ExtendedPrivKey::new_master(network, &seed).unwrap()
+}
+
 pub async fn get_next_bip32_index(pool: &sqlx::Pool<Sqlite>, is_change: bool) -> u32 {
 
     let is_change_i = if is_change { 1 } else { 0 };
@@ -0,0 +1,106 @@
+use bitcoin::psbt::Psbt;
+
+/// UR type of a PSBT, as registered in BCR-2020-006.
+const UR_TYPE: &str = "crypto-psbt";
+
+/// Encodes `psbt` as BC-UR `crypto-psbt` frames for animated QR codes. When `frames` is larger
+/// than the number of fragments, the extra frames are fountain-coded so the reader can recover
+/// from missed ones.
+pub fn psbt_to_ur_frames(psbt: &Psbt, max_fragment_len: usize, frames: Option<usize>) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let message = cbor_encode_bytes(&psbt.serialize());
+
+    let mut encoder = ur::Encoder::new(&message, max_fragment_len, UR_TYPE)
+        .map_err(|e| format!("UR encoding failed: {:?}", e))?;
+
+    let frames = frames.unwrap_or_else(|| encoder.fragment_count());
+
+    let mut parts = Vec::<String>::new();
+
+    for _ in 0..frames {
+        parts.push(encoder.next_part().map_err(|e| format!("UR encoding failed: {:?}", e))?);
+    }
+
+    Ok(parts)
+}
+
+/// Rebuilds a PSBT from BC-UR `crypto-psbt` frames, in any order and with duplicates.
+pub fn psbt_from_ur_frames<'a>(frames: impl Iterator<Item = &'a str>) -> Result<Psbt, Box<dyn std::error::Error>> {
+    let mut decoder = ur::Decoder::default();
+
+    for frame in frames {
+        let frame = frame.trim();
+        if frame.is_empty() {
+            continue;
+        }
+
+        decoder
+            .receive(&frame.to_lowercase())
+            .map_err(|e| format!("Invalid UR frame '{}': {:?}", frame, e))?;
+
+        if decoder.complete() {
+            break;
+        }
+    }
+
+    let message = decoder
+        .message()
+        .map_err(|e| format!("UR decoding failed: {:?}", e))?
+        .ok_or("Not enough UR frames to rebuild the PSBT")?;
+
+    let psbt_bytes = cbor_decode_bytes(&message)?;
+
+    Ok(Psbt::deserialize(&psbt_bytes)?)
+}
+
+/// Wraps `bytes` in a CBOR byte string (major type 2), which is how `crypto-psbt` carries the PSBT.
+fn cbor_encode_bytes(bytes: &[u8]) -> Vec<u8> {
+    let len = bytes.len();
+
+    let mut cbor = Vec::<u8>::with_capacity(len + 9);
+
+    if len < 24 {
+        cbor.push(0x40 | len as u8);
+    } else if len <= u8::MAX as usize {
+        cbor.push(0x58);
+        cbor.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        cbor.push(0x59);
+        cbor.extend_from_slice(&(len as u16).to_be_bytes());
+    } else if len <= u32::MAX as usize {
+        cbor.push(0x5a);
+        cbor.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        cbor.push(0x5b);
+        cbor.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+
+    cbor.extend_from_slice(bytes);
+    cbor
+}
+
+fn cbor_decode_bytes(cbor: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let (&header, rest) = cbor.split_first().ok_or("Empty CBOR message")?;
+
+    if header >> 5 != 2 {
+        return Err("crypto-psbt is not a CBOR byte string".into());
+    }
+
+    let (len, rest) = match header & 0x1f {
+        n @ 0..=23 => (n as usize, rest),
+        n @ 24..=27 => {
+            let size = 1 << (n - 24);
+            if rest.len() < size {
+                return Err("Truncated CBOR message".into());
+            }
+            let len = rest[..size].iter().fold(0usize, |len, byte| (len << 8) | *byte as usize);
+            (len, &rest[size..])
+        },
+        _ => return Err("Unsupported CBOR byte string length".into()),
+    };
+
+    if rest.len() != len {
+        return Err("CBOR byte string length doesn't match the message".into());
+    }
+
+    Ok(rest.to_vec())
+}
@@ -0,0 +1,33 @@
+//! Authentication of requests to the co-signer server by the owner key of the shared key, and of
+//! new server keys by the server. `src/bin/cosigner_server.rs` includes this file too, so both
+//! sides hash the same messages.
+
+use bitcoin::hashes::{Hash, sha256};
+use secp256k1_zkp::{Message, PublicKey};
+
+/// Message the owner key signs (BIP340) to authorize a request to `path` for `key_id`. It commits
+/// to a single-use `challenge` from the server, so that the request can't be replayed, and to the
+/// other `fields` of the request, so that they can't be changed.
+pub fn message(path: &str, key_id: &str, challenge: &str, fields: &[&str]) -> Message {
+    let mut data = format!("{}/{}/{}", path, key_id, challenge);
+    for field in fields {
+        data.push('/');
+        data.push_str(field);
+    }
+
+    let hash = sha256::Hash::hash(data.as_bytes());
+
+    Message::from_slice(hash.as_byte_array()).unwrap()
+}
+
+/// Message the server key signs (BIP340) when it is created for `client_pubkey`, to prove the
+/// server knows its secret key: a key chosen as S' - C would make the shared key C + S = S' the
+/// server's alone, but the server can't sign for it.
+pub fn key_proof_message(client_pubkey: &PublicKey, key_id: &str) -> Message {
+    let mut data = client_pubkey.serialize().to_vec();
+    data.extend_from_slice(key_id.as_bytes());
+
+    let hash = sha256::Hash::hash(&data);
+
+    Message::from_slice(hash.as_byte_array()).unwrap()
+}
@@ -1,6 +1,10 @@
-use bitcoin::Txid;
+use bitcoin::{Transaction, Txid};
 use electrum_client::{GetBalanceRes, ElectrumApi, GetHistoryRes, ListUnspentRes};
 
+pub fn connect() -> electrum_client::Client {
+    electrum_client::Client::new("tcp://127.0.0.1:50001").unwrap()
+}
+
 /// return balance of address
 pub fn get_address_balance(electrum_client: &electrum_client::Client, address: &bitcoin::Address) -> GetBalanceRes {
     electrum_client.script_get_balance(&address.script_pubkey()).unwrap()
@@ -17,3 +21,24 @@ pub fn get_script_list_unspent(electrum_client: &electrum_client::Client, addres
 pub fn transaction_broadcast_raw(electrum_client: &electrum_client::Client, raw_tx: &[u8]) -> Txid {    
     electrum_client.transaction_broadcast_raw(raw_tx).unwrap()
 }
+
+/// broadcast a raw transaction, returning the server's error instead of panicking (e.g. for a
+/// transaction that isn't final yet)
+pub fn try_transaction_broadcast_raw(electrum_client: &electrum_client::Client, raw_tx: &[u8]) -> Result<Txid, electrum_client::Error> {
+    electrum_client.transaction_broadcast_raw(raw_tx)
+}
+
+pub fn get_transaction(electrum_client: &electrum_client::Client, txid: &Txid) -> Transaction {
+    electrum_client.transaction_get(txid).unwrap()
+}
+
+/// get a transaction, returning the server's error instead of panicking (e.g. for a txid
+/// taken from untrusted input)
+pub fn try_get_transaction(electrum_client: &electrum_client::Client, txid: &Txid) -> Result<Transaction, electrum_client::Error> {
+    electrum_client.transaction_get(txid)
+}
+
+/// return the height of the current chain tip
+pub fn get_tip_height(electrum_client: &electrum_client::Client) -> u32 {
+    electrum_client.block_headers_subscribe().unwrap().height as u32
+}
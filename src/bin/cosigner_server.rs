@@ -0,0 +1,302 @@
+//! Local stand-in for the blinded MuSig2 co-signer server, and mock statechain entity, to run
+//! the client against in tests. It keeps its keys, pending nonces and pending transfers in
+//! `cosigner_server.json` and answers one request at a time on 127.0.0.1:8000. Every request
+//! for a key, but the one creating it and those asking for a challenge, must be signed by the
+//! key's current owner key over a challenge that is only accepted once.
+
+#[path = "../auth.rs"]
+mod auth;
+
+use std::{collections::HashMap, io::{BufRead, BufReader, Read, Write}, net::{TcpListener, TcpStream}, str::FromStr};
+
+use secp256k1_zkp::{Secp256k1, SecretKey, PublicKey, KeyPair, Scalar, schnorr, musig::{new_musig_nonce_pair, MusigSecNonce, MusigSession, MusigSessionId}};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+
+const LISTEN_ADDRESS: &str = "127.0.0.1:8000";
+const STATE_FILE: &str = "cosigner_server.json";
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    /// Secret keys by key id.
+    keys: HashMap<String, String>,
+    /// Key id and secret nonce by public nonce, until the nonce is used.
+    nonces: HashMap<String, (String, String)>,
+    /// x1 of the transfers started by the sender, by key (statechain) id.
+    #[serde(default)]
+    transfers: HashMap<String, String>,
+    /// Public key of the client that owns each key, which authenticates its requests.
+    #[serde(default)]
+    owners: HashMap<String, String>,
+    /// Key id of each challenge handed out, until a request uses it.
+    #[serde(default)]
+    challenges: HashMap<String, String>,
+}
+
+impl State {
+    fn load() -> State {
+        std::fs::read_to_string(STATE_FILE)
+            .ok()
+            .and_then(|state| serde_json::from_str(&state).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) {
+        std::fs::write(STATE_FILE, serde_json::to_string_pretty(self).unwrap()).unwrap();
+    }
+
+    fn secret_key(&self, key_id: &str) -> Result<SecretKey, String> {
+        let secret_key = self.keys.get(key_id).ok_or("Unknown key_id")?;
+        SecretKey::from_slice(&hex::decode(secret_key).unwrap()).map_err(|e| e.to_string())
+    }
+
+    fn owner(&self, key_id: &str) -> Result<PublicKey, String> {
+        let owner = self.owners.get(key_id).ok_or("No owner key for key_id")?;
+        PublicKey::from_str(owner).map_err(|e| e.to_string())
+    }
+}
+
+fn field<'a>(body: &'a Value, name: &str) -> Result<&'a str, String> {
+    body[name].as_str().ok_or_else(|| format!("Missing {}", name))
+}
+
+/// Checks the request's `auth_sig`, a BIP340 signature of `auth::message` by `owner` over the
+/// request's challenge and `fields`. The challenge is used up even if the signature is wrong.
+fn check_auth(state: &mut State, body: &Value, path: &str, key_id: &str, owner: &PublicKey, fields: &[&str]) -> Result<(), String> {
+    let challenge = field(body, "challenge")?;
+
+    if state.challenges.remove(challenge).as_deref() != Some(key_id) {
+        return Err("Unknown or already used challenge".to_string());
+    }
+
+    let auth_sig = hex::decode(field(body, "auth_sig")?).map_err(|e| e.to_string())?;
+    let auth_sig = schnorr::Signature::from_slice(&auth_sig).map_err(|e| e.to_string())?;
+
+    Secp256k1::verification_only()
+        .verify_schnorr(&auth_sig, &auth::message(path, key_id, challenge, fields), &owner.x_only_public_key().0)
+        .map_err(|_| "The request is not signed by the owner key".to_string())
+}
+
+/// Creates a key for `client_pubkey`, signing `auth::key_proof_message` with it.
+fn new_key(state: &mut State, body: &Value) -> Result<Value, String> {
+    let secp = Secp256k1::new();
+
+    let client_pubkey = PublicKey::from_str(field(body, "client_pubkey")?).map_err(|e| e.to_string())?;
+
+    let (secret_key, public_key) = secp.generate_keypair(&mut rand::thread_rng());
+    let key_id = uuid::Uuid::new_v4().to_string();
+
+    let key_proof = secp.sign_schnorr(&auth::key_proof_message(&client_pubkey, &key_id), &KeyPair::from_secret_key(&secp, &secret_key));
+
+    state.keys.insert(key_id.clone(), hex::encode(secret_key.secret_bytes()));
+    state.owners.insert(key_id.clone(), client_pubkey.to_string());
+
+    Ok(json!({
+        "key_id": key_id,
+        "server_pubkey": public_key.to_string(),
+        "key_proof": hex::encode(key_proof.as_ref()),
+    }))
+}
+
+/// Hands out a random challenge for the next request for `key_id`, see `check_auth`.
+fn challenge(state: &mut State, body: &Value) -> Result<Value, String> {
+    let key_id = field(body, "key_id")?;
+    state.secret_key(key_id)?;
+
+    let challenge = hex::encode(rand::random::<[u8; 32]>());
+
+    state.challenges.insert(challenge.clone(), key_id.to_string());
+
+    Ok(json!({ "challenge": challenge }))
+}
+
+/// Commits to a nonce without seeing the message, which the client never sends.
+fn sign_first(state: &mut State, body: &Value) -> Result<Value, String> {
+    let secp = Secp256k1::new();
+
+    let key_id = field(body, "key_id")?;
+    let secret_key = state.secret_key(key_id)?;
+
+    let owner = state.owner(key_id)?;
+    check_auth(state, body, "sign/first", key_id, &owner, &[])?;
+
+    let (sec_nonce, pub_nonce) = new_musig_nonce_pair(
+        &secp,
+        MusigSessionId::new(&mut rand::thread_rng()),
+        None,
+        Some(secret_key),
+        secret_key.public_key(&secp),
+        None,
+        None,
+    ).map_err(|e| format!("{:?}", e))?;
+
+    let pub_nonce = hex::encode(pub_nonce.serialize());
+
+    state.nonces.insert(pub_nonce.clone(), (key_id.to_string(), hex::encode(sec_nonce.serialize())));
+
+    Ok(json!({ "server_pubnonce": pub_nonce }))
+}
+
+/// Signs the blinded session. The secret nonce is removed first so it is never used twice.
+fn sign_second(state: &mut State, body: &Value) -> Result<Value, String> {
+    let secp = Secp256k1::new();
+
+    let key_id = field(body, "key_id")?;
+    let secret_key = state.secret_key(key_id)?;
+
+    let negate_seckey = body["negate_seckey"].as_bool().ok_or("Missing negate_seckey")?;
+
+    let owner = state.owner(key_id)?;
+    let fields: [&str; 3] = [field(body, "server_pubnonce")?, field(body, "session")?, &negate_seckey.to_string()];
+    check_auth(state, body, "sign/second", key_id, &owner, &fields)?;
+
+    let (nonce_key_id, sec_nonce) = state.nonces
+        .remove(field(body, "server_pubnonce")?)
+        .ok_or("Unknown or already used nonce")?;
+
+    if nonce_key_id != key_id {
+        return Err("The nonce belongs to another key".to_string());
+    }
+
+    let sec_nonce = MusigSecNonce::from_slice(&hex::decode(sec_nonce).unwrap()).map_err(|e| format!("{:?}", e))?;
+
+    let session = hex::decode(field(body, "session")?).map_err(|e| e.to_string())?;
+    let session = MusigSession::from_slice(&session).map_err(|e| format!("{:?}", e))?;
+
+    let keypair = KeyPair::from_secret_key(&secp, &secret_key);
+
+    let partial_sig = session
+        .partial_sign_without_keyaggcoeff(&secp, sec_nonce, &keypair, negate_seckey)
+        .map_err(|e| format!("{:?}", e))?;
+
+    Ok(json!({ "partial_sig": hex::encode(partial_sig.serialize()) }))
+}
+
+/// Starts a statechain transfer: the sender gets a random x1 and gives o1 + x1 to the receiver.
+fn transfer_sender(state: &mut State, body: &Value) -> Result<Value, String> {
+    let statechain_id = field(body, "statechain_id")?;
+    state.secret_key(statechain_id)?;
+
+    let owner = state.owner(statechain_id)?;
+    check_auth(state, body, "transfer/sender", statechain_id, &owner, &[])?;
+
+    let x1 = SecretKey::new(&mut rand::thread_rng());
+
+    state.transfers.insert(statechain_id.to_string(), hex::encode(x1.secret_bytes()));
+
+    Ok(json!({ "x1": hex::encode(x1.secret_bytes()) }))
+}
+
+/// Completes a statechain transfer with the receiver's t2 = o1 + x1 - o2: the key becomes
+/// s2 = s1 + t2 - x1 = s1 + o1 - o2, so o2 + s2 = o1 + s1, and s1 is forgotten. The request
+/// must be signed by O2, which becomes the owner key.
+fn transfer_receiver(state: &mut State, body: &Value) -> Result<Value, String> {
+    let secp = Secp256k1::new();
+
+    let statechain_id = field(body, "statechain_id")?;
+    let s1 = state.secret_key(statechain_id)?;
+    let owner1 = state.owner(statechain_id)?;
+
+    let owner2 = PublicKey::from_str(field(body, "receiver_pubkey")?).map_err(|e| e.to_string())?;
+    check_auth(state, body, "transfer/receiver", statechain_id, &owner2, &[field(body, "t2")?, field(body, "receiver_pubkey")?])?;
+
+    let x1 = state.transfers.get(statechain_id).ok_or("No transfer started for this statechain")?;
+    let x1 = SecretKey::from_slice(&hex::decode(x1).unwrap()).map_err(|e| e.to_string())?;
+
+    let t2 = hex::decode(field(body, "t2")?).map_err(|e| e.to_string())?;
+    let t2 = SecretKey::from_slice(&t2).map_err(|e| e.to_string())?;
+
+    let s2 = s1
+        .add_tweak(&Scalar::from(t2))
+        .and_then(|key| key.add_tweak(&Scalar::from(x1.negate())))
+        .map_err(|e| e.to_string())?;
+
+    let server_pubkey = s2.public_key(&secp);
+
+    if owner2.combine(&server_pubkey) != owner1.combine(&s1.public_key(&secp)) {
+        return Err("t2 doesn't keep the shared key for this receiver key".to_string());
+    }
+
+    state.transfers.remove(statechain_id);
+    state.keys.insert(statechain_id.to_string(), hex::encode(s2.secret_bytes()));
+    state.owners.insert(statechain_id.to_string(), owner2.to_string());
+
+    Ok(json!({ "server_pubkey": server_pubkey.to_string() }))
+}
+
+fn route(state: &mut State, path: &str, body: &Value) -> Result<Value, String> {
+    match path {
+        "/key" => new_key(state, body),
+        "/challenge" => challenge(state, body),
+        "/sign/first" => sign_first(state, body),
+        "/sign/second" => sign_second(state, body),
+        "/transfer/sender" => transfer_sender(state, body),
+        "/transfer/receiver" => transfer_receiver(state, body),
+        _ => Err(format!("Not found: {}", path)),
+    }
+}
+
+fn handle(stream: TcpStream, state: &mut State) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/").to_string();
+
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        if header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+    let body: Value = serde_json::from_slice(&body).unwrap_or(Value::Null);
+
+    let (status, response) = match route(state, &path, &body) {
+        Ok(response) => ("200 OK", response),
+        Err(e) => ("400 Bad Request", json!({ "error": e })),
+    };
+
+    // Saved on errors too, so a nonce taken by a failed signing is never handed out again.
+    state.save();
+
+    println!("{} {}", path, status);
+
+    let response = response.to_string();
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        response.len(),
+        response,
+    )
+}
+
+fn main() {
+    let mut state = State::load();
+
+    let listener = TcpListener::bind(LISTEN_ADDRESS).unwrap();
+    println!("Co-signer server listening on {}", LISTEN_ADDRESS);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = handle(stream, &mut state) {
+                    println!("error: {}", e);
+                }
+            },
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
+
@@ -0,0 +1,160 @@
+//! Local stand-in for a DLC oracle, to run contracts against in tests. It announces events with
+//! a fresh nonce and attests their outcome with a BIP340 signature of SHA256(outcome) using
+//! that nonce. Its key and the nonces are kept in `dlc_oracle.json`.
+
+#[path = "../bip340.rs"]
+mod bip340;
+
+use std::collections::HashMap;
+
+use bitcoin::hashes::{Hash, sha256};
+use clap::{Parser, Subcommand};
+use secp256k1_zkp::{Secp256k1, SecretKey, XOnlyPublicKey, Parity, Scalar};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+
+const STATE_FILE: &str = "dlc_oracle.json";
+
+#[derive(Parser)]
+#[command(about = "Local DLC oracle", long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Announce an event and its possible outcomes
+    Announce {
+        event_id: String,
+        #[arg(required = true)]
+        outcomes: Vec<String>,
+    },
+    /// Attest the outcome of an announced event
+    Attest {
+        event_id: String,
+        outcome: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Event {
+    nonce_seckey: String,
+    outcomes: Vec<String>,
+    attested_outcome: Option<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    secret_key: Option<String>,
+    events: HashMap<String, Event>,
+}
+
+impl State {
+    fn load() -> State {
+        std::fs::read_to_string(STATE_FILE)
+            .ok()
+            .and_then(|state| serde_json::from_str(&state).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) {
+        std::fs::write(STATE_FILE, serde_json::to_string_pretty(self).unwrap()).unwrap();
+    }
+
+    /// The oracle key, generated on first use.
+    fn secret_key(&mut self) -> SecretKey {
+        let secret_key = self.secret_key
+            .get_or_insert_with(|| hex::encode(SecretKey::new(&mut rand::thread_rng()).secret_bytes()));
+
+        SecretKey::from_slice(&hex::decode(secret_key).unwrap()).unwrap()
+    }
+}
+
+/// The secret key of the even-Y point of `secret_key`, as BIP340 signs with.
+fn even_secret_key(secret_key: SecretKey) -> (SecretKey, XOnlyPublicKey) {
+    let (public_key, parity) = secret_key.x_only_public_key(&Secp256k1::new());
+
+    match parity {
+        Parity::Even => (secret_key, public_key),
+        Parity::Odd => (secret_key.negate(), public_key),
+    }
+}
+
+fn announce(state: &mut State, event_id: String, outcomes: Vec<String>) -> Result<Value, String> {
+    if state.events.contains_key(&event_id) {
+        return Err("The event was already announced".to_string());
+    }
+
+    let (_, oracle_pubkey) = even_secret_key(state.secret_key());
+    let (nonce_seckey, nonce) = even_secret_key(SecretKey::new(&mut rand::thread_rng()));
+
+    state.events.insert(event_id.clone(), Event {
+        nonce_seckey: hex::encode(nonce_seckey.secret_bytes()),
+        outcomes: outcomes.clone(),
+        attested_outcome: None,
+    });
+
+    Ok(json!({
+        "event_id": event_id,
+        "oracle_pubkey": oracle_pubkey.to_string(),
+        "nonce": nonce.to_string(),
+        "outcomes": outcomes,
+    }))
+}
+
+/// Signs the outcome with the event's nonce: s = k + ex. The event can only be attested once,
+/// since two signatures with the same nonce would reveal the oracle key.
+fn attest(state: &mut State, event_id: String, outcome: String) -> Result<Value, String> {
+    let (secret_key, oracle_pubkey) = even_secret_key(state.secret_key());
+
+    let event = state.events.get_mut(&event_id).ok_or("Unknown event")?;
+
+    if !event.outcomes.contains(&outcome) {
+        return Err(format!("{} is not an outcome of the event", outcome));
+    }
+
+    if event.attested_outcome.as_ref().map_or(false, |attested| attested != &outcome) {
+        return Err("The event was already attested with another outcome".to_string());
+    }
+
+    let (nonce_seckey, nonce) = even_secret_key(SecretKey::from_slice(&hex::decode(&event.nonce_seckey).unwrap()).unwrap());
+
+    let msg = sha256::Hash::hash(outcome.as_bytes()).to_byte_array();
+    let e = bip340::challenge(&nonce, &oracle_pubkey, &msg).map_err(|e| e.to_string())?;
+
+    let s = secret_key
+        .mul_tweak(&e)
+        .and_then(|ex| ex.add_tweak(&Scalar::from(nonce_seckey)))
+        .map_err(|e| e.to_string())?;
+
+    let mut signature = [0u8; 64];
+    signature[..32].copy_from_slice(&nonce.serialize());
+    signature[32..].copy_from_slice(&s.secret_bytes());
+
+    event.attested_outcome = Some(outcome.clone());
+
+    Ok(json!({
+        "event_id": event_id,
+        "outcome": outcome,
+        "signature": hex::encode(signature),
+    }))
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let mut state = State::load();
+
+    let res = match cli.command {
+        Commands::Announce { event_id, outcomes } => announce(&mut state, event_id, outcomes),
+        Commands::Attest { event_id, outcome } => attest(&mut state, event_id, outcome),
+    };
+
+    state.save();
+
+    match res {
+        Ok(res) => println!("{}", serde_json::to_string_pretty(&res).unwrap()),
+        Err(e) => println!("{}", serde_json::to_string_pretty(&json!({ "error": e })).unwrap()),
+    }
+}
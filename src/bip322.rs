@@ -0,0 +1,184 @@
+use std::str::FromStr;
+
+use bitcoin::{Address, Network, OutPoint, Script, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness, absolute, base64, bip32::{DerivationPath, Fingerprint}, consensus::encode, hashes::{Hash, sha256}, opcodes::{OP_0, all::OP_RETURN}, psbt::Psbt, script::Builder, sighash::{Prevouts, SighashCache, TapSighashType}, taproot};
+use secp256k1_zkp::{Secp256k1, XOnlyPublicKey, Message};
+use sqlx::Sqlite;
+
+use crate::{addresses, signer, wallet};
+
+const MESSAGE_TAG: &[u8] = b"BIP0322-signed-message";
+
+/// Tagged hash of the message, as committed to by the `to_spend` transaction.
+fn message_hash(message: &[u8]) -> sha256::Hash {
+    let tag = sha256::Hash::hash(MESSAGE_TAG);
+
+    let mut data = Vec::<u8>::with_capacity(64 + message.len());
+    data.extend_from_slice(tag.as_byte_array());
+    data.extend_from_slice(tag.as_byte_array());
+    data.extend_from_slice(message);
+
+    sha256::Hash::hash(&data)
+}
+
+/// Virtual transaction paying the address, whose only input commits to the message.
+fn to_spend(script_pubkey: &Script, message: &[u8]) -> Transaction {
+    let script_sig = Builder::new()
+        .push_opcode(OP_0)
+        .push_slice(message_hash(message).to_byte_array())
+        .into_script();
+
+    Transaction {
+        version: 0,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig,
+            sequence: Sequence::ZERO,
+            witness: Witness::default(),
+        }],
+        output: vec![TxOut { value: 0, script_pubkey: script_pubkey.to_owned() }],
+    }
+}
+
+/// Virtual transaction spending `to_spend` to an OP_RETURN output, which the signature is for.
+fn to_sign(to_spend: &Transaction) -> Transaction {
+    Transaction {
+        version: 0,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint { txid: to_spend.txid(), vout: 0 },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ZERO,
+            witness: Witness::default(),
+        }],
+        output: vec![TxOut { value: 0, script_pubkey: Builder::new().push_opcode(OP_RETURN).into_script() }],
+    }
+}
+
+/// Signs `message` with the key of one of the wallet's addresses, derived again from its
+/// stored derivation path. Returns the BIP322 simple signature (the witness of `to_sign`) or,
+/// with `full`, the whole signed `to_sign` transaction, in base64.
+pub async fn sign_message(pool: &sqlx::Pool<Sqlite>, network: Network, address: &Address, message: &str, full: bool) -> Result<String, Box<dyn std::error::Error>> {
+    let (_, fingerprint, derivation_path, xonly_public_key, _) = wallet::get_all_addresses_info(pool, network).await
+        .into_iter()
+        .find(|info| info.0 == *address)
+        .ok_or("The address is not in the wallet")?;
+
+    let to_spend = to_spend(&address.script_pubkey(), message.as_bytes());
+
+    let mut psbt = Psbt::from_unsigned_tx(to_sign(&to_spend))?;
+
+    let input = &mut psbt.inputs[0];
+    input.witness_utxo = Some(to_spend.output[0].clone());
+    input.tap_internal_key = Some(xonly_public_key);
+    input.tap_key_origins.insert(xonly_public_key, (vec![], (Fingerprint::from_str(&fingerprint)?, DerivationPath::from_str(&derivation_path)?)));
+    input.sighash_type = Some(TapSighashType::Default.into());
+
+    let root = addresses::get_root_key(pool, network).await;
+    let secret_keys = signer::secret_keys_from_origins(&psbt, &root);
+
+    if signer::sign_psbt(&mut psbt, &secret_keys)?.is_empty() {
+        return Err("The key of the address doesn't derive from this wallet's seed".into());
+    }
+
+    signer::finalize_psbt(&mut psbt)?;
+    let tx = signer::extract_tx(psbt)?;
+
+    let signature = match full {
+        true => encode::serialize(&tx),
+        false => encode::serialize(&tx.input[0].witness),
+    };
+
+    Ok(base64::encode(signature))
+}
+
+/// Verifies a BIP322 simple or full signature of `message` by a P2TR address. Only key-path
+/// signatures without proof-of-funds inputs are supported.
+pub fn verify_message(address: &Address, message: &str, signature: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let secp = Secp256k1::verification_only();
+
+    let script_pubkey = address.script_pubkey();
+    if !script_pubkey.is_v1_p2tr() {
+        return Err("Only P2TR addresses are supported".into());
+    }
+
+    let bytes = base64::decode(signature)?;
+
+    let to_spend = to_spend(&script_pubkey, message.as_bytes());
+    let expected = to_sign(&to_spend);
+
+    // The full format is the whole `to_sign` transaction, which may set its own version,
+    // locktime and sequence, the simple one only the witness of its input.
+    let to_sign = match encode::deserialize::<Transaction>(&bytes) {
+        Ok(tx) => {
+            if tx.input.len() != 1 {
+                return Err("Proof-of-funds inputs are not supported".into());
+            }
+            if tx.input[0].previous_output != expected.input[0].previous_output || tx.output != expected.output {
+                return Err("The transaction is not the to_sign transaction of this message".into());
+            }
+            tx
+        },
+        Err(_) => {
+            let mut tx = expected;
+            tx.input[0].witness = encode::deserialize::<Witness>(&bytes)?;
+            tx
+        },
+    };
+
+    let witness = &to_sign.input[0].witness;
+    if witness.len() != 1 {
+        return Err("Only key-path signatures are supported".into());
+    }
+
+    let signature = taproot::Signature::from_slice(&witness[0])?;
+
+    let hash = SighashCache::new(&to_sign).taproot_key_spend_signature_hash(0, &Prevouts::All(&to_spend.output), signature.hash_ty)?;
+    let msg = Message::from_slice(hash.as_byte_array())?;
+
+    let output_key = XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..34])?;
+
+    secp.verify_schnorr(&signature.sig, &msg, &output_key)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vectors from BIP322.
+
+    #[test]
+    fn message_hash_vectors() {
+        assert_eq!(message_hash(b"").to_string(), "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1");
+        assert_eq!(message_hash(b"Hello World").to_string(), "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a");
+    }
+
+    #[test]
+    fn transaction_vectors() {
+        let address = Address::from_str("bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l").unwrap().require_network(Network::Bitcoin).unwrap();
+
+        let vectors = [
+            ("", "c5680aa69bb8d860bf82d4e9cd3504b55dde018de765a91bb566283c545a99a7", "1e9654e951a5ba44c8604c4de6c67fd78a27e81dcadcfe1edf638ba3aaebaed6"),
+            ("Hello World", "b79d196740ad5217771c1098fc4a4b51e0535c32236c71f1ea4d61a2d603352b", "88737ae86f2077145f93cc4b153ae9a1cb8d56afa511988c149c5c8c9d93bddf"),
+        ];
+
+        for (message, to_spend_txid, to_sign_txid) in vectors {
+            let to_spend = to_spend(&address.script_pubkey(), message.as_bytes());
+
+            assert_eq!(to_spend.txid().to_string(), to_spend_txid);
+            assert_eq!(to_sign(&to_spend).txid().to_string(), to_sign_txid);
+        }
+    }
+
+    #[test]
+    fn verify_p2tr_vector() {
+        let address = Address::from_str("bc1ppv609nr0vr25u07u95waq5lucwfm6tde4nydujnu8npg4q75mr5sxq8lt3").unwrap().require_network(Network::Bitcoin).unwrap();
+        let signature = "AUHd69PrJQEv+oKTfZ8l+WROBHuy9HKrbFCJu7U1iK2iiEy1vMU5EfMtjc+VSHM7aU0SDbak5IUZRVno2P5mjSafAQ==";
+
+        verify_message(&address, "Hello World", signature).unwrap();
+
+        assert!(verify_message(&address, "", signature).is_err());
+    }
+}
@@ -0,0 +1,21 @@
+//! BIP340 building blocks that depend on nothing else in the crate, so that the local DLC
+//! oracle in `src/bin/dlc_oracle.rs` includes this file instead of keeping its own copy.
+
+use bitcoin::hashes::{Hash, sha256};
+use secp256k1_zkp::{XOnlyPublicKey, Scalar};
+
+/// BIP340 challenge e = H_BIP0340/challenge(R.x || P.x || m).
+pub fn challenge(nonce: &XOnlyPublicKey, public_key: &XOnlyPublicKey, msg: &[u8; 32]) -> Result<Scalar, Box<dyn std::error::Error>> {
+    let tag = sha256::Hash::hash(b"BIP0340/challenge");
+
+    let mut data = Vec::<u8>::with_capacity(160);
+    data.extend_from_slice(tag.as_byte_array());
+    data.extend_from_slice(tag.as_byte_array());
+    data.extend_from_slice(&nonce.serialize());
+    data.extend_from_slice(&public_key.serialize());
+    data.extend_from_slice(msg);
+
+    let hash = sha256::Hash::hash(&data);
+
+    Scalar::from_be_bytes(hash.to_byte_array()).map_err(|_| "Challenge out of range".into())
+}
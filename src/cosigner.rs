@@ -0,0 +1,424 @@
+use std::str::FromStr;
+
+use bitcoin::{Address, Network, bip32::{ChildNumber, DerivationPath}, hashes::Hash, psbt::Psbt, taproot::{self, TapTweakHash}};
+use miniscript::{Descriptor, DefiniteDescriptorKey};
+use secp256k1_zkp::{Secp256k1, SecretKey, PublicKey, KeyPair, Message, Parity, Scalar, schnorr, musig::{new_musig_nonce_pair, BlindingFactor, MusigAggNonce, MusigPartialSignature, MusigPubNonce, MusigSession, MusigSessionId}};
+use serde::{Serialize, Deserialize};
+use sqlx::{Sqlite, Row};
+
+use crate::{addresses, auth, scripts, signer, wallet::TxOptions};
+
+/// Co-signer server, see `src/bin/cosigner_server.rs` for a local stand-in.
+const COSIGNER_URL: &str = "http://127.0.0.1:8000";
+
+/// Path of the client keys of co-signed addresses.
+const COSIGNED_KEY_PATH: &str = "m/86h/0h/0h/3";
+
+/// The owner key must sign every later request for the new key, see `auth_sig`.
+#[derive(Serialize, Deserialize)]
+pub struct NewKeyRequest {
+    pub client_pubkey: String,
+}
+
+/// `key_proof` is the server's BIP340 signature of `auth::key_proof_message`, see `new_key`.
+#[derive(Serialize, Deserialize)]
+pub struct NewKeyResponse {
+    pub key_id: String,
+    pub server_pubkey: String,
+    pub key_proof: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChallengeRequest {
+    pub key_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChallengeResponse {
+    pub challenge: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SignFirstRequest {
+    pub key_id: String,
+    pub challenge: String,
+    pub auth_sig: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SignFirstResponse {
+    pub server_pubnonce: String,
+}
+
+/// Everything the server gets to produce its partial signature: the blinded session carries
+/// neither the message nor the aggregated key.
+#[derive(Serialize, Deserialize)]
+pub struct SignSecondRequest {
+    pub key_id: String,
+    pub server_pubnonce: String,
+    pub session: String,
+    pub negate_seckey: bool,
+    pub challenge: String,
+    pub auth_sig: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SignSecondResponse {
+    pub partial_sig: String,
+}
+
+pub struct CosignedAddress {
+    pub address: Address,
+    pub secret_key: SecretKey,
+    pub public_key: PublicKey,
+    pub server_pubkey: PublicKey,
+    pub key_id: String,
+    pub aggregate_pubkey: PublicKey,
+    pub derivation_path: String,
+}
+
+pub async fn post<Req: Serialize, Res: for<'de> Deserialize<'de>>(path: &str, body: &Req) -> Result<Res, Box<dyn std::error::Error>> {
+    let response = reqwest::Client::new()
+        .post(format!("{}/{}", COSIGNER_URL, path))
+        .json(body)
+        .send()
+        .await?;
+
+    if !response.status().is_success() {
+        return Err(format!("Co-signer server error {}: {}", response.status(), response.text().await?).into());
+    }
+
+    Ok(response.json::<Res>().await?)
+}
+
+/// Gets a new key shared with `client_pubkey` from the server, with its id. Nothing may be stored
+/// or funded before the server has proven it holds the secret key of its half.
+pub async fn new_key(client_pubkey: &PublicKey) -> Result<(String, PublicKey), Box<dyn std::error::Error>> {
+    let response: NewKeyResponse = post("key", &NewKeyRequest { client_pubkey: client_pubkey.to_string() }).await?;
+
+    let server_pubkey = check_key_proof(client_pubkey, &response)?;
+
+    Ok((response.key_id, server_pubkey))
+}
+
+/// Checks the server's proof of possession of a new key and returns the key.
+fn check_key_proof(client_pubkey: &PublicKey, response: &NewKeyResponse) -> Result<PublicKey, Box<dyn std::error::Error>> {
+    let server_pubkey = PublicKey::from_str(&response.server_pubkey)?;
+
+    let key_proof = schnorr::Signature::from_slice(&hex::decode(&response.key_proof)?)?;
+
+    Secp256k1::verification_only()
+        .verify_schnorr(&key_proof, &auth::key_proof_message(client_pubkey, &response.key_id), &server_pubkey.x_only_public_key().0)
+        .map_err(|_| "The server didn't prove it holds its key")?;
+
+    Ok(server_pubkey)
+}
+
+/// BIP340 signature by the owner key authorizing a request to `path` for `key_id` with the
+/// other request `fields`, which the server checks against the owner public key it holds for
+/// that key. Returns a fresh challenge from the server, which the request must carry, and the
+/// signature.
+pub async fn auth_sig(path: &str, key_id: &str, fields: &[&str], secret_key: &SecretKey) -> Result<(String, String), Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let response: ChallengeResponse = post("challenge", &ChallengeRequest { key_id: key_id.to_string() }).await?;
+
+    let sig = secp.sign_schnorr(&auth::message(path, key_id, &response.challenge, fields), &KeyPair::from_secret_key(&secp, secret_key));
+
+    Ok((response.challenge, hex::encode(sig.as_ref())))
+}
+
+/// Key shared with the server: the plain sum of both public keys, with no MuSig2 key-aggregation
+/// coefficients, so that the server can sign blindly.
+pub fn aggregate_pubkey(client_pubkey: &PublicKey, server_pubkey: &PublicKey) -> Result<PublicKey, Box<dyn std::error::Error>> {
+    Ok(client_pubkey.combine(server_pubkey)?)
+}
+
+/// Secret key the client signs with, whether the server has to negate its key, and the output
+/// key of the address. With P = C + S the output key is Q = ±P + tG (BIP86), so the client
+/// signs with ±c + t and the server with ±s, and both are negated again if Q has an odd Y.
+fn signing_keys(client_secret_key: &SecretKey, client_pubkey: &PublicKey, server_pubkey: &PublicKey) -> Result<(SecretKey, bool, bool, PublicKey), Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let aggregate_pubkey = aggregate_pubkey(client_pubkey, server_pubkey)?;
+    let (internal_key, internal_parity) = aggregate_pubkey.x_only_public_key();
+
+    let tweak = TapTweakHash::from_key_and_tweak(internal_key, None);
+    let tweak = Scalar::from_be_bytes(tweak.to_byte_array())?;
+
+    let negate_internal = internal_parity == Parity::Odd;
+
+    let (client_secret_key, internal_pubkey) = if negate_internal {
+        (client_secret_key.negate(), aggregate_pubkey.negate(&secp))
+    } else {
+        (*client_secret_key, aggregate_pubkey)
+    };
+
+    let client_secret_key = client_secret_key.add_tweak(&tweak)?;
+    let output_pubkey = internal_pubkey.add_exp_tweak(&secp, &tweak)?;
+
+    let negate_output = output_pubkey.x_only_public_key().1 == Parity::Odd;
+
+    Ok((client_secret_key, negate_output, negate_internal ^ negate_output, output_pubkey))
+}
+
+/// Signs `msg` for the key-path of the address of `client_secret_key` and `server_pubkey`,
+/// getting the server's half through the blinded two-round protocol. The signature is checked
+/// against the output key before it is returned.
+pub async fn sign_with_server(key_id: &str, client_secret_key: &SecretKey, server_pubkey: &PublicKey, msg: Message) -> Result<schnorr::Signature, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let client_pubkey = client_secret_key.public_key(&secp);
+
+    let (signing_key, negate_client, negate_server, output_pubkey) = signing_keys(client_secret_key, &client_pubkey, server_pubkey)?;
+    let keypair = KeyPair::from_secret_key(&secp, &signing_key);
+
+    let (challenge, auth_sig) = auth_sig("sign/first", key_id, &[], client_secret_key).await?;
+
+    let request = SignFirstRequest {
+        key_id: key_id.to_string(),
+        challenge,
+        auth_sig,
+    };
+
+    let first: SignFirstResponse = post("sign/first", &request).await?;
+
+    let server_pub_nonce = MusigPubNonce::from_slice(&hex::decode(&first.server_pubnonce)?)
+        .map_err(|e| format!("Invalid server nonce: {:?}", e))?;
+
+    let (client_sec_nonce, client_pub_nonce) = new_musig_nonce_pair(
+        &secp,
+        MusigSessionId::new(&mut rand::thread_rng()),
+        None,
+        Some(signing_key),
+        keypair.public_key(),
+        None,
+        None,
+    ).map_err(|e| format!("Nonce generation failed: {:?}", e))?;
+
+    let agg_nonce = MusigAggNonce::new(&secp, &[client_pub_nonce, server_pub_nonce]);
+
+    let blinding_factor = BlindingFactor::new(&mut rand::thread_rng());
+
+    let session = MusigSession::new_blinded_without_key_agg_cache(
+        &secp,
+        &output_pubkey,
+        agg_nonce,
+        msg,
+        None,
+        &blinding_factor,
+    );
+
+    let client_partial_sig = session
+        .partial_sign_without_keyaggcoeff(&secp, client_sec_nonce, &keypair, negate_client)
+        .map_err(|e| format!("Partial signing failed: {:?}", e))?;
+
+    let session_hex = hex::encode(session.serialize());
+
+    let fields: [&str; 3] = [&first.server_pubnonce, &session_hex, &negate_server.to_string()];
+    let (challenge, auth_sig) = auth_sig("sign/second", key_id, &fields, client_secret_key).await?;
+
+    let request = SignSecondRequest {
+        key_id: key_id.to_string(),
+        server_pubnonce: first.server_pubnonce,
+        session: session_hex,
+        negate_seckey: negate_server,
+        challenge,
+        auth_sig,
+    };
+
+    let second: SignSecondResponse = post("sign/second", &request).await?;
+
+    let server_partial_sig = MusigPartialSignature::from_slice(&hex::decode(&second.partial_sig)?)
+        .map_err(|e| format!("Invalid server partial signature: {:?}", e))?;
+
+    let sig = session.partial_sig_agg(&[client_partial_sig, server_partial_sig]);
+
+    secp.verify_schnorr(&sig, &msg, &output_pubkey.x_only_public_key().0)
+        .map_err(|e| format!("The co-signed signature is not valid: {}", e))?;
+
+    Ok(sig)
+}
+
+async fn get_next_cosigned_index(pool: &sqlx::Pool<Sqlite>) -> u32 {
+    let row = sqlx::query("SELECT MAX(bip32_index) FROM cosigned_addresses")
+        .fetch_one(pool)
+        .await
+        .unwrap();
+
+    match row.get::<Option<u32>, _>(0) {
+        Some(index) => index + 1,
+        None => 0,
+    }
+}
+
+/// Gets a new key from the co-signer server and creates the address of its sum with a new
+/// wallet key. Spending from it needs the server's (blind) cooperation.
+pub async fn new_cosigned_address(pool: &sqlx::Pool<Sqlite>, network: Network) -> Result<CosignedAddress, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let index = get_next_cosigned_index(pool).await;
+
+    let root = addresses::get_root_key(pool, network).await;
+    let derivation_path = DerivationPath::from_str(COSIGNED_KEY_PATH).unwrap()
+        .child(ChildNumber::from_normal_idx(index).unwrap());
+
+    let secret_key = root.derive_priv(&secp, &derivation_path).unwrap().private_key;
+    let public_key = secret_key.public_key(&secp);
+
+    let (key_id, server_pubkey) = new_key(&public_key).await?;
+
+    let aggregate_pubkey = aggregate_pubkey(&public_key, &server_pubkey)?;
+
+    let address = Address::p2tr(&secp, aggregate_pubkey.x_only_public_key().0, None, network);
+
+    let cosigned_address = CosignedAddress {
+        address,
+        secret_key,
+        public_key,
+        server_pubkey,
+        key_id,
+        aggregate_pubkey,
+        derivation_path: derivation_path.to_string(),
+    };
+
+    insert_cosigned_address(pool, index, &cosigned_address).await;
+
+    Ok(cosigned_address)
+}
+
+async fn insert_cosigned_address(pool: &sqlx::Pool<Sqlite>, bip32_index: u32, cosigned_address: &CosignedAddress) {
+    let query = "INSERT INTO cosigned_addresses (bip32_index, client_seckey, client_pubkey, server_pubkey, key_id, aggregate_pubkey, p2tr_address, derivation_path) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)";
+
+    let _ = sqlx::query(query)
+        .bind(bip32_index)
+        .bind(&cosigned_address.secret_key.secret_bytes().to_vec())
+        .bind(&cosigned_address.public_key.serialize().to_vec())
+        .bind(&cosigned_address.server_pubkey.serialize().to_vec())
+        .bind(&cosigned_address.key_id)
+        .bind(&cosigned_address.aggregate_pubkey.serialize().to_vec())
+        .bind(&cosigned_address.address.to_string())
+        .bind(&cosigned_address.derivation_path)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+pub async fn get_cosigned_addresses(pool: &sqlx::Pool<Sqlite>, network: Network) -> Vec::<CosignedAddress> {
+    let query = "SELECT client_seckey, client_pubkey, server_pubkey, key_id, aggregate_pubkey, p2tr_address, derivation_path FROM cosigned_addresses";
+
+    let rows = sqlx::query(query)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+
+    let mut addresses = Vec::<CosignedAddress>::new();
+
+    for row in rows {
+        let p2tr_address = row.get::<String, _>("p2tr_address");
+        let address = Address::from_str(&p2tr_address).unwrap().require_network(network).unwrap();
+
+        addresses.push(CosignedAddress {
+            address,
+            secret_key: SecretKey::from_slice(&row.get::<Vec<u8>, _>("client_seckey")).unwrap(),
+            public_key: PublicKey::from_slice(&row.get::<Vec<u8>, _>("client_pubkey")).unwrap(),
+            server_pubkey: PublicKey::from_slice(&row.get::<Vec<u8>, _>("server_pubkey")).unwrap(),
+            key_id: row.get::<String, _>("key_id"),
+            aggregate_pubkey: PublicKey::from_slice(&row.get::<Vec<u8>, _>("aggregate_pubkey")).unwrap(),
+            derivation_path: row.get::<String, _>("derivation_path"),
+        });
+    }
+
+    addresses
+}
+
+/// Builds a PSBT sending every output of `cosigned_address` to `to_address` and signs each
+/// input with the server.
+pub async fn create_signed_sweep_psbt(client: &electrum_client::Client, cosigned_address: &CosignedAddress, to_address: &Address, fee_rate: u64, options: &TxOptions) -> Result<Psbt, Box<dyn std::error::Error>> {
+    let internal_key = cosigned_address.aggregate_pubkey.x_only_public_key().0;
+    let descriptor = Descriptor::<DefiniteDescriptorKey>::from_str(&format!("tr({})", internal_key))?;
+
+    let mut psbt = scripts::create_sweep_psbt(client, &cosigned_address.address, &descriptor, to_address, fee_rate, options)?;
+
+    for index in 0..psbt.inputs.len() {
+        let (hash, hash_ty) = signer::key_spend_sighash(&psbt, index)?;
+        let msg = Message::from_slice(hash.as_byte_array())?;
+
+        let sig = sign_with_server(&cosigned_address.key_id, &cosigned_address.secret_key, &cosigned_address.server_pubkey, msg).await?;
+
+        psbt.inputs[index].tap_key_sig = Some(taproot::Signature { sig, hash_ty });
+    }
+
+    Ok(psbt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs both halves of the blinded protocol, the server's as `sign_second` does, with
+    /// random keys so that every combination of internal and output key parity comes up.
+    #[test]
+    fn blinded_signature_verifies_for_the_address() {
+        let secp = Secp256k1::new();
+
+        for _ in 0..16 {
+            let (client_secret_key, client_pubkey) = secp.generate_keypair(&mut rand::thread_rng());
+            let (server_secret_key, server_pubkey) = secp.generate_keypair(&mut rand::thread_rng());
+
+            let aggregate_pubkey = aggregate_pubkey(&client_pubkey, &server_pubkey).unwrap();
+            let address = Address::p2tr(&secp, aggregate_pubkey.x_only_public_key().0, None, Network::Regtest);
+
+            let (signing_key, negate_client, negate_server, output_pubkey) = signing_keys(&client_secret_key, &client_pubkey, &server_pubkey).unwrap();
+            assert_eq!(&address.script_pubkey().as_bytes()[2..34], &output_pubkey.x_only_public_key().0.serialize()[..]);
+
+            let client_keypair = KeyPair::from_secret_key(&secp, &signing_key);
+            let server_keypair = KeyPair::from_secret_key(&secp, &server_secret_key);
+
+            let (client_sec_nonce, client_pub_nonce) = new_musig_nonce_pair(&secp, MusigSessionId::new(&mut rand::thread_rng()), None, Some(signing_key), client_keypair.public_key(), None, None).unwrap();
+            let (server_sec_nonce, server_pub_nonce) = new_musig_nonce_pair(&secp, MusigSessionId::new(&mut rand::thread_rng()), None, Some(server_secret_key), server_pubkey, None, None).unwrap();
+
+            let msg = Message::from_slice(&[7u8; 32]).unwrap();
+            let agg_nonce = MusigAggNonce::new(&secp, &[client_pub_nonce, server_pub_nonce]);
+
+            let session = MusigSession::new_blinded_without_key_agg_cache(&secp, &output_pubkey, agg_nonce, msg, None, &BlindingFactor::new(&mut rand::thread_rng()));
+            let server_session = MusigSession::from_slice(&session.serialize()).unwrap();
+
+            let client_partial_sig = session.partial_sign_without_keyaggcoeff(&secp, client_sec_nonce, &client_keypair, negate_client).unwrap();
+            let server_partial_sig = server_session.partial_sign_without_keyaggcoeff(&secp, server_sec_nonce, &server_keypair, negate_server).unwrap();
+
+            let sig = session.partial_sig_agg(&[client_partial_sig, server_partial_sig]);
+
+            assert!(secp.verify_schnorr(&sig, &msg, &output_pubkey.x_only_public_key().0).is_ok());
+        }
+    }
+
+    #[test]
+    fn key_proof_rejects_a_rogue_server_key() {
+        let secp = Secp256k1::new();
+
+        let (_, client_pubkey) = secp.generate_keypair(&mut rand::thread_rng());
+        let (server_secret_key, server_pubkey) = secp.generate_keypair(&mut rand::thread_rng());
+
+        let key_id = "4f1d3c52-0000-4000-8000-000000000000".to_string();
+
+        let key_proof = |key_id: &str| {
+            let sig = secp.sign_schnorr(&auth::key_proof_message(&client_pubkey, key_id), &KeyPair::from_secret_key(&secp, &server_secret_key));
+            hex::encode(sig.as_ref())
+        };
+
+        let response = NewKeyResponse { key_id: key_id.clone(), server_pubkey: server_pubkey.to_string(), key_proof: key_proof(&key_id) };
+        assert_eq!(check_key_proof(&client_pubkey, &response).unwrap(), server_pubkey);
+
+        // S = S' - C makes the shared key S', which the server alone can spend, but it can't sign for S.
+        let rogue_pubkey = server_pubkey.combine(&client_pubkey.negate(&secp)).unwrap();
+        assert_eq!(aggregate_pubkey(&client_pubkey, &rogue_pubkey).unwrap(), server_pubkey);
+
+        let response = NewKeyResponse { key_id: key_id.clone(), server_pubkey: rogue_pubkey.to_string(), key_proof: key_proof(&key_id) };
+        assert!(check_key_proof(&client_pubkey, &response).is_err());
+
+        // Nor does a proof made for another key id.
+        let response = NewKeyResponse { key_id, server_pubkey: server_pubkey.to_string(), key_proof: key_proof("another key") };
+        assert!(check_key_proof(&client_pubkey, &response).is_err());
+    }
+}
@@ -0,0 +1,969 @@
+use std::{collections::HashSet, ops::Range, str::FromStr, time::{SystemTime, UNIX_EPOCH}};
+
+use bitcoin::{Address, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness, absolute, bip32::{ChildNumber, DerivationPath}, hashes::{Hash, sha256}, key::TweakedPublicKey, opcodes::all::{OP_CHECKSIG, OP_CHECKSIGVERIFY}, psbt::Psbt, script::Builder, sighash::{Prevouts, SighashCache, TapSighashType}, taproot::{ControlBlock, LeafVersion, TapLeafHash, TaprootBuilder}};
+use secp256k1_zkp::{Secp256k1, SecretKey, PublicKey, XOnlyPublicKey, KeyPair, Message, Parity, schnorr};
+use serde::{Serialize, Deserialize};
+use sqlx::{Sqlite, Row};
+
+use crate::{adaptor, addresses, backend, bip340, scripts, signer, wallet};
+
+/// Path of the keys this wallet puts in DLC funding outputs, kept apart from the receive (0)
+/// and change (1) chains so they never show up as single-key addresses.
+const DLC_KEY_PATH: &str = "m/86h/0h/0h/4";
+
+/// What an oracle publishes before the event: its key, the nonce R it will sign the outcome
+/// with and the possible outcomes. Any oracle publishing this can be used, e.g. the local
+/// stand-in in `src/bin/dlc_oracle.rs`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OracleAnnouncement {
+    pub event_id: String,
+    pub oracle_pubkey: String,
+    pub nonce: String,
+    pub outcomes: Vec<String>,
+}
+
+/// The oracle's BIP340 signature (R, s) of SHA256(outcome) with the announced nonce. s is the
+/// secret of the attestation point of that outcome.
+#[derive(Serialize, Deserialize)]
+pub struct OracleAttestation {
+    pub event_id: String,
+    pub outcome: String,
+    pub signature: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OutcomePayout {
+    pub outcome: String,
+    /// Paid to the offering party, the accepting party gets the rest of the total collateral.
+    pub offer_payout: u64,
+}
+
+/// One side of the contract: its key in the funding output, where its payouts go, and an
+/// unsigned PSBT with its funding inputs and change outputs.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DlcParty {
+    pub funding_pubkey: String,
+    pub payout_address: String,
+    pub collateral: u64,
+    pub funding_psbt: String,
+}
+
+/// Adaptor signatures of the CETs, in the order of the payouts, and signature of the refund
+/// transaction, both for the funding output's script path.
+#[derive(Serialize, Deserialize)]
+pub struct DlcSignatures {
+    pub cet_adaptor_sigs: Vec<String>,
+    pub refund_sig: String,
+}
+
+/// First message, from the offering party.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DlcOffer {
+    pub contract_id: String,
+    pub announcement: OracleAnnouncement,
+    pub payouts: Vec<OutcomePayout>,
+    pub total_collateral: u64,
+    pub fee_rate: u64,
+    pub refund_locktime: u32,
+    pub offer: DlcParty,
+}
+
+/// Answer of the accepting party.
+#[derive(Serialize, Deserialize)]
+pub struct DlcAccept {
+    pub contract_id: String,
+    pub accept: DlcParty,
+    pub signatures: DlcSignatures,
+}
+
+/// Answer of the offering party, with its funding inputs signed.
+#[derive(Serialize, Deserialize)]
+pub struct DlcSign {
+    pub contract_id: String,
+    pub signatures: DlcSignatures,
+    pub funding_psbt: String,
+}
+
+pub struct Dlc {
+    pub contract_id: String,
+    /// "offer" or "accept"
+    pub role: String,
+    pub secret_key: SecretKey,
+    pub offer: DlcOffer,
+    pub accept: Option<DlcParty>,
+    pub counterparty_signatures: Option<DlcSignatures>,
+    pub status: String,
+}
+
+/// Every transaction of the contract, which both parties build the same way from the offer
+/// and the accepting party's side.
+pub struct DlcTransactions {
+    pub funding_psbt: Psbt,
+    pub funding_output: TxOut,
+    pub funding_leaf: ScriptBuf,
+    pub control_block: ControlBlock,
+    /// One for each payout, in the same order.
+    pub cets: Vec<Transaction>,
+    pub refund: Transaction,
+}
+
+/// Key pair at `index` of the DLC chain.
+async fn derive_dlc_key(pool: &sqlx::Pool<Sqlite>, network: Network, index: u32) -> SecretKey {
+    let secp = Secp256k1::new();
+
+    let root = addresses::get_root_key(pool, network).await;
+
+    let derivation_path = DerivationPath::from_str(DLC_KEY_PATH).unwrap()
+        .child(ChildNumber::from_normal_idx(index).unwrap());
+
+    root.derive_priv(&secp, &derivation_path).unwrap().private_key
+}
+
+async fn get_next_dlc_index(pool: &sqlx::Pool<Sqlite>) -> u32 {
+    let row = sqlx::query("SELECT MAX(bip32_index) FROM dlcs")
+        .fetch_one(pool)
+        .await
+        .unwrap();
+
+    match row.get::<Option<u32>, _>(0) {
+        Some(index) => index + 1,
+        None => 0,
+    }
+}
+
+/// Attestation point S = R + eP of `outcome`, whose secret the oracle reveals by signing it.
+pub fn attestation_point(announcement: &OracleAnnouncement, outcome: &str) -> Result<PublicKey, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let oracle_pubkey = XOnlyPublicKey::from_str(&announcement.oracle_pubkey)?;
+    let nonce = XOnlyPublicKey::from_str(&announcement.nonce)?;
+
+    let msg = sha256::Hash::hash(outcome.as_bytes()).to_byte_array();
+    let e = bip340::challenge(&nonce, &oracle_pubkey, &msg)?;
+
+    let point = nonce.public_key(Parity::Even).combine(&oracle_pubkey.public_key(Parity::Even).mul_tweak(&secp, &e)?)?;
+
+    Ok(point)
+}
+
+/// Checks the attestation against the announcement and returns the secret of the attested
+/// outcome's point.
+pub fn attestation_secret(announcement: &OracleAnnouncement, attestation: &OracleAttestation) -> Result<SecretKey, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::verification_only();
+
+    if attestation.event_id != announcement.event_id {
+        return Err("The attestation is for another event".into());
+    }
+
+    let signature = schnorr::Signature::from_slice(&hex::decode(&attestation.signature)?)?;
+    let bytes = &signature[..];
+
+    if bytes[..32] != XOnlyPublicKey::from_str(&announcement.nonce)?.serialize() {
+        return Err("The attestation doesn't use the announced nonce".into());
+    }
+
+    let msg = Message::from_slice(sha256::Hash::hash(attestation.outcome.as_bytes()).as_byte_array())?;
+    secp.verify_schnorr(&signature, &msg, &XOnlyPublicKey::from_str(&announcement.oracle_pubkey)?)?;
+
+    Ok(SecretKey::from_slice(&bytes[32..])?)
+}
+
+/// 2-of-2 leaf `<K1> OP_CHECKSIGVERIFY <K2> OP_CHECKSIG` with the keys in lexicographic order,
+/// under the unspendable internal key so that only the leaf can spend the funding output.
+fn funding_leaf(keys: &[XOnlyPublicKey; 2]) -> Result<(ScriptBuf, ControlBlock, ScriptBuf), Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let leaf = Builder::new()
+        .push_x_only_key(&keys[0])
+        .push_opcode(OP_CHECKSIGVERIFY)
+        .push_x_only_key(&keys[1])
+        .push_opcode(OP_CHECKSIG)
+        .into_script();
+
+    let internal_key = XOnlyPublicKey::from_str(scripts::UNSPENDABLE_KEY)?;
+
+    let spend_info = TaprootBuilder::new()
+        .add_leaf(0, leaf.clone())?
+        .finalize(&secp, internal_key)
+        .map_err(|_| "Invalid funding tree")?;
+
+    let control_block = spend_info
+        .control_block(&(leaf.clone(), LeafVersion::TapScript))
+        .ok_or("Missing control block")?;
+
+    Ok((leaf, control_block, ScriptBuf::new_v1_p2tr_tweaked(spend_info.output_key())))
+}
+
+fn sorted_keys(offer_key: XOnlyPublicKey, accept_key: XOnlyPublicKey) -> [XOnlyPublicKey; 2] {
+    let mut keys = [offer_key, accept_key];
+    keys.sort_by_key(|key| key.serialize());
+    keys
+}
+
+/// Fee of a CET or of the refund transaction, estimated with both payouts and a placeholder
+/// witness of the 2-of-2 leaf: two signatures, the leaf and the control block.
+fn payout_tx_fee(fee_rate: u64, outputs: &[TxOut]) -> u64 {
+    let mut witness = Witness::new();
+    witness.push([0u8; 64]);
+    witness.push([0u8; 64]);
+    witness.push([0u8; 68]);
+    witness.push([0u8; 33]);
+
+    let tx = Transaction {
+        version: 2,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn { witness, ..Default::default() }],
+        output: outputs.to_vec(),
+    };
+
+    tx.vsize() as u64 * fee_rate
+}
+
+/// Amount each party puts in the funding output: its collateral and half of the fee of the
+/// CET or refund transaction that will spend it, the offering party paying the odd satoshi.
+fn funding_contribution(collateral: u64, payout_fee: u64, is_offer: bool) -> u64 {
+    collateral + payout_fee / 2 + if is_offer { payout_fee % 2 } else { 0 }
+}
+
+/// Transaction spending the funding output to the payouts above the dust limit.
+fn payout_tx(funding_outpoint: OutPoint, lock_time: absolute::LockTime, payouts: &[(ScriptBuf, u64)]) -> Transaction {
+    let output = payouts
+        .iter()
+        .filter(|(script_pubkey, value)| *value >= script_pubkey.dust_value().to_sat())
+        .map(|(script_pubkey, value)| TxOut { value: *value, script_pubkey: script_pubkey.clone() })
+        .collect();
+
+    Transaction {
+        version: 2,
+        lock_time,
+        input: vec![TxIn {
+            previous_output: funding_outpoint,
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_LOCKTIME_NO_RBF,
+            witness: Witness::default(),
+        }],
+        output,
+    }
+}
+
+/// Checks that the payouts cover every announced outcome once and fit in the total collateral.
+fn validate_offer(offer: &DlcOffer) -> Result<(), Box<dyn std::error::Error>> {
+    for outcome in &offer.announcement.outcomes {
+        let count = offer.payouts.iter().filter(|payout| &payout.outcome == outcome).count();
+        if count != 1 {
+            return Err(format!("Outcome {} needs exactly one payout", outcome).into());
+        }
+    }
+
+    if offer.payouts.len() != offer.announcement.outcomes.len() {
+        return Err("Payouts for outcomes the oracle didn't announce".into());
+    }
+
+    if let Some(payout) = offer.payouts.iter().find(|payout| payout.offer_payout > offer.total_collateral) {
+        return Err(format!("The payout of {} is more than the total collateral", payout.outcome).into());
+    }
+
+    if offer.offer.collateral > offer.total_collateral {
+        return Err("The offer collateral is more than the total collateral".into());
+    }
+
+    if offer.refund_locktime == 0 {
+        return Err("The refund transaction needs a locktime".into());
+    }
+
+    Ok(())
+}
+
+/// Checks that the refund locktime, a height or a UNIX time, hasn't passed yet: a refund valid
+/// right away would let either party take its collateral back before the oracle attests.
+fn check_refund_locktime(refund_locktime: u32, tip_height: u32) -> Result<(), Box<dyn std::error::Error>> {
+    let passed = match absolute::LockTime::from_consensus(refund_locktime) {
+        absolute::LockTime::Blocks(height) => height.to_consensus_u32() <= tip_height,
+        absolute::LockTime::Seconds(time) => u64::from(time.to_consensus_u32()) <= SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+    };
+
+    if passed {
+        return Err("The refund locktime has already passed".into());
+    }
+
+    Ok(())
+}
+
+/// Checks that a party's funding inputs pay for its contribution on top of its change outputs,
+/// and that they are all segwit, as a malleated funding txid would void every signed CET.
+fn check_party_funding(psbt: &Psbt, contribution: u64, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input_amount = 0;
+    for input in &psbt.inputs {
+        let witness_utxo = input.witness_utxo.as_ref().ok_or_else(|| format!("Missing witness_utxo in the {} inputs", name))?;
+
+        if !witness_utxo.script_pubkey.is_witness_program() {
+            return Err(format!("The {} funding inputs must all spend segwit outputs", name).into());
+        }
+
+        input_amount += witness_utxo.value;
+    }
+
+    let change_amount: u64 = psbt.unsigned_tx.output.iter().map(|output| output.value).sum();
+
+    if input_amount < change_amount + contribution {
+        return Err(format!("The {} funding inputs don't cover its collateral and fees", name).into());
+    }
+
+    Ok(())
+}
+
+/// Builds the funding transaction, with the offering party's inputs and change first, and
+/// the CETs and refund transaction spending its first output.
+pub fn build_transactions(offer: &DlcOffer, accept: &DlcParty, network: Network) -> Result<DlcTransactions, Box<dyn std::error::Error>> {
+    validate_offer(offer)?;
+
+    if offer.offer.collateral + accept.collateral != offer.total_collateral {
+        return Err("The collaterals don't add up to the total collateral".into());
+    }
+
+    let offer_key = XOnlyPublicKey::from_str(&offer.offer.funding_pubkey)?;
+    let accept_key = XOnlyPublicKey::from_str(&accept.funding_pubkey)?;
+
+    let (funding_leaf, control_block, funding_script) = funding_leaf(&sorted_keys(offer_key, accept_key))?;
+
+    let offer_script = Address::from_str(&offer.offer.payout_address)?.require_network(network)?.script_pubkey();
+    let accept_script = Address::from_str(&accept.payout_address)?.require_network(network)?.script_pubkey();
+
+    let payout_fee = payout_tx_fee(offer.fee_rate, &[
+        TxOut { value: 0, script_pubkey: offer_script.clone() },
+        TxOut { value: 0, script_pubkey: accept_script.clone() },
+    ]);
+
+    let offer_psbt = Psbt::from_str(&offer.offer.funding_psbt)?;
+    let accept_psbt = Psbt::from_str(&accept.funding_psbt)?;
+
+    check_party_funding(&offer_psbt, funding_contribution(offer.offer.collateral, payout_fee, true), "offer")?;
+    check_party_funding(&accept_psbt, funding_contribution(accept.collateral, payout_fee, false), "accept")?;
+
+    let mut outpoints = HashSet::new();
+    for txin in offer_psbt.unsigned_tx.input.iter().chain(accept_psbt.unsigned_tx.input.iter()) {
+        if !outpoints.insert(txin.previous_output) {
+            return Err(format!("The funding inputs spend {} twice", txin.previous_output).into());
+        }
+    }
+
+    let funding_output = TxOut { value: offer.total_collateral + payout_fee, script_pubkey: funding_script };
+
+    let mut output = vec![funding_output.clone()];
+    output.extend(offer_psbt.unsigned_tx.output.iter().cloned());
+    output.extend(accept_psbt.unsigned_tx.output.iter().cloned());
+
+    let funding_tx = Transaction {
+        version: 2,
+        lock_time: absolute::LockTime::ZERO,
+        input: offer_psbt.unsigned_tx.input.iter().chain(accept_psbt.unsigned_tx.input.iter()).cloned().collect(),
+        output,
+    };
+
+    let mut funding_psbt = Psbt::from_unsigned_tx(funding_tx)?;
+    funding_psbt.inputs = offer_psbt.inputs.into_iter().chain(accept_psbt.inputs).collect();
+
+    let funding_outpoint = OutPoint { txid: funding_psbt.unsigned_tx.txid(), vout: 0 };
+
+    let cets = offer.payouts
+        .iter()
+        .map(|payout| payout_tx(funding_outpoint, absolute::LockTime::ZERO, &[
+            (offer_script.clone(), payout.offer_payout),
+            (accept_script.clone(), offer.total_collateral - payout.offer_payout),
+        ]))
+        .collect();
+
+    let refund = payout_tx(funding_outpoint, absolute::LockTime::from_consensus(offer.refund_locktime), &[
+        (offer_script, offer.offer.collateral),
+        (accept_script, accept.collateral),
+    ]);
+
+    Ok(DlcTransactions { funding_psbt, funding_output, funding_leaf, control_block, cets, refund })
+}
+
+/// Script-path sighash of the single input of a CET or of the refund transaction.
+fn payout_sighash(txs: &DlcTransactions, tx: &Transaction) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+    let leaf_hash = TapLeafHash::from_script(&txs.funding_leaf, LeafVersion::TapScript);
+
+    let hash = SighashCache::new(tx).taproot_script_spend_signature_hash(
+        0,
+        &Prevouts::All(&[txs.funding_output.clone()]),
+        leaf_hash,
+        TapSighashType::Default,
+    )?;
+
+    Ok(hash.to_byte_array())
+}
+
+/// Adaptor-signs every CET under the attestation point of its outcome, so that the
+/// counterparty can only complete the signature of the CET the oracle attests, and signs the
+/// refund transaction.
+fn sign_transactions(txs: &DlcTransactions, offer: &DlcOffer, secret_key: &SecretKey) -> Result<DlcSignatures, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let keypair = KeyPair::from_secret_key(&secp, secret_key);
+
+    let mut cet_adaptor_sigs = Vec::<String>::new();
+
+    for (cet, payout) in txs.cets.iter().zip(&offer.payouts) {
+        let adaptor_point = attestation_point(&offer.announcement, &payout.outcome)?;
+        let msg = payout_sighash(txs, cet)?;
+
+        let adaptor_sig = adaptor::create(&keypair, &msg, &adaptor_point)?;
+        cet_adaptor_sigs.push(hex::encode(adaptor_sig.serialize()));
+    }
+
+    let msg = Message::from_slice(&payout_sighash(txs, &txs.refund)?)?;
+    let refund_sig = secp.sign_schnorr(&msg, &keypair);
+
+    Ok(DlcSignatures { cet_adaptor_sigs, refund_sig: hex::encode(&refund_sig[..]) })
+}
+
+fn verify_signatures(txs: &DlcTransactions, offer: &DlcOffer, public_key: &XOnlyPublicKey, signatures: &DlcSignatures) -> Result<(), Box<dyn std::error::Error>> {
+    let secp = Secp256k1::verification_only();
+
+    if signatures.cet_adaptor_sigs.len() != txs.cets.len() {
+        return Err("Wrong number of CET adaptor signatures".into());
+    }
+
+    for ((cet, payout), adaptor_sig) in txs.cets.iter().zip(&offer.payouts).zip(&signatures.cet_adaptor_sigs) {
+        let adaptor_point = attestation_point(&offer.announcement, &payout.outcome)?;
+        let msg = payout_sighash(txs, cet)?;
+
+        let adaptor_sig = adaptor::AdaptorSignature::from_slice(&hex::decode(adaptor_sig)?)?;
+
+        adaptor::verify(public_key, &msg, &adaptor_point, &adaptor_sig).map_err(|e| format!("CET of {}: {}", payout.outcome, e))?;
+    }
+
+    let msg = Message::from_slice(&payout_sighash(txs, &txs.refund)?)?;
+    let refund_sig = schnorr::Signature::from_slice(&hex::decode(&signatures.refund_sig)?)?;
+
+    secp.verify_schnorr(&refund_sig, &msg, public_key).map_err(|e| format!("Refund transaction: {}", e))?;
+
+    Ok(())
+}
+
+/// Witness of the 2-of-2 leaf. The signature of the first key is checked first, so it goes on
+/// top of the stack, i.e. last.
+fn payout_witness(txs: &DlcTransactions, signatures: [(XOnlyPublicKey, schnorr::Signature); 2]) -> Witness {
+    let mut signatures = signatures;
+    signatures.sort_by_key(|(key, _)| key.serialize());
+
+    let mut witness = Witness::new();
+    witness.push(&signatures[1].1[..]);
+    witness.push(&signatures[0].1[..]);
+    witness.push(txs.funding_leaf.as_bytes());
+    witness.push(txs.control_block.serialize());
+
+    witness
+}
+
+/// Unsigned PSBT of this party's funding inputs and change, paying `amount` plus the fee of its
+/// own inputs and change. The transaction overhead is paid by both parties, which covers the
+/// shared funding output. Unlike `wallet::select_coins`, the target grows with every input.
+async fn fund_party(pool: &sqlx::Pool<Sqlite>, client: &electrum_client::Client, network: Network, amount: u64, fee_rate: u64) -> Result<Psbt, Box<dyn std::error::Error>> {
+    // Every P2TR output has the same size, so the change key is only derived if change is kept.
+    let output_key = XOnlyPublicKey::from_str(scripts::UNSPENDABLE_KEY)?;
+    let change = TxOut { value: 0, script_pubkey: ScriptBuf::new_v1_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(output_key)) };
+
+    let fee = |num_inputs: usize| wallet::estimate_vsize(num_inputs, &[change.clone()]) * fee_rate;
+
+    let mut list_unspent = wallet::get_list_unspent(pool, client, network).await;
+    list_unspent.sort_by(|a, b| a.value.cmp(&b.value));
+
+    let mut inputs = Vec::<wallet::AddressInfo>::new();
+    let mut input_amount: u64 = 0;
+
+    for utxo in list_unspent {
+        if input_amount >= amount + fee(inputs.len()) {
+            break;
+        }
+        input_amount += utxo.value;
+        inputs.push(utxo);
+    }
+
+    let fee = fee(inputs.len());
+
+    if inputs.is_empty() || input_amount < amount + fee {
+        return Err("Not enough funds".into());
+    }
+
+    let mut outputs = Vec::<TxOut>::new();
+    let mut change_index = None;
+
+    let change_amount = input_amount - amount - fee;
+    if change_amount >= wallet::p2tr_dust_value() {
+        let (_, change_address, _) = addresses::generate_new_key(pool, network, true).await;
+
+        outputs.push(TxOut { value: change_amount, script_pubkey: change_address.script_pubkey() });
+        change_index = Some(0);
+    }
+
+    let options = wallet::TxOptions { ordering: wallet::TxOrdering::Unordered, ..Default::default() };
+
+    let (psbt, _) = wallet::create_p2tr_key_spend_psbt(&inputs, &outputs, change_index, &options)?;
+
+    Ok(psbt)
+}
+
+/// Payout of the offering party for each outcome, given as `outcome:amount`.
+pub fn parse_payouts(payouts: &[String]) -> Result<Vec<OutcomePayout>, Box<dyn std::error::Error>> {
+    let mut result = Vec::<OutcomePayout>::new();
+
+    for payout in payouts {
+        let (outcome, amount) = payout.rsplit_once(':').ok_or_else(|| format!("Invalid payout {}, expected outcome:amount", payout))?;
+
+        result.push(OutcomePayout { outcome: outcome.to_string(), offer_payout: amount.parse()? });
+    }
+
+    Ok(result)
+}
+
+/// Offers a contract on the announced event, putting up `collateral` out of `total_collateral`.
+pub async fn offer(pool: &sqlx::Pool<Sqlite>, client: &electrum_client::Client, network: Network, announcement: OracleAnnouncement, payouts: Vec<OutcomePayout>, collateral: u64, total_collateral: u64, fee_rate: u64, refund_locktime: u32) -> Result<DlcOffer, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let index = get_next_dlc_index(pool).await;
+    let secret_key = derive_dlc_key(pool, network, index).await;
+
+    let (_, payout_address, _) = addresses::generate_new_key(pool, network, false).await;
+
+    let mut offer = DlcOffer {
+        contract_id: uuid::Uuid::new_v4().to_string(),
+        announcement,
+        payouts,
+        total_collateral,
+        fee_rate,
+        refund_locktime,
+        offer: DlcParty {
+            funding_pubkey: secret_key.x_only_public_key(&secp).0.to_string(),
+            payout_address: payout_address.to_string(),
+            collateral,
+            funding_psbt: String::new(),
+        },
+    };
+
+    validate_offer(&offer)?;
+    check_refund_locktime(refund_locktime, backend::get_tip_height(client))?;
+
+    // The CET fee is paid for the case where both payouts get an output.
+    let payout_fee = payout_tx_fee(fee_rate, &vec![TxOut { value: 0, script_pubkey: payout_address.script_pubkey() }; 2]);
+
+    let funding_psbt = fund_party(pool, client, network, funding_contribution(collateral, payout_fee, true), fee_rate).await?;
+    offer.offer.funding_psbt = funding_psbt.to_string();
+
+    insert_dlc(pool, index, &secret_key, &offer.contract_id, "offer", &offer).await;
+
+    Ok(offer)
+}
+
+/// Accepts an offer with the rest of the total collateral, and signs the CETs and the refund
+/// transaction for the offering party.
+pub async fn accept(pool: &sqlx::Pool<Sqlite>, client: &electrum_client::Client, network: Network, offer: DlcOffer) -> Result<DlcAccept, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    validate_offer(&offer)?;
+    check_refund_locktime(offer.refund_locktime, backend::get_tip_height(client))?;
+
+    let index = get_next_dlc_index(pool).await;
+    let secret_key = derive_dlc_key(pool, network, index).await;
+
+    let (_, payout_address, _) = addresses::generate_new_key(pool, network, false).await;
+
+    let collateral = offer.total_collateral - offer.offer.collateral;
+
+    let offer_script = Address::from_str(&offer.offer.payout_address)?.require_network(network)?.script_pubkey();
+    let payout_fee = payout_tx_fee(offer.fee_rate, &[
+        TxOut { value: 0, script_pubkey: offer_script },
+        TxOut { value: 0, script_pubkey: payout_address.script_pubkey() },
+    ]);
+
+    let funding_psbt = fund_party(pool, client, network, funding_contribution(collateral, payout_fee, false), offer.fee_rate).await?;
+
+    let accept = DlcParty {
+        funding_pubkey: secret_key.x_only_public_key(&secp).0.to_string(),
+        payout_address: payout_address.to_string(),
+        collateral,
+        funding_psbt: funding_psbt.to_string(),
+    };
+
+    let txs = build_transactions(&offer, &accept, network)?;
+    let signatures = sign_transactions(&txs, &offer, &secret_key)?;
+
+    insert_dlc(pool, index, &secret_key, &offer.contract_id, "accept", &offer).await;
+    update_dlc(pool, &offer.contract_id, "accept", &accept, None, "accepted").await;
+
+    Ok(DlcAccept { contract_id: offer.contract_id, accept, signatures })
+}
+
+/// Signs our funding inputs, `own_inputs` of the funding PSBT, and nothing else. A counterparty
+/// input with one of our keys is refused, as signing it would pay for the counterparty's side.
+async fn sign_own_inputs(pool: &sqlx::Pool<Sqlite>, network: Network, funding_psbt: &mut Psbt, own_inputs: Range<usize>) -> Result<(), Box<dyn std::error::Error>> {
+    let root = addresses::get_root_key(pool, network).await;
+    let mut secret_keys = wallet::get_wallet_secret_keys(pool, network).await;
+    secret_keys.extend(signer::secret_keys_from_origins(funding_psbt, &root));
+
+    let mut own_psbt = funding_psbt.clone();
+
+    for (index, input) in own_psbt.inputs.iter_mut().enumerate() {
+        if own_inputs.contains(&index) {
+            continue;
+        }
+
+        if input.tap_internal_key.iter().chain(input.tap_key_origins.keys()).any(|key| secret_keys.contains_key(key)) {
+            return Err(format!("Funding input {} of the counterparty has one of our keys", index).into());
+        }
+
+        input.tap_internal_key = None;
+    }
+
+    let signed_inputs = signer::sign_psbt(&mut own_psbt, &secret_keys)?;
+    if own_inputs.clone().any(|index| !signed_inputs.contains(&index)) {
+        return Err("Couldn't sign every one of our funding inputs".into());
+    }
+
+    for index in own_inputs {
+        funding_psbt.inputs[index] = own_psbt.inputs[index].clone();
+    }
+
+    Ok(())
+}
+
+/// Checks the accepting party's signatures, then signs the CETs, the refund transaction and our
+/// funding inputs for it. Nothing is signed for the funding output before we can get out of it.
+pub async fn sign(pool: &sqlx::Pool<Sqlite>, network: Network, accept: DlcAccept) -> Result<DlcSign, Box<dyn std::error::Error>> {
+    let dlc = get_dlc(pool, &accept.contract_id, "offer").await.ok_or("Unknown contract")?;
+
+    if dlc.status != "offered" {
+        return Err(format!("The contract is {}", dlc.status).into());
+    }
+
+    let mut txs = build_transactions(&dlc.offer, &accept.accept, network)?;
+
+    verify_signatures(&txs, &dlc.offer, &XOnlyPublicKey::from_str(&accept.accept.funding_pubkey)?, &accept.signatures)?;
+
+    let signatures = sign_transactions(&txs, &dlc.offer, &dlc.secret_key)?;
+
+    let offer_inputs = Psbt::from_str(&dlc.offer.offer.funding_psbt)?.inputs.len();
+
+    sign_own_inputs(pool, network, &mut txs.funding_psbt, 0..offer_inputs).await?;
+
+    update_dlc(pool, &dlc.contract_id, "offer", &accept.accept, Some(&accept.signatures), "signed").await;
+
+    Ok(DlcSign { contract_id: dlc.contract_id, signatures, funding_psbt: txs.funding_psbt.to_string() })
+}
+
+/// Checks the offering party's signatures, signs our funding inputs and returns the funding
+/// transaction, ready to broadcast.
+pub async fn fund(pool: &sqlx::Pool<Sqlite>, network: Network, sign: DlcSign) -> Result<Transaction, Box<dyn std::error::Error>> {
+    let dlc = get_dlc(pool, &sign.contract_id, "accept").await.ok_or("Unknown contract")?;
+
+    // Funding again rebuilds the same transaction, in case its broadcast failed.
+    if dlc.status != "accepted" && dlc.status != "funded" {
+        return Err(format!("The contract is {}", dlc.status).into());
+    }
+
+    let accept = dlc.accept.as_ref().ok_or("The contract was not accepted")?;
+
+    let txs = build_transactions(&dlc.offer, accept, network)?;
+
+    verify_signatures(&txs, &dlc.offer, &XOnlyPublicKey::from_str(&dlc.offer.offer.funding_pubkey)?, &sign.signatures)?;
+
+    let offer_psbt = Psbt::from_str(&sign.funding_psbt)?;
+    if offer_psbt.unsigned_tx != txs.funding_psbt.unsigned_tx {
+        return Err("The funding transaction doesn't match the contract".into());
+    }
+
+    let offer_inputs = Psbt::from_str(&dlc.offer.offer.funding_psbt)?.inputs.len();
+
+    // Only the offering party's signatures are taken from its PSBT, the rest is what we built.
+    let mut funding_psbt = txs.funding_psbt;
+    for index in 0..offer_inputs {
+        funding_psbt.inputs[index].tap_key_sig = offer_psbt.inputs[index].tap_key_sig;
+        funding_psbt.inputs[index].final_script_witness = offer_psbt.inputs[index].final_script_witness.clone();
+    }
+
+    let num_inputs = funding_psbt.inputs.len();
+    sign_own_inputs(pool, network, &mut funding_psbt, offer_inputs..num_inputs).await?;
+
+    signer::finalize_psbt(&mut funding_psbt)?;
+    let funding_tx = signer::extract_tx(funding_psbt)?;
+
+    update_dlc(pool, &dlc.contract_id, "accept", accept, Some(&sign.signatures), "funded").await;
+
+    Ok(funding_tx)
+}
+
+/// The contract `contract_id` as offered, or else as accepted, by this wallet.
+async fn get_signed_dlc(pool: &sqlx::Pool<Sqlite>, contract_id: &str) -> Result<Dlc, Box<dyn std::error::Error>> {
+    let dlc = match get_dlc(pool, contract_id, "offer").await {
+        Some(dlc) => dlc,
+        None => get_dlc(pool, contract_id, "accept").await.ok_or("Unknown contract")?,
+    };
+
+    if dlc.status != "signed" && dlc.status != "funded" {
+        return Err(format!("The contract is {}", dlc.status).into());
+    }
+
+    Ok(dlc)
+}
+
+fn counterparty_pubkey(dlc: &Dlc, accept: &DlcParty) -> Result<XOnlyPublicKey, Box<dyn std::error::Error>> {
+    let key = if dlc.role == "offer" { &accept.funding_pubkey } else { &dlc.offer.offer.funding_pubkey };
+
+    Ok(XOnlyPublicKey::from_str(key)?)
+}
+
+/// Completes the counterparty's adaptor signature of the attested outcome's CET with the
+/// oracle's attestation and returns the CET signed by both parties.
+pub async fn settle(pool: &sqlx::Pool<Sqlite>, network: Network, contract_id: &str, attestation: &OracleAttestation) -> Result<Transaction, Box<dyn std::error::Error>> {
+    let dlc = get_signed_dlc(pool, contract_id).await?;
+
+    signed_cet(&dlc, network, attestation)
+}
+
+fn signed_cet(dlc: &Dlc, network: Network, attestation: &OracleAttestation) -> Result<Transaction, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let accept = dlc.accept.as_ref().ok_or("The contract was not accepted")?;
+    let signatures = dlc.counterparty_signatures.as_ref().ok_or("Missing the counterparty signatures")?;
+
+    let secret = attestation_secret(&dlc.offer.announcement, attestation)?;
+
+    let index = dlc.offer.payouts
+        .iter()
+        .position(|payout| payout.outcome == attestation.outcome)
+        .ok_or("No CET for the attested outcome")?;
+
+    let txs = build_transactions(&dlc.offer, accept, network)?;
+    let mut cet = txs.cets[index].clone();
+
+    let adaptor_sig = adaptor::AdaptorSignature::from_slice(&hex::decode(&signatures.cet_adaptor_sigs[index])?)?;
+    let counterparty_sig = adaptor::complete(&adaptor_sig, &secret)?;
+
+    let keypair = KeyPair::from_secret_key(&secp, &dlc.secret_key);
+    let msg = Message::from_slice(&payout_sighash(&txs, &cet)?)?;
+    let own_sig = secp.sign_schnorr(&msg, &keypair);
+
+    let counterparty_key = counterparty_pubkey(dlc, accept)?;
+    secp.verify_schnorr(&counterparty_sig, &msg, &counterparty_key)?;
+
+    cet.input[0].witness = payout_witness(&txs, [(keypair.x_only_public_key().0, own_sig), (counterparty_key, counterparty_sig)]);
+
+    Ok(cet)
+}
+
+/// Refund transaction signed by both parties, which can be broadcast once the chain reaches
+/// the refund locktime if the oracle never attests.
+pub async fn refund(pool: &sqlx::Pool<Sqlite>, network: Network, contract_id: &str) -> Result<Transaction, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let dlc = get_signed_dlc(pool, contract_id).await?;
+    let accept = dlc.accept.as_ref().ok_or("The contract was not accepted")?;
+    let signatures = dlc.counterparty_signatures.as_ref().ok_or("Missing the counterparty signatures")?;
+
+    let txs = build_transactions(&dlc.offer, accept, network)?;
+    let mut refund = txs.refund.clone();
+
+    let keypair = KeyPair::from_secret_key(&secp, &dlc.secret_key);
+    let msg = Message::from_slice(&payout_sighash(&txs, &refund)?)?;
+    let own_sig = secp.sign_schnorr(&msg, &keypair);
+
+    let counterparty_sig = schnorr::Signature::from_slice(&hex::decode(&signatures.refund_sig)?)?;
+
+    refund.input[0].witness = payout_witness(&txs, [(keypair.x_only_public_key().0, own_sig), (counterparty_pubkey(&dlc, accept)?, counterparty_sig)]);
+
+    Ok(refund)
+}
+
+async fn insert_dlc(pool: &sqlx::Pool<Sqlite>, bip32_index: u32, secret_key: &SecretKey, contract_id: &str, role: &str, offer: &DlcOffer) {
+    let query = "INSERT INTO dlcs (contract_id, role, bip32_index, funding_seckey, offer, status) VALUES ($1, $2, $3, $4, $5, $6)";
+
+    let _ = sqlx::query(query)
+        .bind(contract_id)
+        .bind(role)
+        .bind(bip32_index)
+        .bind(&secret_key.secret_bytes().to_vec())
+        .bind(serde_json::to_string(offer).unwrap())
+        .bind("offered")
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+async fn update_dlc(pool: &sqlx::Pool<Sqlite>, contract_id: &str, role: &str, accept: &DlcParty, counterparty_signatures: Option<&DlcSignatures>, status: &str) {
+    let query = "UPDATE dlcs SET accept = $1, counterparty_signatures = $2, status = $3 WHERE contract_id = $4 AND role = $5";
+
+    let _ = sqlx::query(query)
+        .bind(serde_json::to_string(accept).unwrap())
+        .bind(counterparty_signatures.map(|signatures| serde_json::to_string(signatures).unwrap()))
+        .bind(status)
+        .bind(contract_id)
+        .bind(role)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+/// Marks the contract as settled or refunded, once its CET or refund transaction is broadcast.
+pub async fn close(pool: &sqlx::Pool<Sqlite>, contract_id: &str, status: &str) {
+    let query = "UPDATE dlcs SET status = $1 WHERE contract_id = $2 AND status IN ('signed', 'funded')";
+
+    let _ = sqlx::query(query)
+        .bind(status)
+        .bind(contract_id)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+pub async fn get_dlcs(pool: &sqlx::Pool<Sqlite>) -> Vec::<Dlc> {
+    let query = "SELECT contract_id, role, funding_seckey, offer, accept, counterparty_signatures, status FROM dlcs";
+
+    let rows = sqlx::query(query)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+
+    let mut dlcs = Vec::<Dlc>::new();
+
+    for row in rows {
+        dlcs.push(Dlc {
+            contract_id: row.get::<String, _>("contract_id"),
+            role: row.get::<String, _>("role"),
+            secret_key: SecretKey::from_slice(&row.get::<Vec<u8>, _>("funding_seckey")).unwrap(),
+            offer: serde_json::from_str(&row.get::<String, _>("offer")).unwrap(),
+            accept: row.get::<Option<String>, _>("accept").map(|accept| serde_json::from_str(&accept).unwrap()),
+            counterparty_signatures: row.get::<Option<String>, _>("counterparty_signatures").map(|signatures| serde_json::from_str(&signatures).unwrap()),
+            status: row.get::<String, _>("status"),
+        });
+    }
+
+    dlcs
+}
+
+pub async fn get_dlc(pool: &sqlx::Pool<Sqlite>, contract_id: &str, role: &str) -> Option<Dlc> {
+    get_dlcs(pool).await
+        .into_iter()
+        .find(|dlc| dlc.contract_id == contract_id && dlc.role == role)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::Txid;
+    use secp256k1_zkp::Scalar;
+
+    use super::*;
+
+    /// The even-Y secret key of a random key, as a BIP340 signer uses.
+    fn even_secret_key() -> (SecretKey, XOnlyPublicKey) {
+        let secp = Secp256k1::new();
+
+        let secret_key = SecretKey::new(&mut rand::thread_rng());
+        let (public_key, parity) = secret_key.x_only_public_key(&secp);
+
+        match parity {
+            Parity::Even => (secret_key, public_key),
+            Parity::Odd => (secret_key.negate(), public_key),
+        }
+    }
+
+    fn party(secret_key: &SecretKey, collateral: u64, vout: u32) -> DlcParty {
+        let secp = Secp256k1::new();
+
+        let (public_key, _) = secret_key.x_only_public_key(&secp);
+        let payout_address = Address::p2tr(&secp, public_key, None, Network::Regtest);
+
+        let tx = Transaction {
+            version: 2,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn { previous_output: OutPoint { txid: Txid::all_zeros(), vout }, ..Default::default() }],
+            output: vec![],
+        };
+
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut { value: 100_000, script_pubkey: payout_address.script_pubkey() });
+
+        DlcParty {
+            funding_pubkey: public_key.to_string(),
+            payout_address: payout_address.to_string(),
+            collateral,
+            funding_psbt: psbt.to_string(),
+        }
+    }
+
+    /// Runs a contract against an oracle that attests the way `src/bin/dlc_oracle.rs` does, and
+    /// checks that the CET completed from the attestation carries valid signatures of both keys.
+    #[test]
+    fn settle_with_oracle_attestation() {
+        let secp = Secp256k1::new();
+
+        let (oracle_seckey, oracle_pubkey) = even_secret_key();
+        let (nonce_seckey, nonce) = even_secret_key();
+
+        let announcement = OracleAnnouncement {
+            event_id: "coin-flip".to_string(),
+            oracle_pubkey: oracle_pubkey.to_string(),
+            nonce: nonce.to_string(),
+            outcomes: vec!["heads".to_string(), "tails".to_string()],
+        };
+
+        let offer_seckey = SecretKey::new(&mut rand::thread_rng());
+        let accept_seckey = SecretKey::new(&mut rand::thread_rng());
+
+        let offer = DlcOffer {
+            contract_id: "contract".to_string(),
+            announcement: announcement.clone(),
+            payouts: parse_payouts(&["heads:80000".to_string(), "tails:20000".to_string()]).unwrap(),
+            total_collateral: 100_000,
+            fee_rate: 1,
+            refund_locktime: 1000,
+            offer: party(&offer_seckey, 50_000, 0),
+        };
+        let accept = party(&accept_seckey, 50_000, 1);
+
+        let txs = build_transactions(&offer, &accept, Network::Regtest).unwrap();
+
+        let accept_signatures = sign_transactions(&txs, &offer, &accept_seckey).unwrap();
+        verify_signatures(&txs, &offer, &XOnlyPublicKey::from_str(&accept.funding_pubkey).unwrap(), &accept_signatures).unwrap();
+
+        // The oracle signs SHA256(outcome) with the announced nonce: s = k + ex.
+        let msg = sha256::Hash::hash(b"tails").to_byte_array();
+        let e = bip340::challenge(&nonce, &oracle_pubkey, &msg).unwrap();
+        let s = oracle_seckey.mul_tweak(&e).unwrap().add_tweak(&Scalar::from(nonce_seckey)).unwrap();
+
+        let mut signature = [0u8; 64];
+        signature[..32].copy_from_slice(&nonce.serialize());
+        signature[32..].copy_from_slice(&s.secret_bytes());
+
+        let attestation = OracleAttestation {
+            event_id: announcement.event_id.clone(),
+            outcome: "tails".to_string(),
+            signature: hex::encode(signature),
+        };
+
+        let dlc = Dlc {
+            contract_id: offer.contract_id.clone(),
+            role: "offer".to_string(),
+            secret_key: offer_seckey,
+            offer: offer.clone(),
+            accept: Some(accept.clone()),
+            counterparty_signatures: Some(accept_signatures),
+            status: "signed".to_string(),
+        };
+
+        let cet = signed_cet(&dlc, Network::Regtest, &attestation).unwrap();
+        assert_eq!(cet.txid(), txs.cets[1].txid());
+
+        let msg = Message::from_slice(&payout_sighash(&txs, &cet).unwrap()).unwrap();
+        let keys = sorted_keys(offer_seckey.x_only_public_key(&secp).0, accept_seckey.x_only_public_key(&secp).0);
+
+        // The first key's signature is on top of the stack, i.e. second in the witness.
+        for (key, sig) in [(keys[1], &cet.input[0].witness[0]), (keys[0], &cet.input[0].witness[1])] {
+            assert!(secp.verify_schnorr(&schnorr::Signature::from_slice(sig).unwrap(), &msg, &key).is_ok());
+        }
+
+        let other_outcome = OracleAttestation { outcome: "heads".to_string(), ..attestation };
+        assert!(signed_cet(&dlc, Network::Regtest, &other_outcome).is_err());
+    }
+}
@@ -0,0 +1,499 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use bitcoin::{Address, Network, hashes::Hash, psbt::{Psbt, raw::ProprietaryKey}, taproot};
+use frost_secp256k1_tr::{self as frost_tr, Identifier, keys::{KeyPackage, PublicKeyPackage, dkg}, round1::{SigningCommitments, SigningNonces}, round2::SignatureShare};
+use miniscript::{Descriptor, DefiniteDescriptorKey};
+use secp256k1_zkp::{Secp256k1, PublicKey, XOnlyPublicKey, Message, schnorr};
+use serde::{Serialize, Deserialize};
+use sqlx::{Sqlite, Row};
+
+use crate::{scripts, signer, wallet::TxOptions};
+
+/// Prefix of the PSBT proprietary fields carrying FROST commitments and signature shares,
+/// keyed by the signer's identifier.
+const PSBT_FROST_PREFIX: &[u8] = b"frost";
+const PSBT_FROST_COMMITMENTS: u8 = 0x01;
+const PSBT_FROST_SIGNATURE_SHARE: u8 = 0x02;
+
+/// Round 1 file of the distributed key generation, sent to every other participant.
+#[derive(Serialize, Deserialize)]
+pub struct DkgRound1 {
+    pub identifier: u16,
+    pub package: dkg::round1::Package,
+}
+
+/// Round 2 file of the distributed key generation: one package for each other participant,
+/// who only reads their own. Each package holds a secret share, so the file must reach them
+/// privately.
+#[derive(Serialize, Deserialize)]
+pub struct DkgRound2 {
+    pub identifier: u16,
+    pub packages: BTreeMap<u16, dkg::round2::Package>,
+}
+
+pub struct FrostKey {
+    pub address: Address,
+    pub identifier: u16,
+    pub min_signers: u16,
+    pub max_signers: u16,
+    pub internal_key: XOnlyPublicKey,
+    pub key_package: KeyPackage,
+    pub public_key_package: PublicKeyPackage,
+}
+
+fn identifier(id: u16) -> Result<Identifier, Box<dyn std::error::Error>> {
+    Identifier::try_from(id).map_err(|e| format!("Invalid identifier {}: {}", id, e).into())
+}
+
+/// The group key as a BIP340 x-only key, whichever encoding the ciphersuite serializes it in.
+fn internal_key(public_key_package: &PublicKeyPackage) -> Result<XOnlyPublicKey, Box<dyn std::error::Error>> {
+    let bytes = public_key_package.verifying_key().serialize()?;
+
+    match bytes.len() {
+        32 => Ok(XOnlyPublicKey::from_slice(&bytes)?),
+        _ => Ok(PublicKey::from_slice(&bytes)?.x_only_public_key().0),
+    }
+}
+
+/// Starts the key generation of a `min_signers`-of-`max_signers` key as participant
+/// `identifier` (1 to `max_signers`). Returns the session id, to continue with, and the round 1
+/// file for the other participants.
+pub async fn dkg_round1(pool: &sqlx::Pool<Sqlite>, id: u16, min_signers: u16, max_signers: u16) -> Result<(String, DkgRound1), Box<dyn std::error::Error>> {
+    if id == 0 || id > max_signers {
+        return Err(format!("The identifier must be between 1 and {}", max_signers).into());
+    }
+
+    let (secret_package, package) = dkg::part1(identifier(id)?, max_signers, min_signers, rand::thread_rng())?;
+
+    let session_id = uuid::Uuid::new_v4().to_string();
+
+    let query = "INSERT INTO frost_dkg_sessions (session_id, identifier, min_signers, max_signers, round1_secret) VALUES ($1, $2, $3, $4, $5)";
+
+    let _ = sqlx::query(query)
+        .bind(&session_id)
+        .bind(id)
+        .bind(min_signers)
+        .bind(max_signers)
+        .bind(serde_json::to_string(&secret_package)?)
+        .execute(pool)
+        .await
+        .unwrap();
+
+    Ok((session_id, DkgRound1 { identifier: id, package }))
+}
+
+/// Round 2 of the key generation, from the round 1 files of every other participant.
+pub async fn dkg_round2(pool: &sqlx::Pool<Sqlite>, session_id: &str, round1: &[DkgRound1]) -> Result<DkgRound2, Box<dyn std::error::Error>> {
+    let row = sqlx::query("SELECT identifier, round1_secret FROM frost_dkg_sessions WHERE session_id = $1 AND round1_secret IS NOT NULL")
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await
+        .unwrap()
+        .ok_or("Unknown or finished key generation session")?;
+
+    let id = row.get::<u16, _>("identifier");
+    let secret_package: dkg::round1::SecretPackage = serde_json::from_str(&row.get::<String, _>("round1_secret"))?;
+
+    let mut round1_packages = BTreeMap::new();
+    for file in round1.iter().filter(|file| file.identifier != id) {
+        round1_packages.insert(identifier(file.identifier)?, file.package.clone());
+    }
+
+    let (secret_package, packages) = dkg::part2(secret_package, &round1_packages)?;
+
+    let query = "UPDATE frost_dkg_sessions SET round1_secret = NULL, round1_packages = $1, round2_secret = $2 WHERE session_id = $3";
+
+    let _ = sqlx::query(query)
+        .bind(serde_json::to_string(&round1_packages)?)
+        .bind(serde_json::to_string(&secret_package)?)
+        .bind(session_id)
+        .execute(pool)
+        .await
+        .unwrap();
+
+    let mut round2 = BTreeMap::new();
+    for file in round1.iter().filter(|file| file.identifier != id) {
+        let package = packages.get(&identifier(file.identifier)?).ok_or("Missing round 2 package")?;
+        round2.insert(file.identifier, package.clone());
+    }
+
+    Ok(DkgRound2 { identifier: id, packages: round2 })
+}
+
+/// Last round of the key generation, from the round 2 files of every other participant.
+/// Stores our key share and returns the key, whose address is the same for all participants.
+pub async fn dkg_round3(pool: &sqlx::Pool<Sqlite>, network: Network, session_id: &str, round2: &[DkgRound2]) -> Result<FrostKey, Box<dyn std::error::Error>> {
+    let row = sqlx::query("SELECT identifier, min_signers, max_signers, round1_packages, round2_secret FROM frost_dkg_sessions WHERE session_id = $1 AND round2_secret IS NOT NULL")
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await
+        .unwrap()
+        .ok_or("Unknown key generation session, or round 2 not done")?;
+
+    let id = row.get::<u16, _>("identifier");
+    let round1_packages: BTreeMap<Identifier, dkg::round1::Package> = serde_json::from_str(&row.get::<String, _>("round1_packages"))?;
+    let secret_package: dkg::round2::SecretPackage = serde_json::from_str(&row.get::<String, _>("round2_secret"))?;
+
+    let mut round2_packages = BTreeMap::new();
+    for file in round2.iter().filter(|file| file.identifier != id) {
+        let package = file.packages.get(&id).ok_or_else(|| format!("No round 2 package for us from {}", file.identifier))?;
+        round2_packages.insert(identifier(file.identifier)?, package.clone());
+    }
+
+    let (key_package, public_key_package) = dkg::part3(&secret_package, &round1_packages, &round2_packages)?;
+
+    let internal_key = internal_key(&public_key_package)?;
+    let address = Address::p2tr(&Secp256k1::new(), internal_key, None, network);
+
+    let frost_key = FrostKey {
+        address,
+        identifier: id,
+        min_signers: row.get::<u16, _>("min_signers"),
+        max_signers: row.get::<u16, _>("max_signers"),
+        internal_key,
+        key_package,
+        public_key_package,
+    };
+
+    insert_frost_key(pool, &frost_key).await?;
+
+    let _ = sqlx::query("DELETE FROM frost_dkg_sessions WHERE session_id = $1")
+        .bind(session_id)
+        .execute(pool)
+        .await
+        .unwrap();
+
+    Ok(frost_key)
+}
+
+async fn insert_frost_key(pool: &sqlx::Pool<Sqlite>, frost_key: &FrostKey) -> Result<(), Box<dyn std::error::Error>> {
+    let query = "INSERT INTO frost_keys (identifier, min_signers, max_signers, key_package, public_key_package, internal_key, p2tr_address) VALUES ($1, $2, $3, $4, $5, $6, $7)";
+
+    let _ = sqlx::query(query)
+        .bind(frost_key.identifier)
+        .bind(frost_key.min_signers)
+        .bind(frost_key.max_signers)
+        .bind(frost_key.key_package.serialize()?)
+        .bind(frost_key.public_key_package.serialize()?)
+        .bind(&frost_key.internal_key.serialize().to_vec())
+        .bind(&frost_key.address.to_string())
+        .execute(pool)
+        .await
+        .unwrap();
+
+    Ok(())
+}
+
+pub async fn get_frost_keys(pool: &sqlx::Pool<Sqlite>, network: Network) -> Vec::<FrostKey> {
+    let query = "SELECT identifier, min_signers, max_signers, key_package, public_key_package, internal_key, p2tr_address FROM frost_keys";
+
+    let rows = sqlx::query(query)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+
+    let mut keys = Vec::<FrostKey>::new();
+
+    for row in rows {
+        let p2tr_address = row.get::<String, _>("p2tr_address");
+        let address = Address::from_str(&p2tr_address).unwrap().require_network(network).unwrap();
+
+        keys.push(FrostKey {
+            address,
+            identifier: row.get::<u16, _>("identifier"),
+            min_signers: row.get::<u16, _>("min_signers"),
+            max_signers: row.get::<u16, _>("max_signers"),
+            internal_key: XOnlyPublicKey::from_slice(&row.get::<Vec<u8>, _>("internal_key")).unwrap(),
+            key_package: KeyPackage::deserialize(&row.get::<Vec<u8>, _>("key_package")).unwrap(),
+            public_key_package: PublicKeyPackage::deserialize(&row.get::<Vec<u8>, _>("public_key_package")).unwrap(),
+        });
+    }
+
+    keys
+}
+
+/// Builds an unsigned PSBT sending every output of the FROST key's address to `to_address`.
+pub fn create_sweep_psbt(client: &electrum_client::Client, frost_key: &FrostKey, to_address: &Address, fee_rate: u64, options: &TxOptions) -> Result<Psbt, Box<dyn std::error::Error>> {
+    let descriptor = Descriptor::<DefiniteDescriptorKey>::from_str(&format!("tr({})", frost_key.internal_key))?;
+
+    scripts::create_sweep_psbt(client, &frost_key.address, &descriptor, to_address, fee_rate, options)
+}
+
+fn proprietary_key(subtype: u8, identifier: &Identifier) -> ProprietaryKey {
+    ProprietaryKey {
+        prefix: PSBT_FROST_PREFIX.to_vec(),
+        subtype,
+        key: identifier.serialize(),
+    }
+}
+
+/// Values of the FROST proprietary fields of `subtype` in an input, by signer.
+fn get_proprietary(input: &bitcoin::psbt::Input, subtype: u8) -> Result<BTreeMap<Identifier, Vec<u8>>, Box<dyn std::error::Error>> {
+    let mut values = BTreeMap::new();
+
+    for (key, value) in &input.proprietary {
+        if key.prefix == PSBT_FROST_PREFIX && key.subtype == subtype {
+            values.insert(Identifier::deserialize(&key.key)?, value.clone());
+        }
+    }
+
+    Ok(values)
+}
+
+/// Inputs of `psbt` spending the key path of one of our FROST keys.
+async fn get_frost_inputs(pool: &sqlx::Pool<Sqlite>, network: Network, psbt: &Psbt) -> Vec<(usize, FrostKey)> {
+    let mut inputs = Vec::<(usize, FrostKey)>::new();
+
+    for (index, input) in psbt.inputs.iter().enumerate() {
+        if input.tap_merkle_root.is_some() {
+            continue;
+        }
+
+        let internal_key = match input.tap_internal_key {
+            Some(internal_key) => internal_key,
+            None => continue,
+        };
+
+        if let Some(frost_key) = get_frost_keys(pool, network).await.into_iter().find(|key| key.internal_key == internal_key) {
+            inputs.push((index, frost_key));
+        }
+    }
+
+    inputs
+}
+
+/// Signing package of an input: the commitments of the signers taking part, who are whoever
+/// committed in the PSBT, and the key-path sighash.
+fn get_signing_package(psbt: &Psbt, index: usize, frost_key: &FrostKey) -> Result<frost_tr::SigningPackage, Box<dyn std::error::Error>> {
+    let mut commitments = BTreeMap::new();
+
+    for (identifier, value) in get_proprietary(&psbt.inputs[index], PSBT_FROST_COMMITMENTS)? {
+        commitments.insert(identifier, SigningCommitments::deserialize(&value)?);
+    }
+
+    if commitments.len() < frost_key.min_signers as usize {
+        return Err(format!("Input {}: {} of the {} commitments needed", index, commitments.len(), frost_key.min_signers).into());
+    }
+
+    let (hash, _) = signer::key_spend_sighash(psbt, index)?;
+
+    Ok(frost_tr::SigningPackage::new(commitments, hash.as_byte_array()))
+}
+
+/// Signing round 1: commits to fresh nonces for every input of our FROST keys and adds the
+/// commitments to the PSBT. The nonces are kept until `sign` uses them.
+/// Returns the indexes of the inputs we committed to.
+pub async fn commit(pool: &sqlx::Pool<Sqlite>, network: Network, psbt: &mut Psbt) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let txid = psbt.unsigned_tx.txid().to_string();
+
+    let mut committed_inputs = Vec::<usize>::new();
+
+    for (index, frost_key) in get_frost_inputs(pool, network, psbt).await {
+        let key = proprietary_key(PSBT_FROST_COMMITMENTS, frost_key.key_package.identifier());
+
+        if psbt.inputs[index].proprietary.contains_key(&key) {
+            continue;
+        }
+
+        let (nonces, commitments) = frost_tr::round1::commit(frost_key.key_package.signing_share(), &mut rand::thread_rng());
+        let commitments = commitments.serialize()?;
+
+        let query = "INSERT INTO frost_nonces (txid, input_index, internal_key, commitments, nonces) VALUES ($1, $2, $3, $4, $5)";
+
+        let _ = sqlx::query(query)
+            .bind(&txid)
+            .bind(index as u32)
+            .bind(&frost_key.internal_key.serialize().to_vec())
+            .bind(&commitments)
+            .bind(serde_json::to_string(&nonces)?)
+            .execute(pool)
+            .await
+            .unwrap();
+
+        psbt.inputs[index].proprietary.insert(key, commitments);
+
+        committed_inputs.push(index);
+    }
+
+    Ok(committed_inputs)
+}
+
+/// Signing round 2: once at least the threshold of signers committed, signs each input with our
+/// stored nonces and adds our signature share. The nonces are erased before they are used.
+/// Returns the indexes of the inputs that were signed.
+pub async fn sign(pool: &sqlx::Pool<Sqlite>, network: Network, psbt: &mut Psbt) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let txid = psbt.unsigned_tx.txid().to_string();
+
+    let mut signed_inputs = Vec::<usize>::new();
+
+    for (index, frost_key) in get_frost_inputs(pool, network, psbt).await {
+        let identifier = frost_key.key_package.identifier();
+        let share_key = proprietary_key(PSBT_FROST_SIGNATURE_SHARE, identifier);
+
+        if psbt.inputs[index].proprietary.contains_key(&share_key) {
+            continue;
+        }
+
+        let commitments = psbt.inputs[index].proprietary
+            .get(&proprietary_key(PSBT_FROST_COMMITMENTS, identifier))
+            .ok_or_else(|| format!("Input {}: we haven't committed", index))?
+            .clone();
+
+        let row = sqlx::query("SELECT nonces FROM frost_nonces WHERE txid = $1 AND input_index = $2 AND internal_key = $3 AND commitments = $4")
+            .bind(&txid)
+            .bind(index as u32)
+            .bind(&frost_key.internal_key.serialize().to_vec())
+            .bind(&commitments)
+            .fetch_optional(pool)
+            .await
+            .unwrap()
+            .ok_or_else(|| format!("Input {}: our nonces were already used or are unknown, commit again", index))?;
+
+        let nonces: SigningNonces = serde_json::from_str(&row.get::<String, _>("nonces"))?;
+
+        let _ = sqlx::query("DELETE FROM frost_nonces WHERE txid = $1 AND input_index = $2 AND internal_key = $3 AND commitments = $4")
+            .bind(&txid)
+            .bind(index as u32)
+            .bind(&frost_key.internal_key.serialize().to_vec())
+            .bind(&commitments)
+            .execute(pool)
+            .await
+            .unwrap();
+
+        let signing_package = get_signing_package(psbt, index, &frost_key)?;
+
+        // No merkle root: the BIP86 tweak of a key-path-only output.
+        let share = frost_tr::round2::sign_with_tweak(&signing_package, &nonces, &frost_key.key_package, None)?;
+
+        psbt.inputs[index].proprietary.insert(share_key, share.serialize());
+
+        signed_inputs.push(index);
+    }
+
+    Ok(signed_inputs)
+}
+
+/// Aggregates the signature shares of every signer that committed into the `tap_key_sig` of
+/// each FROST input, checking the shares and the final signature, and removes the FROST fields.
+/// Returns the indexes of the inputs that were signed.
+pub async fn aggregate(pool: &sqlx::Pool<Sqlite>, network: Network, psbt: &mut Psbt) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::verification_only();
+
+    let mut signed_inputs = Vec::<usize>::new();
+
+    for (index, frost_key) in get_frost_inputs(pool, network, psbt).await {
+        let signing_package = get_signing_package(psbt, index, &frost_key)?;
+
+        let mut shares = BTreeMap::new();
+        for (identifier, value) in get_proprietary(&psbt.inputs[index], PSBT_FROST_SIGNATURE_SHARE)? {
+            shares.insert(identifier, SignatureShare::deserialize(&value)?);
+        }
+
+        for identifier in signing_package.signing_commitments().keys() {
+            if !shares.contains_key(identifier) {
+                return Err(format!("Input {}: missing the signature share of a signer that committed", index).into());
+            }
+        }
+
+        let signature = frost_tr::aggregate_with_tweak(&signing_package, &shares, &frost_key.public_key_package, None)
+            .map_err(|e| format!("Input {}: {}", index, e))?;
+
+        let bytes = signature.serialize()?;
+        // BIP340 encodes R as its x coordinate only.
+        let sig = schnorr::Signature::from_slice(&bytes[bytes.len() - 64..])?;
+
+        let (hash, hash_ty) = signer::key_spend_sighash(psbt, index)?;
+        let msg = Message::from_slice(hash.as_byte_array())?;
+
+        let output_key = frost_key.address.script_pubkey().as_bytes()[2..34].to_vec();
+        secp.verify_schnorr(&sig, &msg, &XOnlyPublicKey::from_slice(&output_key)?)
+            .map_err(|e| format!("Input {}: aggregated signature is not valid: {}", index, e))?;
+
+        let input = &mut psbt.inputs[index];
+        input.tap_key_sig = Some(taproot::Signature { sig, hash_ty });
+        input.proprietary.retain(|key, _| key.prefix != PSBT_FROST_PREFIX);
+
+        signed_inputs.push(index);
+    }
+
+    Ok(signed_inputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 2-of-3 key generation and a signing by two of the participants, run in memory the way
+    /// the rounds above run them, checked against the output key of the FROST address.
+    #[test]
+    fn dkg_and_threshold_signature_verify_for_the_address() {
+        let (min_signers, max_signers) = (2, 3);
+
+        let mut round1_secrets = BTreeMap::new();
+        let mut round1_packages = BTreeMap::new();
+        for id in 1..=max_signers {
+            let (secret_package, package) = dkg::part1(identifier(id).unwrap(), max_signers, min_signers, rand::thread_rng()).unwrap();
+            round1_secrets.insert(identifier(id).unwrap(), secret_package);
+            round1_packages.insert(identifier(id).unwrap(), package);
+        }
+
+        let mut round2_secrets = BTreeMap::new();
+        let mut round2_packages = BTreeMap::new();
+        for (id, secret_package) in round1_secrets {
+            let others: BTreeMap<_, _> = round1_packages
+                .iter()
+                .filter(|(other, _)| **other != id)
+                .map(|(other, package)| (*other, package.clone()))
+                .collect();
+
+            let (secret_package, packages) = dkg::part2(secret_package, &others).unwrap();
+            round2_secrets.insert(id, (secret_package, others));
+            round2_packages.insert(id, packages);
+        }
+
+        let mut key_packages = BTreeMap::new();
+        let mut public_key_packages = Vec::new();
+        for (id, (secret_package, others)) in &round2_secrets {
+            let received: BTreeMap<_, _> = round2_packages
+                .iter()
+                .filter(|(sender, _)| *sender != id)
+                .map(|(sender, packages)| (*sender, packages[id].clone()))
+                .collect();
+
+            let (key_package, public_key_package) = dkg::part3(secret_package, others, &received).unwrap();
+            key_packages.insert(*id, key_package);
+            public_key_packages.push(public_key_package);
+        }
+
+        let internal_keys: Vec<_> = public_key_packages.iter().map(|package| internal_key(package).unwrap()).collect();
+        assert!(internal_keys.iter().all(|key| *key == internal_keys[0]));
+
+        let address = Address::p2tr(&Secp256k1::new(), internal_keys[0], None, Network::Regtest);
+
+        let msg = [7u8; 32];
+        let signers: Vec<_> = key_packages.values().take(min_signers as usize).collect();
+
+        let mut nonces = BTreeMap::new();
+        let mut commitments = BTreeMap::new();
+        for key_package in &signers {
+            let (signing_nonces, signing_commitments) = frost_tr::round1::commit(key_package.signing_share(), &mut rand::thread_rng());
+            nonces.insert(*key_package.identifier(), signing_nonces);
+            commitments.insert(*key_package.identifier(), signing_commitments);
+        }
+
+        let signing_package = frost_tr::SigningPackage::new(commitments, &msg);
+
+        let mut shares = BTreeMap::new();
+        for key_package in &signers {
+            let share = frost_tr::round2::sign_with_tweak(&signing_package, &nonces[key_package.identifier()], key_package, None).unwrap();
+            shares.insert(*key_package.identifier(), share);
+        }
+
+        let signature = frost_tr::aggregate_with_tweak(&signing_package, &shares, &public_key_packages[0], None).unwrap();
+        let bytes = signature.serialize().unwrap();
+        let sig = schnorr::Signature::from_slice(&bytes[bytes.len() - 64..]).unwrap();
+
+        let output_key = XOnlyPublicKey::from_slice(&address.script_pubkey().as_bytes()[2..34]).unwrap();
+        assert!(Secp256k1::verification_only().verify_schnorr(&sig, &Message::from_slice(&msg).unwrap(), &output_key).is_ok());
+    }
+}
@@ -1,27 +1,79 @@
 mod backend;
 mod addresses;
 mod wallet;
+mod signer;
+mod airgap;
+mod auth;
+mod bip340;
+mod scripts;
+mod musig;
+mod cosigner;
+mod statechain;
+mod frost;
+mod adaptor;
+mod dlc;
+mod bip322;
+mod reserves;
 
 use std::str::FromStr;
 
-use bitcoin::{Address, TxOut};
-use clap::{Parser, Subcommand};
+use bitcoin::{Address, OutPoint, Sequence, Txid, absolute};
+use clap::{Args, Parser, Subcommand};
 use electrum_client::ListUnspentRes;
 use secp256k1_zkp::Secp256k1;
 use serde::{Serialize, Deserialize};
 use serde_json::json;
 use sqlx::{SqlitePool, Sqlite, migrate::MigrateDatabase};
 
-use crate::wallet::generate_p2tr_key_spend_tx;
-
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Cli {
+    /// Never connect to the backend (air-gapped signer)
+    #[arg(long, global = true)]
+    offline: bool,
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Args)]
+struct TxArgs {
+    /// nLockTime as a block height or UNIX timestamp (defaults to the tip height, for anti-fee-sniping,
+    /// or 0 when every input is final)
+    #[arg(long)]
+    locktime: Option<u32>,
+    /// nSequence for every input
+    #[arg(long)]
+    sequence: Option<u32>,
+    /// Relative timelock in blocks for every input (BIP68)
+    #[arg(long, conflicts_with_all = ["sequence", "relative_time"])]
+    relative_blocks: Option<u16>,
+    /// Relative timelock in seconds for every input, rounded up to 512s intervals (BIP68)
+    #[arg(long, conflicts_with = "sequence")]
+    relative_time: Option<u32>,
+    /// nSequence for a single input, as txid:vout=sequence
+    #[arg(long)]
+    input_sequence: Vec<String>,
+    /// Order of the inputs and outputs
+    #[arg(long, value_enum, default_value_t = wallet::TxOrdering::Shuffle)]
+    ordering: wallet::TxOrdering,
+}
+
+#[derive(Args)]
+struct RecipientArgs {
+    /// Recipients as address:amount pairs
+    recipients: Vec<String>,
+    /// CSV (address,amount[,subtract_fee] per line) or JSON file with more recipients
+    #[arg(short, long)]
+    file: Option<String>,
+    /// Split the fee between all recipients instead of adding it on top
+    #[arg(long)]
+    subtract_fee_from_amount: bool,
+    /// Split the fee between the recipients at these positions (0-based)
+    #[arg(long)]
+    subtract_fee_from: Vec<usize>,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Create Aggregated Public Key
@@ -33,17 +85,578 @@ enum Commands {
     /// List transactions
     ListTransactions { },
     /// Send coin to an address
-    Send { address: String, amount: u64, fees: u64 },
+    Send {
+        address: String,
+        amount: u64,
+        fees: u64,
+        /// Deduct the fee from the amount sent instead of adding it on top
+        #[arg(long)]
+        subtract_fee_from_amount: bool,
+        #[command(flatten)]
+        tx_args: TxArgs,
+    },
+    /// Send coins to several recipients in a single transaction
+    SendMany {
+        fees: u64,
+        #[command(flatten)]
+        recipient_args: RecipientArgs,
+        #[command(flatten)]
+        tx_args: TxArgs,
+    },
+    /// Send all confirmed coins to an address, without change
+    Sweep {
+        address: String,
+        /// Fee rate in sat/vB, deducted from the swept amount
+        fee_rate: u64,
+        /// Only sweep coins held by these wallet addresses
+        #[arg(long)]
+        from_address: Vec<String>,
+        /// Only sweep coins of this account (m/86h/0h/<account>h)
+        #[arg(long)]
+        account: Option<u32>,
+        #[command(flatten)]
+        tx_args: TxArgs,
+    },
+    /// Replace an unconfirmed wallet transaction with one paying a higher fee (RBF)
+    BumpFee {
+        txid: String,
+        /// New fee rate in sat/vB
+        #[arg(long)]
+        fee_rate: u64,
+        #[command(flatten)]
+        tx_args: TxArgs,
+    },
+    /// Speed up an unconfirmed transaction by spending its wallet output (CPFP)
+    Cpfp {
+        txid: String,
+        /// Target fee rate in sat/vB for the parent and child together
+        #[arg(long)]
+        fee_rate: u64,
+        #[command(flatten)]
+        tx_args: TxArgs,
+    },
+    /// Create an unsigned PSBT paying the recipients, for review before signing
+    CreatePsbt {
+        fees: u64,
+        #[command(flatten)]
+        recipient_args: RecipientArgs,
+        #[command(flatten)]
+        tx_args: TxArgs,
+        /// File to write the PSBT to (base64 on stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+    /// Sign the inputs of a PSBT that belong to this wallet's seed
+    SignPsbt {
+        /// PSBT file, binary or base64 ("-" for stdin)
+        file: String,
+        /// File to write the PSBT to (base64 on stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+    /// Finalize a PSBT whose inputs are all signed; fails on the first input missing signatures
+    FinalizePsbt {
+        /// PSBT file, binary or base64 ("-" for stdin)
+        file: String,
+        /// File to write the PSBT to (base64 on stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+    /// Print the network transaction of a finalized PSBT
+    ExtractTx {
+        /// PSBT file, binary or base64 ("-" for stdin)
+        file: String,
+    },
+    /// Broadcast a raw transaction
+    Broadcast { tx_hex: String },
+    /// Import a tr(K,{...}) descriptor with a tapscript tree and show its address
+    ImportDescriptor { descriptor: String },
+    /// Compile a miniscript policy to a tr() descriptor and show its address
+    ImportPolicy {
+        /// Policy such as "or(pk(A),and(pk(B),after(800000)))"
+        policy: String,
+    },
+    /// List the addresses with a tapscript tree and their leaves
+    ListScriptAddresses {},
+    /// Show the public key to give the cosigners of the next MuSig2 address
+    MusigPublicKey {},
+    /// Create an n-of-n MuSig2 address of a new wallet key and the cosigners' public keys
+    NewMusigAddress {
+        /// Cosigner public keys (33-byte compressed hex)
+        #[arg(required = true)]
+        cosigner_pubkeys: Vec<String>,
+    },
+    /// List the MuSig2 addresses and their participants
+    ListMusigAddresses {},
+    /// Create a PSBT sending all the coins of a MuSig2 address, to pass around the cosigners
+    MusigCreatePsbt {
+        address: String,
+        to_address: String,
+        /// Fee rate in sat/vB, deducted from the amount sent
+        fee_rate: u64,
+        #[command(flatten)]
+        tx_args: TxArgs,
+        /// File to write the PSBT to (base64 on stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+    /// Create an address shared with the co-signer server, which signs blindly
+    NewCosignedAddress {},
+    /// List the addresses shared with the co-signer server
+    ListCosignedAddresses {},
+    /// Send all the coins of a co-signed address, signing with the co-signer server
+    CosignedSpend {
+        address: String,
+        to_address: String,
+        /// Fee rate in sat/vB, deducted from the amount sent
+        fee_rate: u64,
+        #[command(flatten)]
+        tx_args: TxArgs,
+    },
+    /// Deposit coins from the wallet into a statecoin shared with the statechain entity
+    StatechainDeposit {
+        amount: u64,
+        /// Fee of the funding transaction
+        fees: u64,
+        /// Fee rate in sat/vB of the backup transaction
+        backup_fee_rate: u64,
+        #[command(flatten)]
+        tx_args: TxArgs,
+    },
+    /// Show a new key and backup address to receive a statecoin with
+    StatechainTransferAddress {},
+    /// Transfer a statecoin to the owner of a transfer key, writing the transfer message
+    StatechainTransfer {
+        statechain_id: String,
+        /// Receiver's key, from statechain-transfer-address
+        receiver_pubkey: String,
+        /// Receiver's backup address, from statechain-transfer-address
+        backup_address: String,
+        /// Fee rate in sat/vB of the new backup transaction
+        backup_fee_rate: u64,
+        /// File to write the transfer message to (stdout otherwise). Keep it private
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Complete the transfer of a statecoin to this wallet
+    StatechainReceive {
+        /// Transfer message file ("-" for stdin)
+        file: String,
+    },
+    /// Withdraw a statecoin to an address with the statechain entity's cooperation
+    StatechainWithdraw {
+        statechain_id: String,
+        to_address: String,
+        /// Fee rate in sat/vB, deducted from the amount withdrawn
+        fee_rate: u64,
+    },
+    /// List the statecoins and their backup transactions
+    ListStatecoins {},
+    /// Broadcast the backup transactions of statecoins whose locktime has matured
+    CheckBackups {
+        /// Keep checking instead of exiting after one pass
+        #[arg(long)]
+        daemon: bool,
+        /// Seconds between checks in daemon mode
+        #[arg(long, default_value_t = 600, requires = "daemon")]
+        interval: u64,
+    },
+    /// FROST key generation round 1: start a t-of-n key as participant IDENTIFIER
+    FrostDkgRound1 {
+        /// Our participant number, from 1 to max_signers
+        identifier: u16,
+        min_signers: u16,
+        max_signers: u16,
+        /// File to write the round 1 package to, for every other participant (stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// FROST key generation round 2, from the other participants' round 1 files
+    FrostDkgRound2 {
+        session_id: String,
+        #[arg(required = true)]
+        files: Vec<String>,
+        /// File to write the round 2 packages to (stdout otherwise). Keep it private
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// FROST key generation round 3, from the other participants' round 2 files
+    FrostDkgRound3 {
+        session_id: String,
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+    /// List the FROST keys we hold a share of
+    ListFrostKeys {},
+    /// Create a PSBT sending all the coins of a FROST key's address, to pass around the signers
+    FrostCreatePsbt {
+        address: String,
+        to_address: String,
+        /// Fee rate in sat/vB, deducted from the amount sent
+        fee_rate: u64,
+        #[command(flatten)]
+        tx_args: TxArgs,
+        /// File to write the PSBT to (base64 on stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+    /// FROST round 1: add our nonce commitments to a PSBT (then combine-psbt the signers' copies)
+    FrostCommit {
+        /// PSBT file, binary or base64 ("-" for stdin)
+        file: String,
+        /// File to write the PSBT to (base64 on stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+    /// FROST round 2: add our signature shares once at least the threshold of signers committed
+    FrostSign {
+        /// PSBT file, binary or base64 ("-" for stdin)
+        file: String,
+        /// File to write the PSBT to (base64 on stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+    /// Aggregate the signature shares into the key-path signature
+    FrostAggregate {
+        /// PSBT file, binary or base64 ("-" for stdin)
+        file: String,
+        /// File to write the PSBT to (base64 on stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+    /// Generate an adaptor secret and its adaptor point
+    NewAdaptorSecret {},
+    /// Create adaptor signatures, under an adaptor point, for the wallet's key-path inputs of a PSBT
+    AdaptorSign {
+        /// PSBT file, binary or base64 ("-" for stdin)
+        file: String,
+        /// Adaptor point (33-byte compressed hex)
+        adaptor_point: String,
+        /// File to write the adaptor signatures to (stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Verify adaptor signatures against the inputs of a PSBT
+    AdaptorVerify {
+        /// PSBT file, binary or base64 ("-" for stdin)
+        file: String,
+        /// Adaptor signatures file
+        adaptor_sigs: String,
+        /// Inputs that must be adaptor-signed (every input of the PSBT by default)
+        #[arg(long)]
+        input: Vec<usize>,
+    },
+    /// Complete adaptor signatures with the adaptor secret into the PSBT's key-path signatures
+    AdaptorComplete {
+        /// PSBT file, binary or base64 ("-" for stdin)
+        file: String,
+        /// Adaptor signatures file
+        adaptor_sigs: String,
+        /// Adaptor secret (hex)
+        secret: String,
+        /// File to write the PSBT to (base64 on stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+    /// Extract the adaptor secret from a transaction that used completed adaptor signatures
+    AdaptorExtract {
+        /// Adaptor signatures file
+        adaptor_sigs: String,
+        txid: String,
+    },
+    /// Offer a DLC on an oracle's announced event, funding our collateral from the wallet
+    DlcOffer {
+        /// Oracle announcement file
+        announcement: String,
+        /// Our payout for each outcome, as outcome:amount
+        #[arg(required = true)]
+        payouts: Vec<String>,
+        /// Our collateral, in satoshis
+        #[arg(long)]
+        collateral: u64,
+        /// Collateral of both parties, which the payouts split
+        #[arg(long)]
+        total_collateral: u64,
+        /// Fee rate of every transaction of the contract, in sat/vB
+        #[arg(long)]
+        fee_rate: u64,
+        /// Block height or UNIX timestamp from which the collaterals can be refunded
+        #[arg(long)]
+        refund_locktime: u32,
+        /// File to write the offer to (stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Accept a DLC offer, funding the rest of the collateral from the wallet
+    DlcAccept {
+        /// Offer file
+        file: String,
+        /// File to write the acceptance to (stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Sign an accepted DLC: its CETs, refund transaction and our funding inputs
+    DlcSign {
+        /// Acceptance file
+        file: String,
+        /// File to write the signatures to (stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Sign our funding inputs of a signed DLC and broadcast the funding transaction
+    DlcFund {
+        /// Signatures file
+        file: String,
+    },
+    /// Broadcast the CET of the outcome attested by the oracle
+    DlcSettle {
+        contract_id: String,
+        /// Oracle attestation file
+        attestation: String,
+    },
+    /// Broadcast the refund transaction of a DLC the oracle didn't attest
+    DlcRefund {
+        contract_id: String,
+    },
+    /// List the DLCs of the wallet
+    ListDlcs {},
+    /// Sign a message with the key of a wallet address (BIP322)
+    SignMessage {
+        address: String,
+        message: String,
+        /// Give the whole to_sign transaction instead of only its witness
+        #[arg(long)]
+        full: bool,
+    },
+    /// Verify a BIP322 simple or full signature of a message by a P2TR address
+    VerifyMessage {
+        address: String,
+        message: String,
+        /// Base64 signature
+        signature: String,
+    },
+    /// Export a BIP127 proof of reserves of every confirmed wallet UTXO, committing to a challenge message
+    ProofOfReserves {
+        /// Challenge message, e.g. given by the auditor
+        message: String,
+        /// File to write the PSBT to (base64 on stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+    /// Verify a BIP127 proof of reserves against the current UTXO set
+    VerifyReserves {
+        /// PSBT file, binary or base64 ("-" for stdin)
+        file: String,
+        /// Challenge message
+        message: String,
+    },
+    /// MuSig2 round 1: add our public nonces to a PSBT (then combine-psbt the cosigners' copies)
+    MusigNonce {
+        /// PSBT file, binary or base64 ("-" for stdin)
+        file: String,
+        /// File to write the PSBT to (base64 on stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+    /// MuSig2 round 2: add our partial signatures once the PSBT has every nonce
+    MusigSign {
+        /// PSBT file, binary or base64 ("-" for stdin)
+        file: String,
+        /// File to write the PSBT to (base64 on stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+    /// Verify the partial signatures of every cosigner and aggregate them into the key-path signature
+    MusigAggregate {
+        /// PSBT file, binary or base64 ("-" for stdin)
+        file: String,
+        /// File to write the PSBT to (base64 on stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+    /// Send all the coins of a tapscript or policy address, by key path if the wallet has the
+    /// internal key and no leaf is chosen, otherwise through the script path
+    SpendScriptPath {
+        address: String,
+        to_address: String,
+        /// Fee rate in sat/vB, deducted from the amount sent
+        fee_rate: u64,
+        /// Leaf to spend, as listed by list-script-addresses (any leaf we can sign otherwise).
+        /// Use --locktime and --relative-blocks/--relative-time to meet its after()/older()
+        #[arg(long)]
+        leaf: Option<usize>,
+        #[command(flatten)]
+        tx_args: TxArgs,
+        /// Write the signed PSBT to this file for the other signers instead of broadcasting
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+    /// Create an address spendable by a new wallet key, or by the recovery key after some blocks
+    NewRecoveryAddress {
+        /// Recovery public key (x-only hex, optionally with a [fingerprint/path] origin)
+        recovery_key: String,
+        /// Blocks after confirmation until the recovery key can spend (BIP68)
+        blocks: u16,
+    },
+    /// Send all the coins of a recovery address through its timelocked recovery leaf
+    Recover {
+        address: String,
+        to_address: String,
+        /// Fee rate in sat/vB, deducted from the amount sent
+        fee_rate: u64,
+        /// Write the PSBT to this file for the recovery key holder instead of broadcasting
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+    /// Show what a PSBT spends and pays, and what to check before signing it
+    DecodePsbt {
+        /// PSBT file, binary or base64 ("-" for stdin)
+        file: String,
+    },
+    /// Merge the signatures of several copies of the same PSBT
+    CombinePsbt {
+        /// PSBT files, binary or base64
+        #[arg(required = true, num_args = 2..)]
+        files: Vec<String>,
+        /// File to write the PSBT to (base64 on stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+        /// Write the combined PSBT even if the copies have conflicting fields
+        #[arg(long)]
+        force: bool,
+    },
+    /// Print a PSBT as animated QR text frames (BC-UR crypto-psbt), one per line
+    ExportUr {
+        /// PSBT file, binary or base64 ("-" for stdin)
+        file: String,
+        /// Maximum length of the payload of each frame
+        #[arg(long, default_value_t = 200)]
+        max_fragment_len: usize,
+        /// Number of frames to print, more than needed adds fountain-coded frames
+        #[arg(long)]
+        frames: Option<usize>,
+    },
+    /// Rebuild a PSBT from BC-UR text frames, one per line
+    ImportUr {
+        /// File with the frames ("-" for stdin)
+        file: String,
+        /// File to write the PSBT to (base64 on stdout otherwise)
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Write the PSBT in binary instead of base64
+        #[arg(long, requires = "output")]
+        binary: bool,
+    },
+}
+
+impl Commands {
+    fn needs_backend(&self) -> bool {
+        !matches!(self,
+            Commands::GenerateNewKey { .. } |
+            Commands::ListAddresses { .. } |
+            Commands::SignPsbt { .. } |
+            Commands::FinalizePsbt { .. } |
+            Commands::ExtractTx { .. } |
+            Commands::DecodePsbt { .. } |
+            Commands::CombinePsbt { .. } |
+            Commands::ExportUr { .. } |
+            Commands::ImportUr { .. } |
+            Commands::ImportDescriptor { .. } |
+            Commands::ImportPolicy { .. } |
+            Commands::ListScriptAddresses { .. } |
+            Commands::NewRecoveryAddress { .. } |
+            Commands::MusigPublicKey { .. } |
+            Commands::NewMusigAddress { .. } |
+            Commands::ListMusigAddresses { .. } |
+            Commands::MusigNonce { .. } |
+            Commands::MusigSign { .. } |
+            Commands::MusigAggregate { .. } |
+            Commands::ListCosignedAddresses { .. } |
+            Commands::StatechainTransferAddress { .. } |
+            Commands::ListStatecoins { .. } |
+            Commands::FrostDkgRound1 { .. } |
+            Commands::FrostDkgRound2 { .. } |
+            Commands::FrostDkgRound3 { .. } |
+            Commands::ListFrostKeys { .. } |
+            Commands::FrostCommit { .. } |
+            Commands::FrostSign { .. } |
+            Commands::FrostAggregate { .. } |
+            Commands::NewAdaptorSecret { .. } |
+            Commands::AdaptorSign { .. } |
+            Commands::AdaptorVerify { .. } |
+            Commands::AdaptorComplete { .. } |
+            Commands::DlcSign { .. } |
+            Commands::ListDlcs { .. } |
+            Commands::SignMessage { .. } |
+            Commands::VerifyMessage { .. }
+        )
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
     let network = bitcoin::Network::Signet;
 
-    let client = electrum_client::Client::new("tcp://127.0.0.1:50001").unwrap();
-
     let cli = Cli::parse();
 
+    // The backend is only connected to by the commands that need it, so that an air-gapped
+    // machine can keep the seed and sign without ever going online.
+    if cli.offline && cli.command.needs_backend() {
+        let res = json!({
+            "error": "This command needs the backend and can't run in offline mode",
+        });
+        println!("{}", serde_json::to_string_pretty(&res).unwrap());
+        return;
+    }
+
     if !Sqlite::database_exists("wallet.db").await.unwrap_or(false) {
         match Sqlite::create_database("wallet.db").await {
             Ok(_) => println!("Create db success"),
@@ -75,6 +688,8 @@ async fn main() {
         println!("{}", serde_json::to_string_pretty(&json!(addresses)).unwrap());
     },
     Commands::GetBalance {  } => {
+        let client = backend::connect();
+
 
         #[derive(Serialize, Deserialize, Debug)]
             struct Balance {
@@ -97,6 +712,8 @@ async fn main() {
 
     },
     Commands::ListTransactions {  } => {
+        let client = backend::connect();
+
         #[derive(Serialize, Deserialize, Debug)]
             struct History {
                 address: String,
@@ -120,87 +737,1487 @@ async fn main() {
             
             println!("{}", serde_json::to_string_pretty(&json!(result)).unwrap());
     },
-    Commands::Send { address, amount, fees } => {
+    Commands::Send { address, amount, fees, subtract_fee_from_amount, tx_args } => {
+        let client = backend::connect();
+
+        let to_address = Address::from_str(&address).unwrap().require_network(network).unwrap();
+
+        let recipients = vec![wallet::Recipient {
+            address: to_address,
+            amount,
+            subtract_fee: subtract_fee_from_amount,
+        }];
+
+        let Some(options) = print_tx_options(&client, &tx_args) else { return };
+
+        let tx = wallet::create_payment_tx(&pool, &client, network, &recipients, fees, &options).await;
+
+        broadcast_tx(&client, tx);
+    },
+    Commands::SendMany { fees, recipient_args, tx_args } => {
+        let client = backend::connect();
+
+        let all_recipients = match parse_recipients(&recipient_args, network) {
+            Ok(recipients) => recipients,
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+                return;
+            }
+        };
+
+        let Some(options) = print_tx_options(&client, &tx_args) else { return };
+
+        let tx = wallet::create_payment_tx(&pool, &client, network, &all_recipients, fees, &options).await;
+
+        broadcast_tx(&client, tx);
+    },
+    Commands::Sweep { address, fee_rate, from_address, account, tx_args } => {
+        let client = backend::connect();
+
         let to_address = Address::from_str(&address).unwrap().require_network(network).unwrap();
 
-        let (_, change_address, _) = addresses::generate_new_key(&pool, network, true).await;
+        let from_addresses: Vec<Address> = from_address
+            .iter()
+            .map(|a| Address::from_str(a).unwrap().require_network(network).unwrap())
+            .collect();
+
+        let Some(options) = print_tx_options(&client, &tx_args) else { return };
 
-        let amount_to_send_in_sats = bitcoin::Amount::from_sat(amount);
+        let tx = wallet::create_sweep_tx(&pool, &client, network, &to_address, fee_rate, &from_addresses, account, &options).await;
 
-        let addresses = wallet::get_all_addresses_info(&pool, network).await;
+        broadcast_tx(&client, tx);
+    },
+    Commands::BumpFee { txid, fee_rate, tx_args } => {
+        let client = backend::connect();
 
-        let mut list_unspent = Vec::<wallet::AddressInfo>::new(); 
+        let original_txid = Txid::from_str(&txid).unwrap();
 
-        for address in addresses {
-            let address_utxos = backend::get_script_list_unspent(&client, &address.0);
+        let Some(options) = print_tx_options(&client, &tx_args) else { return };
 
-            for utxo in address_utxos {
-                list_unspent.push(wallet::AddressInfo {
-                    address: address.0.clone(),
-                    secret_key: address.4.clone(),
-                    xonly_public_key: address.3.clone(),
-                    fingerprint: address.1.clone(),
-                    derivation_path: address.2.clone(),
-                    height: utxo.height,
-                    tx_hash: utxo.tx_hash,
-                    tx_pos: utxo.tx_pos,
-                    value: utxo.value,
+        let wallet_tx = match wallet::create_bump_fee_tx(&pool, &client, network, &original_txid, fee_rate, &options).await {
+            Ok(wallet_tx) => wallet_tx,
+            Err(e) => {
+                let res = json!({
+                    "error": e.to_string(),
                 });
+                println!("{}", serde_json::to_string_pretty(&res).unwrap());
+                return;
             }
+        };
+
+        let fee = wallet_tx.fee;
+
+        if let Some(replacement_txid) = broadcast_tx(&client, Ok(wallet_tx)) {
+            wallet::insert_replacement(&pool, &original_txid, &replacement_txid, fee_rate, fee).await;
         }
+    },
+    Commands::SignPsbt { file, output, binary } => {
+        let res = async {
+            let mut psbt = signer::read_psbt(&file)?;
 
-        list_unspent.sort_by(|a, b| a.value.cmp(&b.value));
+            let root = addresses::get_root_key(&pool, network).await;
+            let mut secret_keys = wallet::get_wallet_secret_keys(&pool, network).await;
+            secret_keys.extend(signer::secret_keys_from_origins(&psbt, &root));
 
-        let mut previous_outputs = Vec::<wallet::AddressInfo>::new(); 
+            let mut signed_inputs = signer::sign_psbt(&mut psbt, &secret_keys)?;
+            signed_inputs.extend(signer::sign_psbt_script_path(&mut psbt, &secret_keys, None)?);
+            signed_inputs.sort();
+            signed_inputs.dedup();
 
-        for utxo in list_unspent {
-            
-            let input_amount: u64 = previous_outputs.iter().map(|s| s.value).sum();
+            signer::write_psbt(&psbt, output.as_deref(), binary)?;
 
-            if (input_amount) > (amount + fees) {
-                break;
-            } else {
-                previous_outputs.push(utxo);
+            Ok::<_, Box<dyn std::error::Error>>(signed_inputs)
+        }.await;
+
+        match res {
+            Ok(signed_inputs) => {
+                if output.is_some() {
+                    let res = json!({
+                        "file": output,
+                        "signed_inputs": signed_inputs,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&res).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::FinalizePsbt { file, output, binary } => {
+        let res = signer::read_psbt(&file).and_then(|mut psbt| {
+            signer::finalize_psbt(&mut psbt)?;
+            signer::write_psbt(&psbt, output.as_deref(), binary)
+        });
+
+        match res {
+            Ok(_) => {
+                if output.is_some() {
+                    println!("{}", serde_json::to_string_pretty(&json!({ "file": output })).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
             }
         }
+    },
+    Commands::ExtractTx { file } => {
+        match signer::read_psbt(&file).and_then(signer::extract_tx) {
+            Ok(tx) => println!("{}", bitcoin::consensus::encode::serialize_hex(&tx)),
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::DecodePsbt { file } => {
+        let psbt = match signer::read_psbt(&file) {
+            Ok(psbt) => psbt,
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+                return;
+            }
+        };
+
+        let root = addresses::get_root_key(&pool, network).await;
+
+        let wallet_keys: Vec<_> = wallet::get_all_addresses_info(&pool, network).await
+            .into_iter()
+            .map(|address| address.3)
+            .collect();
+        let wallet_addresses = wallet::get_all_addresses(&pool, network).await;
+        let change_addresses = wallet::get_change_addresses(&pool, network).await;
+
+        let res = signer::decode_psbt(&psbt, network, &root, &wallet_keys, &wallet_addresses, &change_addresses);
+        println!("{}", serde_json::to_string_pretty(&res).unwrap());
+    },
+    Commands::CombinePsbt { files, output, binary, force } => {
+        let res = files
+            .iter()
+            .map(|file| signer::read_psbt(file).map_err(|e| format!("{}: {}", file, e).into()))
+            .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()
+            .and_then(signer::combine_psbts);
 
-        let input_amount: u64 = previous_outputs.iter().map(|s| s.value).sum();
-        println!("input_amount: {}", input_amount);
-        println!("amount + fee: {}", (amount + fees));
+        let (psbt, conflicts) = match res {
+            Ok(res) => res,
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+                return;
+            }
+        };
 
-        if (input_amount) < (amount + fees) {
+        if !conflicts.is_empty() && !force {
             let res = json!({
-                "error": "Not enough funds",
+                "error": "The PSBTs have conflicting fields",
+                "conflicts": conflicts,
             });
             println!("{}", serde_json::to_string_pretty(&res).unwrap());
             return;
         }
 
-        let input_amount_in_sats = bitcoin::Amount::from_sat(input_amount);
-        let fees_amount_in_sats = bitcoin::Amount::from_sat(fees);
+        if let Err(e) = signer::write_psbt(&psbt, output.as_deref(), binary) {
+            println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            return;
+        }
+
+        if output.is_some() {
+            let res = json!({
+                "file": output,
+                "conflicts": conflicts,
+            });
+            println!("{}", serde_json::to_string_pretty(&res).unwrap());
+        }
+    },
+    Commands::ExportUr { file, max_fragment_len, frames } => {
+        match signer::read_psbt(&file).and_then(|psbt| airgap::psbt_to_ur_frames(&psbt, max_fragment_len, frames)) {
+            Ok(frames) => {
+                for frame in frames {
+                    println!("{}", frame);
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::ImportUr { file, output, binary } => {
+        let res = signer::read_psbt(&file).and_then(|psbt| signer::write_psbt(&psbt, output.as_deref(), binary));
+
+        match res {
+            Ok(_) => {
+                if output.is_some() {
+                    println!("{}", serde_json::to_string_pretty(&json!({ "file": output })).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::ImportDescriptor { descriptor } => {
+        match scripts::import_descriptor(&pool, network, &descriptor).await {
+            Ok(address) => println!("{}", serde_json::to_string_pretty(&json!({ "address": address })).unwrap()),
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::ImportPolicy { policy } => {
+        match scripts::import_policy(&pool, network, &policy).await {
+            Ok((address, descriptor)) => {
+                let res = json!({
+                    "address": address,
+                    "descriptor": descriptor,
+                });
+                println!("{}", serde_json::to_string_pretty(&res).unwrap());
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::MusigPublicKey {  } => {
+        let public_key = musig::get_next_public_key(&pool, network).await;
+
+        println!("{}", serde_json::to_string_pretty(&json!({ "public_key": public_key.to_string() })).unwrap());
+    },
+    Commands::NewMusigAddress { cosigner_pubkeys } => {
+        match musig::new_musig_address(&pool, network, &cosigner_pubkeys).await {
+            Ok(musig_address) => {
+                let participants: Vec<_> = musig_address.participants.iter().map(|p| p.to_string()).collect();
+
+                let res = json!({
+                    "address": musig_address.address,
+                    "public_key": musig_address.public_key.to_string(),
+                    "participants": participants,
+                    "aggregate_pubkey": musig_address.aggregate_pubkey.to_string(),
+                });
+                println!("{}", serde_json::to_string_pretty(&res).unwrap());
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::ListMusigAddresses {  } => {
+        let result: Vec<_> = musig::get_musig_addresses(&pool, network).await
+            .iter()
+            .map(|musig_address| {
+                let participants: Vec<_> = musig_address.participants.iter().map(|p| p.to_string()).collect();
+
+                json!({
+                    "address": musig_address.address,
+                    "public_key": musig_address.public_key.to_string(),
+                    "participants": participants,
+                    "aggregate_pubkey": musig_address.aggregate_pubkey.to_string(),
+                    "fingerprint": musig_address.fingerprint,
+                    "derivation_path": musig_address.derivation_path,
+                })
+            })
+            .collect();
+
+        println!("{}", serde_json::to_string_pretty(&json!(result)).unwrap());
+    },
+    Commands::NewCosignedAddress {  } => {
+        match cosigner::new_cosigned_address(&pool, network).await {
+            Ok(cosigned_address) => {
+                let res = json!({
+                    "address": cosigned_address.address,
+                    "public_key": cosigned_address.public_key.to_string(),
+                    "server_pubkey": cosigned_address.server_pubkey.to_string(),
+                    "key_id": cosigned_address.key_id,
+                });
+                println!("{}", serde_json::to_string_pretty(&res).unwrap());
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::ListCosignedAddresses {  } => {
+        let result: Vec<_> = cosigner::get_cosigned_addresses(&pool, network).await
+            .iter()
+            .map(|cosigned_address| json!({
+                "address": cosigned_address.address,
+                "public_key": cosigned_address.public_key.to_string(),
+                "server_pubkey": cosigned_address.server_pubkey.to_string(),
+                "aggregate_pubkey": cosigned_address.aggregate_pubkey.to_string(),
+                "key_id": cosigned_address.key_id,
+                "derivation_path": cosigned_address.derivation_path,
+            }))
+            .collect();
+
+        println!("{}", serde_json::to_string_pretty(&json!(result)).unwrap());
+    },
+    Commands::CosignedSpend { address, to_address, fee_rate, tx_args } => {
+        let client = backend::connect();
+
+        let res = async {
+            let address = Address::from_str(&address)?.require_network(network)?;
+            let to_address = Address::from_str(&to_address)?.require_network(network)?;
 
-        let change_amount = input_amount_in_sats
-            .checked_sub(amount_to_send_in_sats)
-            .and_then(|x| x.checked_sub(fees_amount_in_sats))
-            .ok_or("Fees more than input amount!").unwrap();
+            let cosigned_address = cosigner::get_cosigned_addresses(&pool, network).await
+                .into_iter()
+                .find(|cosigned_address| cosigned_address.address == address)
+                .ok_or("Unknown co-signed address")?;
 
-        let outputs = vec![
-            TxOut { value: amount_to_send_in_sats.to_sat(), script_pubkey: to_address.script_pubkey() },
-            TxOut { value: change_amount.to_sat(), script_pubkey: change_address.script_pubkey() },
-        ];
+            let options = tx_options(&client, &tx_args)?;
 
+            let mut psbt = cosigner::create_signed_sweep_psbt(&client, &cosigned_address, &to_address, fee_rate, &options).await?;
 
-        let tx = generate_p2tr_key_spend_tx(&previous_outputs, &outputs).unwrap();
-        let tx_hex_string = bitcoin::consensus::encode::serialize_hex(&tx);
+            signer::finalize_psbt(&mut psbt)?;
+            let tx = signer::extract_tx(psbt)?;
+
+            Ok::<_, Box<dyn std::error::Error>>(tx)
+        }.await;
+
+        let tx = match res {
+            Ok(tx) => tx,
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+                return;
+            }
+        };
 
         let tx_bytes = bitcoin::consensus::encode::serialize(&tx);
 
-        println!("tx_hex: {}", tx_hex_string);
+        println!("tx_hex: {}", bitcoin::consensus::encode::serialize_hex(&tx));
 
         let txid = backend::transaction_broadcast_raw(&client, &tx_bytes);
 
         println!("txid: {}", txid);
     },
+    Commands::StatechainDeposit { amount, fees, backup_fee_rate, tx_args } => {
+        let client = backend::connect();
+
+        let res = async {
+            let options = tx_options(&client, &tx_args)?;
+
+            statechain::deposit(&pool, &client, network, amount, fees, backup_fee_rate, &options).await
+        }.await;
+
+        let (statecoin, funding_tx) = match res {
+            Ok(res) => res,
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+                return;
+            }
+        };
+
+        println!("statechain_id: {}", statecoin.statechain_id);
+        println!("address: {}", statecoin.address);
+
+        broadcast_tx(&client, Ok(funding_tx));
+    },
+    Commands::StatechainTransferAddress {  } => {
+        let (public_key, backup_address) = statechain::new_transfer_address(&pool, network).await;
+
+        let res = json!({
+            "receiver_pubkey": public_key.to_string(),
+            "backup_address": backup_address,
+        });
+        println!("{}", serde_json::to_string_pretty(&res).unwrap());
+    },
+    Commands::StatechainTransfer { statechain_id, receiver_pubkey, backup_address, backup_fee_rate, output } => {
+        let client = backend::connect();
+
+        let res = async {
+            let backup_address = Address::from_str(&backup_address)?.require_network(network)?;
+
+            let message = statechain::transfer(&pool, &client, network, &statechain_id, &receiver_pubkey, &backup_address, backup_fee_rate).await?;
+
+            write_json_file(&message, output.as_deref())
+        }.await;
+
+        match res {
+            Ok(_) => {
+                if output.is_some() {
+                    println!("{}", serde_json::to_string_pretty(&json!({ "file": output })).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::StatechainReceive { file } => {
+        let client = backend::connect();
+
+        let res = async {
+            let message = if file == "-" {
+                std::io::read_to_string(std::io::stdin())?
+            } else {
+                std::fs::read_to_string(&file)?
+            };
+            let message: statechain::TransferMessage = serde_json::from_str(&message)?;
+
+            statechain::receive(&pool, &client, network, &message).await
+        }.await;
+
+        match res {
+            Ok(statecoin) => {
+                let res = json!({
+                    "statechain_id": statecoin.statechain_id,
+                    "address": statecoin.address,
+                    "amount": statecoin.amount,
+                });
+                println!("{}", serde_json::to_string_pretty(&res).unwrap());
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::StatechainWithdraw { statechain_id, to_address, fee_rate } => {
+        let client = backend::connect();
+
+        let res = async {
+            let to_address = Address::from_str(&to_address)?.require_network(network)?;
+
+            statechain::withdraw(&pool, network, &statechain_id, &to_address, fee_rate).await
+        }.await;
+
+        let tx = match res {
+            Ok(tx) => tx,
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+                return;
+            }
+        };
+
+        let tx_bytes = bitcoin::consensus::encode::serialize(&tx);
+
+        println!("tx_hex: {}", bitcoin::consensus::encode::serialize_hex(&tx));
+
+        match backend::try_transaction_broadcast_raw(&client, &tx_bytes) {
+            Ok(txid) => {
+                statechain::set_withdrawn(&pool, &statechain_id).await;
+
+                println!("txid: {}", txid);
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::ListStatecoins {  } => {
+        let mut result = Vec::<serde_json::Value>::new();
+
+        for statecoin in statechain::get_statecoins(&pool, network).await {
+            let backup_txs: Vec<_> = statechain::get_backup_txs(&pool, &statecoin.statechain_id).await
+                .iter()
+                .map(|backup_tx| json!({
+                    "tx_n": backup_tx.tx_n,
+                    "txid": backup_tx.tx.txid(),
+                    "lock_time": backup_tx.lock_time,
+                }))
+                .collect();
+
+            result.push(json!({
+                "statechain_id": statecoin.statechain_id,
+                "address": statecoin.address,
+                "outpoint": statecoin.outpoint.to_string(),
+                "amount": statecoin.amount,
+                "status": statecoin.status,
+                "backup_txs": backup_txs,
+            }));
+        }
+
+        println!("{}", serde_json::to_string_pretty(&json!(result)).unwrap());
+    },
+    Commands::CheckBackups { daemon, interval } => {
+        let client = backend::connect();
+
+        loop {
+            let result: Vec<_> = statechain::check_backups(&pool, &client, network).await
+                .iter()
+                .map(|check| json!({
+                    "statechain_id": check.statechain_id,
+                    "tx_n": check.tx_n,
+                    "txid": check.txid,
+                    "lock_time": check.lock_time,
+                    "status": check.status,
+                }))
+                .collect();
+
+            println!("{}", serde_json::to_string_pretty(&json!(result)).unwrap());
+
+            if !daemon {
+                break;
+            }
+
+            tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+        }
+    },
+    Commands::FrostDkgRound1 { identifier, min_signers, max_signers, output } => {
+        let res = async {
+            let (session_id, round1) = frost::dkg_round1(&pool, identifier, min_signers, max_signers).await?;
+
+            write_json_file(&round1, output.as_deref())?;
+
+            Ok::<_, Box<dyn std::error::Error>>(session_id)
+        }.await;
+
+        match res {
+            Ok(session_id) => {
+                let res = json!({
+                    "session_id": session_id,
+                    "file": output,
+                });
+                println!("{}", serde_json::to_string_pretty(&res).unwrap());
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::FrostDkgRound2 { session_id, files, output } => {
+        let res = async {
+            let mut round1 = Vec::<frost::DkgRound1>::new();
+            for file in &files {
+                round1.push(serde_json::from_str(&std::fs::read_to_string(file)?)?);
+            }
+
+            let round2 = frost::dkg_round2(&pool, &session_id, &round1).await?;
+
+            write_json_file(&round2, output.as_deref())
+        }.await;
+
+        match res {
+            Ok(_) => {
+                if output.is_some() {
+                    println!("{}", serde_json::to_string_pretty(&json!({ "file": output })).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::FrostDkgRound3 { session_id, files } => {
+        let res = async {
+            let mut round2 = Vec::<frost::DkgRound2>::new();
+            for file in &files {
+                round2.push(serde_json::from_str(&std::fs::read_to_string(file)?)?);
+            }
+
+            frost::dkg_round3(&pool, network, &session_id, &round2).await
+        }.await;
+
+        match res {
+            Ok(frost_key) => {
+                let res = json!({
+                    "address": frost_key.address,
+                    "internal_key": frost_key.internal_key.to_string(),
+                    "identifier": frost_key.identifier,
+                    "threshold": format!("{}-of-{}", frost_key.min_signers, frost_key.max_signers),
+                });
+                println!("{}", serde_json::to_string_pretty(&res).unwrap());
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::ListFrostKeys {  } => {
+        let result: Vec<_> = frost::get_frost_keys(&pool, network).await
+            .iter()
+            .map(|frost_key| json!({
+                "address": frost_key.address,
+                "internal_key": frost_key.internal_key.to_string(),
+                "identifier": frost_key.identifier,
+                "threshold": format!("{}-of-{}", frost_key.min_signers, frost_key.max_signers),
+            }))
+            .collect();
+
+        println!("{}", serde_json::to_string_pretty(&json!(result)).unwrap());
+    },
+    Commands::FrostCreatePsbt { address, to_address, fee_rate, tx_args, output, binary } => {
+        let client = backend::connect();
+
+        let res = async {
+            let address = Address::from_str(&address)?.require_network(network)?;
+            let to_address = Address::from_str(&to_address)?.require_network(network)?;
+
+            let frost_key = frost::get_frost_keys(&pool, network).await
+                .into_iter()
+                .find(|frost_key| frost_key.address == address)
+                .ok_or("Unknown FROST address")?;
+
+            let options = tx_options(&client, &tx_args)?;
+
+            let psbt = frost::create_sweep_psbt(&client, &frost_key, &to_address, fee_rate, &options)?;
+
+            signer::write_psbt(&psbt, output.as_deref(), binary)?;
+
+            Ok::<_, Box<dyn std::error::Error>>(())
+        }.await;
+
+        match res {
+            Ok(_) => {
+                if output.is_some() {
+                    println!("{}", serde_json::to_string_pretty(&json!({ "file": output })).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::FrostCommit { file, output, binary } => {
+        let res = async {
+            let mut psbt = signer::read_psbt(&file)?;
+
+            let inputs = frost::commit(&pool, network, &mut psbt).await?;
+
+            signer::write_psbt(&psbt, output.as_deref(), binary)?;
+
+            Ok::<_, Box<dyn std::error::Error>>(inputs)
+        }.await;
+
+        match res {
+            Ok(inputs) => {
+                if output.is_some() {
+                    let res = json!({
+                        "file": output,
+                        "committed_inputs": inputs,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&res).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::FrostSign { file, output, binary } => {
+        let res = async {
+            let mut psbt = signer::read_psbt(&file)?;
+
+            let inputs = frost::sign(&pool, network, &mut psbt).await?;
+
+            signer::write_psbt(&psbt, output.as_deref(), binary)?;
+
+            Ok::<_, Box<dyn std::error::Error>>(inputs)
+        }.await;
+
+        match res {
+            Ok(inputs) => {
+                if output.is_some() {
+                    let res = json!({
+                        "file": output,
+                        "signed_inputs": inputs,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&res).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::FrostAggregate { file, output, binary } => {
+        let res = async {
+            let mut psbt = signer::read_psbt(&file)?;
+
+            let inputs = frost::aggregate(&pool, network, &mut psbt).await?;
+
+            signer::write_psbt(&psbt, output.as_deref(), binary)?;
+
+            Ok::<_, Box<dyn std::error::Error>>(inputs)
+        }.await;
+
+        match res {
+            Ok(inputs) => {
+                if output.is_some() {
+                    let res = json!({
+                        "file": output,
+                        "signed_inputs": inputs,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&res).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::NewAdaptorSecret {  } => {
+        let (secret, adaptor_point) = Secp256k1::new().generate_keypair(&mut rand::thread_rng());
+
+        let res = json!({
+            "secret": hex::encode(secret.secret_bytes()),
+            "adaptor_point": adaptor_point.to_string(),
+        });
+        println!("{}", serde_json::to_string_pretty(&res).unwrap());
+    },
+    Commands::AdaptorSign { file, adaptor_point, output } => {
+        let res = async {
+            let psbt = signer::read_psbt(&file)?;
+            let adaptor_point = secp256k1_zkp::PublicKey::from_str(&adaptor_point)?;
+
+            let root = addresses::get_root_key(&pool, network).await;
+            let mut secret_keys = wallet::get_wallet_secret_keys(&pool, network).await;
+            secret_keys.extend(signer::secret_keys_from_origins(&psbt, &root));
+
+            let adaptor_sigs = adaptor::sign_psbt(&psbt, &secret_keys, &adaptor_point)?;
+
+            write_json_file(&adaptor_sigs, output.as_deref())?;
+
+            Ok::<_, Box<dyn std::error::Error>>(adaptor_sigs.signatures.iter().map(|sig| sig.input).collect::<Vec<_>>())
+        }.await;
+
+        match res {
+            Ok(signed_inputs) => {
+                if output.is_some() {
+                    let res = json!({
+                        "file": output,
+                        "signed_inputs": signed_inputs,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&res).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::AdaptorVerify { file, adaptor_sigs, input } => {
+        let res = signer::read_psbt(&file).and_then(|psbt| {
+            let adaptor_sigs: adaptor::AdaptorSignatures = serde_json::from_str(&std::fs::read_to_string(&adaptor_sigs)?)?;
+
+            let expected_inputs = match input.is_empty() {
+                true => (0..psbt.inputs.len()).collect(),
+                false => input,
+            };
+
+            adaptor::verify_psbt(&psbt, &adaptor_sigs, &expected_inputs)?;
+
+            Ok(adaptor_sigs.signatures.iter().map(|sig| sig.input).collect::<Vec<_>>())
+        });
+
+        match res {
+            Ok(inputs) => println!("{}", serde_json::to_string_pretty(&json!({ "valid": true, "inputs": inputs })).unwrap()),
+            Err(e) => println!("{}", serde_json::to_string_pretty(&json!({ "valid": false, "error": e.to_string() })).unwrap()),
+        }
+    },
+    Commands::AdaptorComplete { file, adaptor_sigs, secret, output, binary } => {
+        let res = signer::read_psbt(&file).and_then(|mut psbt| {
+            let adaptor_sigs: adaptor::AdaptorSignatures = serde_json::from_str(&std::fs::read_to_string(&adaptor_sigs)?)?;
+            let secret = secp256k1_zkp::SecretKey::from_slice(&hex::decode(&secret)?)?;
+
+            let signed_inputs = adaptor::complete_psbt(&mut psbt, &adaptor_sigs, &secret)?;
+
+            signer::write_psbt(&psbt, output.as_deref(), binary)?;
+
+            Ok(signed_inputs)
+        });
+
+        match res {
+            Ok(signed_inputs) => {
+                if output.is_some() {
+                    let res = json!({
+                        "file": output,
+                        "signed_inputs": signed_inputs,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&res).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::AdaptorExtract { adaptor_sigs, txid } => {
+        let client = backend::connect();
+
+        let res = (|| {
+            let adaptor_sigs: adaptor::AdaptorSignatures = serde_json::from_str(&std::fs::read_to_string(&adaptor_sigs)?)?;
+            let txid = Txid::from_str(&txid)?;
+
+            let tx = backend::get_transaction(&client, &txid);
+
+            adaptor::extract_from_tx(&tx, &adaptor_sigs)
+        })();
+
+        match res {
+            Ok(secret) => println!("{}", serde_json::to_string_pretty(&json!({ "secret": hex::encode(secret.secret_bytes()) })).unwrap()),
+            Err(e) => println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap()),
+        }
+    },
+    Commands::DlcOffer { announcement, payouts, collateral, total_collateral, fee_rate, refund_locktime, output } => {
+        let client = backend::connect();
+
+        let res = async {
+            let announcement: dlc::OracleAnnouncement = serde_json::from_str(&std::fs::read_to_string(&announcement)?)?;
+            let payouts = dlc::parse_payouts(&payouts)?;
+
+            let offer = dlc::offer(&pool, &client, network, announcement, payouts, collateral, total_collateral, fee_rate, refund_locktime).await?;
+
+            write_json_file(&offer, output.as_deref())?;
+
+            Ok::<_, Box<dyn std::error::Error>>(offer.contract_id)
+        }.await;
+
+        match res {
+            Ok(contract_id) => {
+                if output.is_some() {
+                    let res = json!({
+                        "file": output,
+                        "contract_id": contract_id,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&res).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::DlcAccept { file, output } => {
+        let client = backend::connect();
+
+        let res = async {
+            let offer: dlc::DlcOffer = serde_json::from_str(&std::fs::read_to_string(&file)?)?;
+
+            let accept = dlc::accept(&pool, &client, network, offer).await?;
+
+            write_json_file(&accept, output.as_deref())?;
+
+            Ok::<_, Box<dyn std::error::Error>>(accept.contract_id)
+        }.await;
+
+        match res {
+            Ok(contract_id) => {
+                if output.is_some() {
+                    let res = json!({
+                        "file": output,
+                        "contract_id": contract_id,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&res).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::DlcSign { file, output } => {
+        let res = async {
+            let accept: dlc::DlcAccept = serde_json::from_str(&std::fs::read_to_string(&file)?)?;
+
+            let sign = dlc::sign(&pool, network, accept).await?;
+
+            write_json_file(&sign, output.as_deref())?;
+
+            Ok::<_, Box<dyn std::error::Error>>(sign.contract_id)
+        }.await;
+
+        match res {
+            Ok(contract_id) => {
+                if output.is_some() {
+                    let res = json!({
+                        "file": output,
+                        "contract_id": contract_id,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&res).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::DlcFund { file } => {
+        let client = backend::connect();
+
+        let res = async {
+            let sign: dlc::DlcSign = serde_json::from_str(&std::fs::read_to_string(&file)?)?;
+
+            dlc::fund(&pool, network, sign).await
+        }.await;
+
+        broadcast_raw_tx(&client, res);
+    },
+    Commands::DlcSettle { contract_id, attestation } => {
+        let client = backend::connect();
+
+        let res = async {
+            let attestation: dlc::OracleAttestation = serde_json::from_str(&std::fs::read_to_string(&attestation)?)?;
+
+            dlc::settle(&pool, network, &contract_id, &attestation).await
+        }.await;
+
+        if broadcast_raw_tx(&client, res).is_some() {
+            dlc::close(&pool, &contract_id, "settled").await;
+        }
+    },
+    Commands::DlcRefund { contract_id } => {
+        let client = backend::connect();
+
+        let res = dlc::refund(&pool, network, &contract_id).await;
+
+        if broadcast_raw_tx(&client, res).is_some() {
+            dlc::close(&pool, &contract_id, "refunded").await;
+        }
+    },
+    Commands::ListDlcs {  } => {
+        let mut result = Vec::<serde_json::Value>::new();
+
+        for dlc in dlc::get_dlcs(&pool).await {
+            result.push(json!({
+                "contract_id": dlc.contract_id,
+                "role": dlc.role,
+                "event_id": dlc.offer.announcement.event_id,
+                "total_collateral": dlc.offer.total_collateral,
+                "refund_locktime": dlc.offer.refund_locktime,
+                "status": dlc.status,
+            }));
+        }
+
+        println!("{}", serde_json::to_string_pretty(&json!(result)).unwrap());
+    },
+    Commands::SignMessage { address, message, full } => {
+        let res = async {
+            let address = Address::from_str(&address)?.require_network(network)?;
+
+            bip322::sign_message(&pool, network, &address, &message, full).await
+        }.await;
+
+        match res {
+            Ok(signature) => println!("{}", serde_json::to_string_pretty(&json!({ "signature": signature })).unwrap()),
+            Err(e) => println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap()),
+        }
+    },
+    Commands::VerifyMessage { address, message, signature } => {
+        let res = (|| {
+            let address = Address::from_str(&address)?.require_network(network)?;
+
+            bip322::verify_message(&address, &message, &signature)
+        })();
+
+        match res {
+            Ok(()) => println!("{}", serde_json::to_string_pretty(&json!({ "valid": true })).unwrap()),
+            Err(e) => println!("{}", serde_json::to_string_pretty(&json!({ "valid": false, "error": e.to_string() })).unwrap()),
+        }
+    },
+    Commands::ProofOfReserves { message, output, binary } => {
+        let client = backend::connect();
+
+        let res = async {
+            let (psbt, reserves, utxos) = reserves::create_proof(&pool, &client, network, &message).await?;
+
+            signer::write_psbt(&psbt, output.as_deref(), binary)?;
+
+            Ok::<_, Box<dyn std::error::Error>>((reserves, utxos))
+        }.await;
+
+        match res {
+            Ok((reserves, utxos)) => {
+                if output.is_some() {
+                    let res = json!({
+                        "file": output,
+                        "reserves": reserves,
+                        "utxos": utxos,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&res).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::VerifyReserves { file, message } => {
+        let client = backend::connect();
+
+        let res = signer::read_psbt(&file).and_then(|psbt| reserves::verify_proof(&client, network, &psbt, &message));
+
+        match res {
+            Ok((reserves, utxos)) => {
+                let res = json!({
+                    "valid": true,
+                    "reserves": reserves,
+                    "utxos": utxos,
+                });
+                println!("{}", serde_json::to_string_pretty(&res).unwrap());
+            },
+            Err(e) => println!("{}", serde_json::to_string_pretty(&json!({ "valid": false, "error": e.to_string() })).unwrap()),
+        }
+    },
+    Commands::MusigCreatePsbt { address, to_address, fee_rate, tx_args, output, binary } => {
+        let client = backend::connect();
+
+        let res = async {
+            let address = Address::from_str(&address)?.require_network(network)?;
+            let to_address = Address::from_str(&to_address)?.require_network(network)?;
+
+            let musig_address = musig::get_musig_addresses(&pool, network).await
+                .into_iter()
+                .find(|musig_address| musig_address.address == address)
+                .ok_or("Unknown MuSig2 address")?;
+
+            let options = tx_options(&client, &tx_args)?;
+
+            let psbt = musig::create_sweep_psbt(&client, &musig_address, &to_address, fee_rate, &options)?;
+
+            signer::write_psbt(&psbt, output.as_deref(), binary)?;
+
+            Ok::<_, Box<dyn std::error::Error>>(())
+        }.await;
+
+        match res {
+            Ok(_) => {
+                if output.is_some() {
+                    println!("{}", serde_json::to_string_pretty(&json!({ "file": output })).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::MusigNonce { file, output, binary } => {
+        let res = async {
+            let mut psbt = signer::read_psbt(&file)?;
+
+            let inputs = musig::add_nonces(&pool, network, &mut psbt).await?;
+
+            signer::write_psbt(&psbt, output.as_deref(), binary)?;
+
+            Ok::<_, Box<dyn std::error::Error>>(inputs)
+        }.await;
+
+        match res {
+            Ok(inputs) => {
+                if output.is_some() {
+                    let res = json!({
+                        "file": output,
+                        "nonce_inputs": inputs,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&res).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::MusigSign { file, output, binary } => {
+        let res = async {
+            let mut psbt = signer::read_psbt(&file)?;
+
+            let inputs = musig::partial_sign(&pool, network, &mut psbt).await?;
+
+            signer::write_psbt(&psbt, output.as_deref(), binary)?;
+
+            Ok::<_, Box<dyn std::error::Error>>(inputs)
+        }.await;
+
+        match res {
+            Ok(inputs) => {
+                if output.is_some() {
+                    let res = json!({
+                        "file": output,
+                        "signed_inputs": inputs,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&res).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::MusigAggregate { file, output, binary } => {
+        let res = async {
+            let mut psbt = signer::read_psbt(&file)?;
+
+            let inputs = musig::aggregate_signatures(&pool, network, &mut psbt).await?;
+
+            signer::write_psbt(&psbt, output.as_deref(), binary)?;
+
+            Ok::<_, Box<dyn std::error::Error>>(inputs)
+        }.await;
+
+        match res {
+            Ok(inputs) => {
+                if output.is_some() {
+                    let res = json!({
+                        "file": output,
+                        "signed_inputs": inputs,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&res).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::ListScriptAddresses {  } => {
+        let policies = scripts::get_script_policies(&pool).await;
+
+        let result: Vec<_> = scripts::get_script_addresses(&pool, network).await
+            .iter()
+            .map(|(address, descriptor)| {
+                let leaves: Vec<_> = scripts::get_leaves(descriptor)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, (leaf_hash, script))| json!({
+                        "index": index,
+                        "leaf_hash": leaf_hash.to_string(),
+                        "script": script,
+                    }))
+                    .collect();
+
+                json!({
+                    "address": address,
+                    "descriptor": descriptor.to_string(),
+                    "policy": policies.get(&address.to_string()),
+                    "leaves": leaves,
+                })
+            })
+            .collect();
+
+        println!("{}", serde_json::to_string_pretty(&json!(result)).unwrap());
+    },
+    Commands::SpendScriptPath { address, to_address, fee_rate, leaf, tx_args, output, binary } => {
+        let client = backend::connect();
+
+        let Some(options) = print_tx_options(&client, &tx_args) else { return };
+
+        spend_script_path(&pool, &client, network, &address, &to_address, fee_rate, leaf, &options, output.as_deref(), binary).await;
+    },
+    Commands::NewRecoveryAddress { recovery_key, blocks } => {
+        match scripts::new_recovery_address(&pool, network, &recovery_key, blocks).await {
+            Ok((address, descriptor)) => {
+                let res = json!({
+                    "address": address,
+                    "descriptor": descriptor,
+                });
+                println!("{}", serde_json::to_string_pretty(&res).unwrap());
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::Recover { address, to_address, fee_rate, output, binary } => {
+        let client = backend::connect();
+
+        let res = async {
+            let script_address = Address::from_str(&address)?.require_network(network)?;
+
+            let descriptor = scripts::get_script_address(&pool, network, &script_address).await
+                .ok_or("Unknown tapscript address")?;
+
+            let (leaf, sequence) = scripts::get_recovery_leaf(&descriptor)
+                .ok_or("This address has no timelocked recovery leaf")?;
+
+            let blocks = sequence.to_relative_lock_time()
+                .and_then(|lock_time| match lock_time {
+                    bitcoin::relative::LockTime::Blocks(height) => Some(height.value() as u32),
+                    bitcoin::relative::LockTime::Time(_) => None,
+                })
+                .ok_or("Only block-based recovery timelocks are supported")?;
+
+            let tip_height = backend::get_tip_height(&client);
+
+            for utxo in backend::get_script_list_unspent(&client, &script_address) {
+                // A coin confirmed at height h can be spent through older(N) from block h + N.
+                if utxo.height == 0 || utxo.height as u32 + blocks > tip_height + 1 {
+                    let available = if utxo.height == 0 { "once confirmed".to_string() } else { format!("at height {}", utxo.height as u32 + blocks) };
+                    return Err(format!("{}:{} can only be recovered {}", utxo.tx_hash, utxo.tx_pos, available).into());
+                }
+            }
+
+            let options = wallet::TxOptions {
+                lock_time: wallet::anti_fee_sniping_lock_time(tip_height),
+                sequence,
+                ..Default::default()
+            };
+
+            Ok::<_, Box<dyn std::error::Error>>((leaf, options))
+        }.await;
+
+        let (leaf, options) = match res {
+            Ok(res) => res,
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+                return;
+            }
+        };
+
+        spend_script_path(&pool, &client, network, &address, &to_address, fee_rate, Some(leaf), &options, output.as_deref(), binary).await;
+    },
+    Commands::Broadcast { tx_hex } => {
+        let client = backend::connect();
+
+        let tx_bytes = match hex::decode(tx_hex.trim()) {
+            Ok(tx_bytes) => tx_bytes,
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+                return;
+            }
+        };
+
+        let txid = backend::transaction_broadcast_raw(&client, &tx_bytes);
+
+        println!("txid: {}", txid);
+    },
+    Commands::CreatePsbt { fees, recipient_args, tx_args, output, binary } => {
+        let client = backend::connect();
+
+        let res = async {
+            let recipients = parse_recipients(&recipient_args, network)?;
+            let options = tx_options(&client, &tx_args)?;
+
+            let (inputs_info, outputs, change_index) = wallet::prepare_payment(&pool, &client, network, &recipients, fees).await?;
+            let (psbt, change_index) = wallet::create_p2tr_key_spend_psbt(&inputs_info, &outputs, change_index, &options)?;
+
+            signer::write_psbt(&psbt, output.as_deref(), binary)?;
+
+            Ok::<_, Box<dyn std::error::Error>>(change_index)
+        }.await;
+
+        match res {
+            Ok(change_index) => {
+                if output.is_some() {
+                    let res = json!({
+                        "file": output,
+                        "change_index": change_index,
+                    });
+                    println!("{}", serde_json::to_string_pretty(&res).unwrap());
+                }
+            },
+            Err(e) => {
+                println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            }
+        }
+    },
+    Commands::Cpfp { txid, fee_rate, tx_args } => {
+        let client = backend::connect();
+
+        let parent_txid = Txid::from_str(&txid).unwrap();
+
+        let Some(options) = print_tx_options(&client, &tx_args) else { return };
+
+        let tx = wallet::create_cpfp_tx(&pool, &client, network, &parent_txid, fee_rate, &options).await;
+
+        broadcast_tx(&client, tx);
+    },
+}
+}
+
+/// Sends all the coins of the tapscript `address` to `to_address`, signing with the wallet keys,
+/// and broadcasts the transaction. The timelocks of the leaves are met through `options`. With `output`, the signed
+/// PSBT is written there for the other signers instead.
+#[allow(clippy::too_many_arguments)]
+async fn spend_script_path(pool: &SqlitePool, client: &electrum_client::Client, network: bitcoin::Network, address: &str, to_address: &str, fee_rate: u64, leaf: Option<usize>, options: &wallet::TxOptions, output: Option<&str>, binary: bool) {
+    let res = async {
+        let address = Address::from_str(address)?.require_network(network)?;
+        let to_address = Address::from_str(to_address)?.require_network(network)?;
+
+        let descriptor = scripts::get_script_address(pool, network, &address).await
+            .ok_or("Unknown tapscript address")?;
+
+        let leaf_hash = match leaf {
+            Some(index) => Some(scripts::get_leaves(&descriptor).get(index).ok_or("No leaf at this index")?.0),
+            None => None,
+        };
+
+        let mut psbt = scripts::create_sweep_psbt(client, &address, &descriptor, &to_address, fee_rate, options)?;
+
+        let root = addresses::get_root_key(pool, network).await;
+        let mut secret_keys = wallet::get_wallet_secret_keys(pool, network).await;
+        secret_keys.extend(signer::secret_keys_from_origins(&psbt, &root));
+
+        // Without a chosen leaf, a key-path signature (e.g. the key a policy was compiled around)
+        // is preferred by the finalizer over any leaf.
+        if leaf_hash.is_none() {
+            signer::sign_psbt(&mut psbt, &secret_keys)?;
+        }
+
+        signer::sign_psbt_script_path(&mut psbt, &secret_keys, leaf_hash)?;
+
+        Ok::<_, Box<dyn std::error::Error>>(psbt)
+    }.await;
+
+    let mut psbt = match res {
+        Ok(psbt) => psbt,
+        Err(e) => {
+            println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            return;
+        }
+    };
+
+    if output.is_some() {
+        match signer::write_psbt(&psbt, output, binary) {
+            Ok(_) => println!("{}", serde_json::to_string_pretty(&json!({ "file": output })).unwrap()),
+            Err(e) => println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap()),
+        }
+        return;
+    }
+
+    let tx = match signer::finalize_psbt(&mut psbt).and_then(|_| signer::extract_tx(psbt)) {
+        Ok(tx) => tx,
+        Err(e) => {
+            println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            return;
+        }
+    };
+
+    let tx_bytes = bitcoin::consensus::encode::serialize(&tx);
+
+    println!("tx_hex: {}", bitcoin::consensus::encode::serialize_hex(&tx));
+
+    let txid = backend::transaction_broadcast_raw(client, &tx_bytes);
+
+    println!("txid: {}", txid);
+}
+
+/// Writes `value` as JSON to `path`, or prints it when there is no path.
+fn write_json_file<T: Serialize>(value: &T, path: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let json = serde_json::to_string_pretty(value)?;
+
+    match path {
+        Some(path) => std::fs::write(path, json)?,
+        None => println!("{}", json),
+    }
+
+    Ok(())
+}
+
+fn parse_recipients(args: &RecipientArgs, network: bitcoin::Network) -> Result<Vec<wallet::Recipient>, Box<dyn std::error::Error>> {
+    let mut recipients = Vec::<wallet::Recipient>::new();
+
+    for recipient in &args.recipients {
+        recipients.push(wallet::parse_recipient(recipient, network)?);
+    }
+
+    if let Some(file) = &args.file {
+        recipients.extend(wallet::parse_recipients_file(file, network)?);
+    }
+
+    if let Some(index) = args.subtract_fee_from.iter().find(|i| **i >= recipients.len()) {
+        return Err(format!("No recipient at position {}", index).into());
+    }
+
+    for (index, recipient) in recipients.iter_mut().enumerate() {
+        if args.subtract_fee_from_amount || args.subtract_fee_from.contains(&index) {
+            recipient.subtract_fee = true;
+        }
+    }
+
+    Ok(recipients)
+}
+
+fn tx_options(client: &electrum_client::Client, args: &TxArgs) -> Result<wallet::TxOptions, Box<dyn std::error::Error>> {
+    let lock_time = match args.locktime {
+        Some(locktime) => absolute::LockTime::from_consensus(locktime),
+        None => wallet::anti_fee_sniping_lock_time(backend::get_tip_height(client)),
+    };
+
+    let mut options = wallet::TxOptions {
+        lock_time,
+        ordering: args.ordering,
+        ..Default::default()
+    };
+
+    if let Some(sequence) = args.sequence {
+        options.sequence = Sequence(sequence);
+    }
+
+    if let Some(blocks) = args.relative_blocks {
+        options.sequence = Sequence::from_height(blocks);
+    }
+
+    if let Some(seconds) = args.relative_time {
+        options.sequence = Sequence::from_seconds_ceil(seconds)?;
+    }
+
+    for input_sequence in &args.input_sequence {
+        let (outpoint, sequence) = input_sequence
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid input sequence '{}', expected txid:vout=sequence", input_sequence))?;
+
+        options.input_sequences.insert(OutPoint::from_str(outpoint)?, Sequence(sequence.parse::<u32>()?));
+    }
+
+    // The anti-fee-sniping locktime has no effect when every input is final, so it is dropped
+    // rather than failing; an explicit --locktime still gets the error.
+    let all_final = !options.sequence.enables_absolute_lock_time()
+        && options.input_sequences.values().all(|sequence| !sequence.enables_absolute_lock_time());
+
+    if args.locktime.is_none() && all_final {
+        options.lock_time = absolute::LockTime::ZERO;
+    }
+
+    Ok(options)
+}
+
+/// `tx_options` for the command arms that stop on invalid options, printing the error.
+fn print_tx_options(client: &electrum_client::Client, args: &TxArgs) -> Option<wallet::TxOptions> {
+    match tx_options(client, args) {
+        Ok(options) => Some(options),
+        Err(e) => {
+            println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            None
+        }
+    }
+}
+
+fn broadcast_tx(client: &electrum_client::Client, tx: Result<wallet::WalletTx, Box<dyn std::error::Error>>) -> Option<Txid> {
+    let wallet_tx = match tx {
+        Ok(wallet_tx) => wallet_tx,
+        Err(e) => {
+            let res = json!({
+                "error": e.to_string(),
+            });
+            println!("{}", serde_json::to_string_pretty(&res).unwrap());
+            return None;
+        }
+    };
+
+    let tx_hex_string = bitcoin::consensus::encode::serialize_hex(&wallet_tx.tx);
+
+    let tx_bytes = bitcoin::consensus::encode::serialize(&wallet_tx.tx);
+
+    println!("tx_hex: {}", tx_hex_string);
+    println!("fee: {}", wallet_tx.fee);
+
+    if let Some(change_index) = wallet_tx.change_index {
+        println!("change_index: {}", change_index);
+    }
+
+    let txid = backend::transaction_broadcast_raw(client, &tx_bytes);
+
+    println!("txid: {}", txid);
+
+    Some(txid)
+}
+
+/// Broadcasts a transaction signed outside the wallet's payment flow, e.g. a contract
+/// transaction, printing the error instead if it couldn't be built.
+fn broadcast_raw_tx(client: &electrum_client::Client, tx: Result<bitcoin::Transaction, Box<dyn std::error::Error>>) -> Option<Txid> {
+    let tx = match tx {
+        Ok(tx) => tx,
+        Err(e) => {
+            println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            return None;
+        }
+    };
+
+    println!("tx_hex: {}", bitcoin::consensus::encode::serialize_hex(&tx));
+
+    match backend::try_transaction_broadcast_raw(client, &bitcoin::consensus::encode::serialize(&tx)) {
+        Ok(txid) => {
+            println!("txid: {}", txid);
+            Some(txid)
+        },
+        Err(e) => {
+            println!("{}", serde_json::to_string_pretty(&json!({ "error": e.to_string() })).unwrap());
+            None
+        }
+    }
 }
-}
-    
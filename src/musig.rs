@@ -0,0 +1,486 @@
+use std::str::FromStr;
+
+use bitcoin::{Address, Network, ScriptBuf, bip32::{ChildNumber, DerivationPath}, hashes::Hash, key::TweakedPublicKey, psbt::{Psbt, raw::ProprietaryKey}, taproot::{self, TapTweakHash}};
+use miniscript::{Descriptor, DefiniteDescriptorKey};
+use rand::RngCore;
+use secp256k1_zkp::{Secp256k1, SecretKey, PublicKey, XOnlyPublicKey, KeyPair, Message, musig::{new_musig_nonce_pair, MusigAggNonce, MusigKeyAggCache, MusigPartialSignature, MusigPubNonce, MusigSecNonce, MusigSession, MusigSessionId}};
+use sqlx::{Sqlite, Row};
+
+use crate::{addresses, scripts, signer, wallet::TxOptions};
+
+/// Prefix of the PSBT proprietary fields carrying MuSig2 nonces and partial signatures, keyed
+/// by the participant's public key, so they travel (and get combined) with the PSBT.
+const PSBT_MUSIG_PREFIX: &[u8] = b"musig2";
+const PSBT_MUSIG_PUB_NONCE: u8 = 0x01;
+const PSBT_MUSIG_PARTIAL_SIG: u8 = 0x02;
+
+/// Path of the keys this wallet contributes to MuSig2 addresses, kept apart from the
+/// receive (0) and change (1) chains so they never show up as single-key addresses.
+const MUSIG_KEY_PATH: &str = "m/86h/0h/0h/2";
+
+#[derive(Clone)]
+pub struct MusigAddress {
+    pub address: Address,
+    pub secret_key: SecretKey,
+    pub public_key: PublicKey,
+    /// Every participant's key in key-aggregation order, including ours.
+    pub participants: Vec<PublicKey>,
+    pub aggregate_pubkey: XOnlyPublicKey,
+    pub fingerprint: String,
+    pub derivation_path: String,
+}
+
+async fn get_next_musig_index(pool: &sqlx::Pool<Sqlite>) -> u32 {
+    let row = sqlx::query("SELECT MAX(bip32_index) FROM musig_addresses")
+        .fetch_one(pool)
+        .await
+        .unwrap();
+
+    match row.get::<Option<u32>, _>(0) {
+        Some(index) => index + 1,
+        None => 0,
+    }
+}
+
+/// Key pair at `index` of the MuSig2 chain, with its fingerprint and derivation path.
+async fn derive_musig_key(pool: &sqlx::Pool<Sqlite>, network: Network, index: u32) -> (SecretKey, PublicKey, String, String) {
+    let secp = Secp256k1::new();
+
+    let root = addresses::get_root_key(pool, network).await;
+    let fingerprint = root.fingerprint(&secp).to_string();
+
+    let derivation_path = DerivationPath::from_str(MUSIG_KEY_PATH).unwrap()
+        .child(ChildNumber::from_normal_idx(index).unwrap());
+
+    let secret_key = root.derive_priv(&secp, &derivation_path).unwrap().private_key;
+    let public_key = secret_key.public_key(&secp);
+
+    (secret_key, public_key, fingerprint, derivation_path.to_string())
+}
+
+/// Public key the next MuSig2 address will use for this wallet, to hand to the cosigners.
+pub async fn get_next_public_key(pool: &sqlx::Pool<Sqlite>, network: Network) -> PublicKey {
+    let index = get_next_musig_index(pool).await;
+
+    derive_musig_key(pool, network, index).await.1
+}
+
+/// Sorts the keys as in BIP327 KeySort, so that every cosigner gets the same aggregated key
+/// whatever order they list the others in.
+pub fn sort_participants(participants: &mut [PublicKey]) {
+    participants.sort_by_key(|public_key| public_key.serialize());
+}
+
+/// Key-aggregation cache of `participants`, tweaked for a BIP86 key-path spend (no script tree).
+/// Aggregation is deterministic, so the cache is rebuilt from the stored participants instead
+/// of being serialized.
+pub fn get_key_agg_cache(participants: &[PublicKey]) -> Result<MusigKeyAggCache, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let mut key_agg_cache = MusigKeyAggCache::new(&secp, participants);
+
+    let tweak = TapTweakHash::from_key_and_tweak(key_agg_cache.agg_pk(), None);
+    let tweak = SecretKey::from_slice(&tweak.to_byte_array())?;
+
+    key_agg_cache
+        .pubkey_xonly_tweak_add(&secp, tweak)
+        .map_err(|e| format!("Invalid key tweak: {:?}", e))?;
+
+    Ok(key_agg_cache)
+}
+
+/// Creates the n-of-n MuSig2 address of a new wallet key and `cosigner_pubkeys`. The address
+/// commits to the aggregated key with the BIP86 tweak, so the output can only be spent by all
+/// the participants signing together.
+pub async fn new_musig_address(pool: &sqlx::Pool<Sqlite>, network: Network, cosigner_pubkeys: &[String]) -> Result<MusigAddress, Box<dyn std::error::Error>> {
+    if cosigner_pubkeys.is_empty() {
+        return Err("At least one cosigner public key is required".into());
+    }
+
+    let index = get_next_musig_index(pool).await;
+    let (secret_key, public_key, fingerprint, derivation_path) = derive_musig_key(pool, network, index).await;
+
+    let mut participants = vec![public_key];
+
+    for cosigner_pubkey in cosigner_pubkeys {
+        let cosigner_pubkey = PublicKey::from_str(cosigner_pubkey)
+            .map_err(|e| format!("Invalid cosigner public key {}: {}", cosigner_pubkey, e))?;
+
+        if participants.contains(&cosigner_pubkey) {
+            return Err(format!("Duplicate public key {}", cosigner_pubkey).into());
+        }
+
+        participants.push(cosigner_pubkey);
+    }
+
+    sort_participants(&mut participants);
+
+    let aggregate_pubkey = MusigKeyAggCache::new(&Secp256k1::new(), &participants).agg_pk();
+
+    let address = Address::p2tr(&Secp256k1::new(), aggregate_pubkey, None, network);
+
+    // The tweaked cache is what the signing sessions use, so it has to sign for this address.
+    let output_key = get_key_agg_cache(&participants)?.agg_pk();
+    if address.script_pubkey() != ScriptBuf::new_v1_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(output_key)) {
+        return Err("The tweaked key-aggregation cache doesn't match the address".into());
+    }
+
+    let musig_address = MusigAddress {
+        address,
+        secret_key,
+        public_key,
+        participants,
+        aggregate_pubkey,
+        fingerprint,
+        derivation_path,
+    };
+
+    insert_musig_address(pool, index, &musig_address).await;
+
+    Ok(musig_address)
+}
+
+async fn insert_musig_address(pool: &sqlx::Pool<Sqlite>, bip32_index: u32, musig_address: &MusigAddress) {
+    let query = "INSERT INTO musig_addresses (bip32_index, client_seckey, client_pubkey, participants, aggregate_pubkey, p2tr_address, fingerprint, derivation_path) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)";
+
+    let participants: Vec<String> = musig_address.participants.iter().map(|p| p.to_string()).collect();
+
+    let _ = sqlx::query(query)
+        .bind(bip32_index)
+        .bind(&musig_address.secret_key.secret_bytes().to_vec())
+        .bind(&musig_address.public_key.serialize().to_vec())
+        .bind(serde_json::to_string(&participants).unwrap())
+        .bind(&musig_address.aggregate_pubkey.serialize().to_vec())
+        .bind(&musig_address.address.to_string())
+        .bind(&musig_address.fingerprint)
+        .bind(&musig_address.derivation_path)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+pub async fn get_musig_addresses(pool: &sqlx::Pool<Sqlite>, network: Network) -> Vec::<MusigAddress> {
+    let query = "SELECT client_seckey, client_pubkey, participants, aggregate_pubkey, p2tr_address, fingerprint, derivation_path FROM musig_addresses";
+
+    let rows = sqlx::query(query)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+
+    let mut addresses = Vec::<MusigAddress>::new();
+
+    for row in rows {
+        let p2tr_address = row.get::<String, _>("p2tr_address");
+        let address = Address::from_str(&p2tr_address).unwrap().require_network(network).unwrap();
+
+        let secret_key = SecretKey::from_slice(&row.get::<Vec<u8>, _>("client_seckey")).unwrap();
+        let public_key = PublicKey::from_slice(&row.get::<Vec<u8>, _>("client_pubkey")).unwrap();
+
+        let participants: Vec<String> = serde_json::from_str(&row.get::<String, _>("participants")).unwrap();
+        let participants = participants.iter().map(|p| PublicKey::from_str(p).unwrap()).collect();
+
+        let aggregate_pubkey = XOnlyPublicKey::from_slice(&row.get::<Vec<u8>, _>("aggregate_pubkey")).unwrap();
+
+        addresses.push(MusigAddress {
+            address,
+            secret_key,
+            public_key,
+            participants,
+            aggregate_pubkey,
+            fingerprint: row.get::<String, _>("fingerprint"),
+            derivation_path: row.get::<String, _>("derivation_path"),
+        });
+    }
+
+    addresses
+}
+
+/// Builds an unsigned PSBT sending every output of `musig_address` to `to_address`. Its inputs
+/// have the aggregated key as `tap_internal_key`, which is how the signing rounds find them.
+pub fn create_sweep_psbt(client: &electrum_client::Client, musig_address: &MusigAddress, to_address: &Address, fee_rate: u64, options: &TxOptions) -> Result<Psbt, Box<dyn std::error::Error>> {
+    let descriptor = Descriptor::<DefiniteDescriptorKey>::from_str(&format!("tr({})", musig_address.aggregate_pubkey))?;
+
+    scripts::create_sweep_psbt(client, &musig_address.address, &descriptor, to_address, fee_rate, options)
+}
+
+fn proprietary_key(subtype: u8, public_key: &PublicKey) -> ProprietaryKey {
+    ProprietaryKey {
+        prefix: PSBT_MUSIG_PREFIX.to_vec(),
+        subtype,
+        key: public_key.serialize().to_vec(),
+    }
+}
+
+/// Inputs of `psbt` spending the key path of one of our MuSig2 addresses.
+async fn get_musig_inputs(pool: &sqlx::Pool<Sqlite>, network: Network, psbt: &Psbt) -> Vec<(usize, MusigAddress)> {
+    let musig_addresses = get_musig_addresses(pool, network).await;
+
+    psbt.inputs
+        .iter()
+        .enumerate()
+        .filter(|(_, input)| input.tap_merkle_root.is_none())
+        .filter_map(|(index, input)| {
+            let internal_key = input.tap_internal_key?;
+
+            musig_addresses
+                .iter()
+                .find(|musig_address| musig_address.aggregate_pubkey == internal_key)
+                .map(|musig_address| (index, musig_address.clone()))
+        })
+        .collect()
+}
+
+fn get_pub_nonces(psbt: &Psbt, index: usize, participants: &[PublicKey]) -> Result<Vec<MusigPubNonce>, Box<dyn std::error::Error>> {
+    participants
+        .iter()
+        .map(|participant| {
+            let pub_nonce = psbt.inputs[index].proprietary
+                .get(&proprietary_key(PSBT_MUSIG_PUB_NONCE, participant))
+                .ok_or_else(|| format!("Input {}: missing the nonce of {}", index, participant))?;
+
+            MusigPubNonce::from_slice(pub_nonce)
+                .map_err(|e| format!("Input {}: invalid nonce of {}: {:?}", index, participant, e).into())
+        })
+        .collect()
+}
+
+/// First round: generates a fresh nonce for every MuSig2 input we take part in and adds the
+/// public nonce to the PSBT. The secret nonce is stored under a random session id until it is
+/// used by `partial_sign`. Inputs that already have our nonce are left as they are.
+/// Returns the indexes of the inputs that got a new nonce.
+pub async fn add_nonces(pool: &sqlx::Pool<Sqlite>, network: Network, psbt: &mut Psbt) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let txid = psbt.unsigned_tx.txid();
+
+    let mut nonce_inputs = Vec::<usize>::new();
+
+    for (index, musig_address) in get_musig_inputs(pool, network, psbt).await {
+        let nonce_key = proprietary_key(PSBT_MUSIG_PUB_NONCE, &musig_address.public_key);
+
+        if let Some(pub_nonce) = psbt.inputs[index].proprietary.get(&nonce_key) {
+            if get_sec_nonce(pool, &musig_address.public_key, pub_nonce).await.is_some() {
+                continue;
+            }
+        }
+
+        let key_agg_cache = get_key_agg_cache(&musig_address.participants)?;
+
+        let (hash, _) = signer::key_spend_sighash(psbt, index)?;
+        let msg = Message::from_slice(hash.as_byte_array())?;
+
+        let mut session_id = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut session_id);
+
+        let (sec_nonce, pub_nonce) = new_musig_nonce_pair(
+            &secp,
+            MusigSessionId::assume_unique_per_nonce_gen(session_id),
+            Some(&key_agg_cache),
+            Some(musig_address.secret_key),
+            musig_address.public_key,
+            Some(msg),
+            None,
+        ).map_err(|e| format!("Input {}: nonce generation failed: {:?}", index, e))?;
+
+        insert_nonce(pool, &session_id, &txid.to_string(), index, &musig_address.public_key, &sec_nonce, &pub_nonce).await;
+
+        psbt.inputs[index].proprietary.insert(nonce_key, pub_nonce.serialize().to_vec());
+
+        nonce_inputs.push(index);
+    }
+
+    Ok(nonce_inputs)
+}
+
+/// Second round: once the PSBT has the nonces of every participant, signs each MuSig2 input
+/// with our stored secret nonce and adds the partial signature to the PSBT. A secret nonce is
+/// erased before it is used, so a failed or repeated round needs new nonces rather than
+/// signing twice with the same one.
+/// Returns the indexes of the inputs that were signed.
+pub async fn partial_sign(pool: &sqlx::Pool<Sqlite>, network: Network, psbt: &mut Psbt) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let txid = psbt.unsigned_tx.txid().to_string();
+
+    let mut signed_inputs = Vec::<usize>::new();
+
+    for (index, musig_address) in get_musig_inputs(pool, network, psbt).await {
+        let partial_sig_key = proprietary_key(PSBT_MUSIG_PARTIAL_SIG, &musig_address.public_key);
+
+        if psbt.inputs[index].proprietary.contains_key(&partial_sig_key) {
+            continue;
+        }
+
+        let pub_nonces = get_pub_nonces(psbt, index, &musig_address.participants)?;
+
+        let our_pub_nonce = psbt.inputs[index].proprietary
+            .get(&proprietary_key(PSBT_MUSIG_PUB_NONCE, &musig_address.public_key))
+            .ok_or_else(|| format!("Input {}: missing our nonce", index))?;
+
+        let (nonce_txid, nonce_index, sec_nonce) = get_sec_nonce(pool, &musig_address.public_key, our_pub_nonce).await
+            .ok_or_else(|| format!("Input {}: our nonce was already used or is unknown, create new nonces", index))?;
+
+        if nonce_txid != txid || nonce_index != index {
+            return Err(format!("Input {}: our nonce was created for another transaction", index).into());
+        }
+
+        erase_sec_nonce(pool, &musig_address.public_key, our_pub_nonce).await;
+
+        let key_agg_cache = get_key_agg_cache(&musig_address.participants)?;
+
+        let (hash, _) = signer::key_spend_sighash(psbt, index)?;
+        let msg = Message::from_slice(hash.as_byte_array())?;
+
+        let agg_nonce = MusigAggNonce::new(&secp, &pub_nonces);
+        let session = MusigSession::new(&secp, &key_agg_cache, agg_nonce, msg);
+
+        let keypair = KeyPair::from_secret_key(&secp, &musig_address.secret_key);
+
+        let partial_sig = session
+            .partial_sign(&secp, sec_nonce, &keypair, &key_agg_cache)
+            .map_err(|e| format!("Input {}: partial signing failed: {:?}", index, e))?;
+
+        psbt.inputs[index].proprietary.insert(partial_sig_key, partial_sig.serialize().to_vec());
+
+        signed_inputs.push(index);
+    }
+
+    Ok(signed_inputs)
+}
+
+/// Checks every participant's partial signature of each MuSig2 input and aggregates them into
+/// the `tap_key_sig`, removing the nonces and partial signatures from the PSBT.
+/// Returns the indexes of the inputs that were signed.
+pub async fn aggregate_signatures(pool: &sqlx::Pool<Sqlite>, network: Network, psbt: &mut Psbt) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let mut signed_inputs = Vec::<usize>::new();
+
+    for (index, musig_address) in get_musig_inputs(pool, network, psbt).await {
+        let pub_nonces = get_pub_nonces(psbt, index, &musig_address.participants)?;
+
+        let key_agg_cache = get_key_agg_cache(&musig_address.participants)?;
+
+        let (hash, hash_ty) = signer::key_spend_sighash(psbt, index)?;
+        let msg = Message::from_slice(hash.as_byte_array())?;
+
+        let agg_nonce = MusigAggNonce::new(&secp, &pub_nonces);
+        let session = MusigSession::new(&secp, &key_agg_cache, agg_nonce, msg);
+
+        let mut partial_sigs = Vec::<MusigPartialSignature>::new();
+
+        for (participant, pub_nonce) in musig_address.participants.iter().zip(pub_nonces) {
+            let partial_sig = psbt.inputs[index].proprietary
+                .get(&proprietary_key(PSBT_MUSIG_PARTIAL_SIG, participant))
+                .ok_or_else(|| format!("Input {}: missing the partial signature of {}", index, participant))?;
+
+            let partial_sig = MusigPartialSignature::from_slice(partial_sig)
+                .map_err(|e| format!("Input {}: invalid partial signature of {}: {:?}", index, participant, e))?;
+
+            if !session.partial_verify(&secp, &key_agg_cache, partial_sig, pub_nonce, *participant) {
+                return Err(format!("Input {}: the partial signature of {} is not valid", index, participant).into());
+            }
+
+            partial_sigs.push(partial_sig);
+        }
+
+        let sig = session.partial_sig_agg(&partial_sigs);
+
+        secp.verify_schnorr(&sig, &msg, &key_agg_cache.agg_pk())
+            .map_err(|e| format!("Input {}: aggregated signature is not valid: {}", index, e))?;
+
+        let input = &mut psbt.inputs[index];
+        input.tap_key_sig = Some(taproot::Signature { sig, hash_ty });
+        input.proprietary.retain(|key, _| key.prefix != PSBT_MUSIG_PREFIX);
+
+        signed_inputs.push(index);
+    }
+
+    Ok(signed_inputs)
+}
+
+async fn insert_nonce(pool: &sqlx::Pool<Sqlite>, session_id: &[u8; 32], txid: &str, input_index: usize, client_pubkey: &PublicKey, sec_nonce: &MusigSecNonce, pub_nonce: &MusigPubNonce) {
+    let query = "INSERT INTO musig_nonces (session_id, txid, input_index, client_pubkey, secnonce, pubnonce) VALUES ($1, $2, $3, $4, $5, $6)";
+
+    let _ = sqlx::query(query)
+        .bind(session_id.to_vec())
+        .bind(txid)
+        .bind(input_index as u32)
+        .bind(&client_pubkey.serialize().to_vec())
+        .bind(sec_nonce.serialize().to_vec())
+        .bind(pub_nonce.serialize().to_vec())
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+/// Transaction, input and secret nonce of `pub_nonce`, if it hasn't been used yet.
+async fn get_sec_nonce(pool: &sqlx::Pool<Sqlite>, client_pubkey: &PublicKey, pub_nonce: &[u8]) -> Option<(String, usize, MusigSecNonce)> {
+    let query = "SELECT txid, input_index, secnonce FROM musig_nonces WHERE client_pubkey = $1 AND pubnonce = $2 AND secnonce IS NOT NULL";
+
+    let row = sqlx::query(query)
+        .bind(&client_pubkey.serialize().to_vec())
+        .bind(pub_nonce)
+        .fetch_optional(pool)
+        .await
+        .unwrap()?;
+
+    let sec_nonce = MusigSecNonce::from_slice(&row.get::<Vec<u8>, _>("secnonce")).ok()?;
+
+    Some((row.get::<String, _>("txid"), row.get::<u32, _>("input_index") as usize, sec_nonce))
+}
+
+/// Erases the secret nonce but keeps the session, so the record shows it was used.
+async fn erase_sec_nonce(pool: &sqlx::Pool<Sqlite>, client_pubkey: &PublicKey, pub_nonce: &[u8]) {
+    let query = "UPDATE musig_nonces SET secnonce = NULL WHERE client_pubkey = $1 AND pubnonce = $2";
+
+    let _ = sqlx::query(query)
+        .bind(&client_pubkey.serialize().to_vec())
+        .bind(pub_nonce)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aggregated_signature_verifies_for_the_address() {
+        let secp = Secp256k1::new();
+
+        let keys: Vec<_> = (0..3).map(|_| secp.generate_keypair(&mut rand::thread_rng())).collect();
+
+        let mut participants: Vec<_> = keys.iter().map(|(_, public_key)| *public_key).collect();
+        let mut reversed: Vec<_> = participants.iter().rev().cloned().collect();
+        sort_participants(&mut participants);
+        sort_participants(&mut reversed);
+        assert_eq!(participants, reversed);
+
+        let aggregate_pubkey = MusigKeyAggCache::new(&secp, &participants).agg_pk();
+        let address = Address::p2tr(&secp, aggregate_pubkey, None, Network::Regtest);
+
+        let key_agg_cache = get_key_agg_cache(&participants).unwrap();
+        assert_eq!(address.script_pubkey(), ScriptBuf::new_v1_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(key_agg_cache.agg_pk())));
+
+        let msg = Message::from_slice(&[7u8; 32]).unwrap();
+
+        let (sec_nonces, pub_nonces): (Vec<_>, Vec<_>) = keys
+            .iter()
+            .map(|(secret_key, public_key)| new_musig_nonce_pair(&secp, MusigSessionId::new(&mut rand::thread_rng()), None, Some(*secret_key), *public_key, None, None).unwrap())
+            .unzip();
+
+        let session = MusigSession::new(&secp, &key_agg_cache, MusigAggNonce::new(&secp, &pub_nonces), msg);
+
+        let partial_sigs: Vec<_> = keys
+            .iter()
+            .zip(sec_nonces)
+            .map(|((secret_key, _), sec_nonce)| session.partial_sign(&secp, sec_nonce, &KeyPair::from_secret_key(&secp, secret_key), &key_agg_cache).unwrap())
+            .collect();
+
+        let sig = session.partial_sig_agg(&partial_sigs);
+
+        assert!(secp.verify_schnorr(&sig, &msg, &key_agg_cache.agg_pk()).is_ok());
+    }
+}
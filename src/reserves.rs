@@ -0,0 +1,152 @@
+use std::collections::{HashMap, HashSet};
+
+use bitcoin::{Address, Network, OutPoint, TxIn, TxOut, Txid, Witness, hashes::{Hash, sha256d}, opcodes::OP_TRUE, psbt::{self, Psbt}, script::Builder, sighash::{Prevouts, SighashCache, TapSighashType}, taproot};
+use secp256k1_zkp::{Secp256k1, XOnlyPublicKey, Message};
+use sqlx::Sqlite;
+
+use crate::{backend, signer, wallet};
+
+/// Outpoint of the BIP127 commitment input: txid = SHA256d("Proof-of-Reserves: " || message),
+/// as bdk-reserves computes it, so that proofs verify with either implementation.
+/// It doesn't exist, so the proof can never be broadcast as a transaction.
+pub fn challenge_outpoint(message: &str) -> OutPoint {
+    let hash = sha256d::Hash::hash(format!("Proof-of-Reserves: {}", message).as_bytes());
+
+    OutPoint { txid: Txid::from_byte_array(hash.to_byte_array()), vout: 0 }
+}
+
+/// Output spent by the commitment input, as set in its `witness_utxo` for the signatures of
+/// the other inputs to commit to.
+fn challenge_txout() -> TxOut {
+    TxOut { value: 0, script_pubkey: Builder::new().push_opcode(OP_TRUE).into_script() }
+}
+
+/// Builds a finalized BIP127 proof of reserves for `message`: the commitment input followed by
+/// every confirmed wallet UTXO, all signed with SIGHASH_ALL, and a single output of the total
+/// amount. Unconfirmed UTXOs are left out, they may never make it into the chain.
+/// Returns the PSBT, the total amount and the number of UTXOs.
+pub async fn create_proof(pool: &sqlx::Pool<Sqlite>, client: &electrum_client::Client, network: Network, message: &str) -> Result<(Psbt, u64, usize), Box<dyn std::error::Error>> {
+    let list_unspent: Vec<wallet::AddressInfo> = wallet::get_list_unspent(pool, client, network).await
+        .into_iter()
+        .filter(|utxo| utxo.height > 0)
+        .collect();
+
+    if list_unspent.is_empty() {
+        return Err("The wallet has no confirmed UTXOs".into());
+    }
+
+    let reserves: u64 = list_unspent.iter().map(|utxo| utxo.value).sum();
+
+    let outputs = vec![TxOut { value: reserves, ..challenge_txout() }];
+    let options = wallet::TxOptions { ordering: wallet::TxOrdering::Unordered, ..Default::default() };
+
+    let (mut psbt, _) = wallet::create_p2tr_key_spend_psbt(&list_unspent, &outputs, None, &options)?;
+
+    psbt.unsigned_tx.input.insert(0, TxIn { previous_output: challenge_outpoint(message), ..Default::default() });
+    psbt.inputs.insert(0, psbt::Input { witness_utxo: Some(challenge_txout()), ..Default::default() });
+
+    let secret_keys: HashMap<_, _> = list_unspent.iter().map(|utxo| (utxo.xonly_public_key, utxo.secret_key)).collect();
+
+    signer::sign_psbt(&mut psbt, &secret_keys)?;
+
+    // The commitment input is finalized empty, there is nothing it could be signed with.
+    psbt.inputs[0].final_script_witness = Some(Witness::new());
+    signer::finalize_psbt(&mut psbt)?;
+
+    Ok((psbt, reserves, list_unspent.len()))
+}
+
+/// Checks a proof of reserves for `message` against the backend: the commitment input, the
+/// key-path signature of every other input, and that those inputs are still unspent and
+/// confirmed.
+/// Returns the total amount and the number of UTXOs.
+pub fn verify_proof(client: &electrum_client::Client, network: Network, psbt: &Psbt, message: &str) -> Result<(u64, usize), Box<dyn std::error::Error>> {
+    let secp = Secp256k1::verification_only();
+
+    let tx = &psbt.unsigned_tx;
+
+    if tx.input.len() < 2 {
+        return Err("The proof has no reserves inputs".into());
+    }
+
+    if tx.input[0].previous_output != challenge_outpoint(message) {
+        return Err("The first input doesn't commit to this message".into());
+    }
+
+    if tx.output.len() != 1 {
+        return Err("The proof must have a single output".into());
+    }
+
+    let mut prevouts = vec![challenge_txout()];
+    let mut outpoints = HashSet::new();
+
+    for (index, input) in tx.input.iter().enumerate().skip(1) {
+        let outpoint = input.previous_output;
+
+        // The same UTXO twice would count its amount twice.
+        if !outpoints.insert(outpoint) {
+            return Err(format!("Input {}: {} is already in the proof", index, outpoint).into());
+        }
+
+        let prev_tx = backend::try_get_transaction(client, &outpoint.txid).map_err(|e| format!("Input {}: {}", index, e))?;
+        let prevout = prev_tx.output.get(outpoint.vout as usize).ok_or_else(|| format!("Input {}: {} doesn't exist", index, outpoint))?;
+
+        let address = Address::from_script(&prevout.script_pubkey, network).map_err(|e| format!("Input {}: {}", index, e))?;
+
+        let height = backend::get_script_list_unspent(client, &address)
+            .iter()
+            .find(|utxo| utxo.tx_hash == outpoint.txid && utxo.tx_pos == outpoint.vout as usize)
+            .map(|utxo| utxo.height)
+            .ok_or_else(|| format!("Input {}: {} is spent", index, outpoint))?;
+
+        // An unconfirmed output may be double spent, or never confirm at all.
+        if height == 0 {
+            return Err(format!("Input {}: {} is not confirmed", index, outpoint).into());
+        }
+
+        prevouts.push(prevout.clone());
+    }
+
+    let mut sighash_cache = SighashCache::new(tx);
+
+    for index in 1..tx.input.len() {
+        let script_pubkey = &prevouts[index].script_pubkey;
+        if !script_pubkey.is_v1_p2tr() {
+            return Err(format!("Input {} is not P2TR", index).into());
+        }
+
+        let witness = psbt.inputs[index].final_script_witness.as_ref().ok_or_else(|| format!("Input {} is not finalized", index))?;
+        if witness.len() != 1 {
+            return Err(format!("Input {} is not a key-path spend", index).into());
+        }
+
+        let signature = taproot::Signature::from_slice(&witness[0])?;
+
+        // Other sighash types wouldn't commit to the commitment input.
+        if signature.hash_ty != TapSighashType::Default && signature.hash_ty != TapSighashType::All {
+            return Err(format!("Input {} is not signed with SIGHASH_ALL", index).into());
+        }
+
+        let hash = sighash_cache.taproot_key_spend_signature_hash(index, &Prevouts::All(&prevouts), signature.hash_ty)?;
+        let msg = Message::from_slice(hash.as_byte_array())?;
+
+        let output_key = XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..34])?;
+
+        secp.verify_schnorr(&signature.sig, &msg, &output_key).map_err(|e| format!("Input {}: {}", index, e))?;
+    }
+
+    let reserves = prevouts.iter().map(|prevout| prevout.value).sum();
+
+    Ok((reserves, tx.input.len() - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn challenge_outpoint_vectors() {
+        assert_eq!(challenge_outpoint("").to_string(), "aa10b4e2626e016fa5773c84b355c283fd2fdee08a725a5469eb9367b453830d:0");
+        assert_eq!(challenge_outpoint("Hello World").to_string(), "4f36e89ef87b3b45ce5de4bcfa7df984430556cc24443ff169bf1b2fe9f0a487:0");
+    }
+}
@@ -0,0 +1,244 @@
+use std::{collections::HashMap, str::FromStr};
+
+use bitcoin::{Address, Network, Sequence, Transaction, TxIn, TxOut, OutPoint, ScriptBuf, Witness, bip32::{ChildNumber, DerivationPath}, psbt::Psbt, taproot::{TapLeafHash, LeafVersion}};
+use miniscript::{Descriptor, DescriptorPublicKey, DefiniteDescriptorKey, Terminal, policy::Concrete, psbt::PsbtExt};
+use secp256k1_zkp::Secp256k1;
+use sqlx::{Sqlite, Row};
+
+use crate::{addresses, backend, wallet::TxOptions};
+
+/// Path of the hot keys of recovery addresses, kept apart from the receive (0) and change (1)
+/// chains so they never show up as single-key addresses.
+const RECOVERY_KEY_PATH: &str = "m/86h/0h/0h/5";
+
+/// Stores a `tr(K,{...})` descriptor and returns its address. The descriptor must use fixed keys
+/// (no wildcards); keys with an origin from this wallet's seed, or keys of wallet addresses, can
+/// be signed for.
+pub async fn import_descriptor(pool: &sqlx::Pool<Sqlite>, network: Network, descriptor: &str) -> Result<Address, Box<dyn std::error::Error>> {
+    let descriptor = Descriptor::<DescriptorPublicKey>::from_str(descriptor)?;
+
+    if !matches!(descriptor, Descriptor::Tr(_)) {
+        return Err("Only tr() descriptors are supported".into());
+    }
+
+    if descriptor.has_wildcard() {
+        return Err("Ranged descriptors are not supported, use fixed keys".into());
+    }
+
+    let address = descriptor.at_derivation_index(0)?.address(network)?;
+
+    insert_script_address(pool, &address, &descriptor.to_string(), None).await;
+
+    Ok(address)
+}
+
+async fn get_next_recovery_index(pool: &sqlx::Pool<Sqlite>) -> u32 {
+    let row = sqlx::query("SELECT MAX(hot_key_index) FROM script_addresses")
+        .fetch_one(pool)
+        .await
+        .unwrap();
+
+    match row.get::<Option<u32>, _>(0) {
+        Some(index) => index + 1,
+        None => 0,
+    }
+}
+
+/// Stores a `tr(hot,and_v(v:pk(recovery),older(blocks)))` address, with the next key of the
+/// recovery chain as hot key, and returns its address and descriptor.
+pub async fn new_recovery_address(pool: &sqlx::Pool<Sqlite>, network: Network, recovery_key: &str, blocks: u16) -> Result<(Address, String), Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let index = get_next_recovery_index(pool).await;
+
+    let root = addresses::get_root_key(pool, network).await;
+    let derivation_path = DerivationPath::from_str(RECOVERY_KEY_PATH).unwrap()
+        .child(ChildNumber::from_normal_idx(index).unwrap());
+
+    let hot_key = root.derive_priv(&secp, &derivation_path)?.private_key.x_only_public_key(&secp).0;
+
+    // The key origin lets any copy of this seed find and sign with the hot key.
+    let hot_key = format!("[{}/{}/{}]{}", root.fingerprint(&secp), RECOVERY_KEY_PATH.trim_start_matches("m/"), index, hot_key);
+    let descriptor = format!("tr({},and_v(v:pk({}),older({})))", hot_key, recovery_key, blocks);
+
+    let address = import_descriptor(pool, network, &descriptor).await?;
+
+    let _ = sqlx::query("UPDATE script_addresses SET hot_key_index = $1 WHERE p2tr_address = $2")
+        .bind(index)
+        .bind(&address.to_string())
+        .execute(pool)
+        .await
+        .unwrap();
+
+    Ok((address, descriptor))
+}
+
+/// Unspendable internal key from BIP341 (the "H" point), used when no key of the policy can
+/// be taken out for the key path.
+pub const UNSPENDABLE_KEY: &str = "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0";
+
+/// Compiles a policy such as `or(pk(A),and(pk(B),after(800000)))` to a `tr()` descriptor,
+/// stores it along with the policy and returns its address and descriptor. The most likely
+/// single key spend becomes the key path and the rest is laid out as a tapscript tree
+/// weighted by the `N@` probabilities of the policy.
+pub async fn import_policy(pool: &sqlx::Pool<Sqlite>, network: Network, policy: &str) -> Result<(Address, String), Box<dyn std::error::Error>> {
+    let concrete = Concrete::<DescriptorPublicKey>::from_str(policy)?;
+    concrete.is_valid()?;
+
+    let unspendable_key = DescriptorPublicKey::from_str(UNSPENDABLE_KEY)?;
+    let descriptor = concrete.compile_tr(Some(unspendable_key))?;
+
+    if descriptor.has_wildcard() {
+        return Err("Ranged policies are not supported, use fixed keys".into());
+    }
+
+    let address = descriptor.at_derivation_index(0)?.address(network)?;
+    let descriptor = descriptor.to_string();
+
+    insert_script_address(pool, &address, &descriptor, Some(policy)).await;
+
+    Ok((address, descriptor))
+}
+
+pub async fn insert_script_address(pool: &sqlx::Pool<Sqlite>, address: &Address, descriptor: &str, policy: Option<&str>) {
+    let query = "INSERT INTO script_addresses (p2tr_address, descriptor, policy) VALUES ($1, $2, $3)";
+
+    let _ = sqlx::query(query)
+        .bind(&address.to_string())
+        .bind(descriptor)
+        .bind(policy)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+/// Policies of the addresses imported with `import_policy`, by address.
+pub async fn get_script_policies(pool: &sqlx::Pool<Sqlite>) -> HashMap<String, String> {
+    let query = "SELECT p2tr_address, policy FROM script_addresses WHERE policy IS NOT NULL";
+
+    let rows = sqlx::query(query)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+
+    rows.iter()
+        .map(|row| (row.get::<String, _>("p2tr_address"), row.get::<String, _>("policy")))
+        .collect()
+}
+
+pub async fn get_script_addresses(pool: &sqlx::Pool<Sqlite>, network: Network) -> Vec::<(Address, Descriptor<DefiniteDescriptorKey>)> {
+    let query = "SELECT p2tr_address, descriptor FROM script_addresses";
+
+    let rows = sqlx::query(query)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+
+    let mut addresses = Vec::<(Address, Descriptor<DefiniteDescriptorKey>)>::new();
+
+    for row in rows {
+        let p2tr_address = row.get::<String, _>("p2tr_address");
+        let address = Address::from_str(&p2tr_address).unwrap().require_network(network).unwrap();
+
+        let descriptor = row.get::<String, _>("descriptor");
+        let descriptor = Descriptor::<DescriptorPublicKey>::from_str(&descriptor).unwrap()
+            .at_derivation_index(0)
+            .unwrap();
+
+        addresses.push((address, descriptor));
+    }
+
+    addresses
+}
+
+pub async fn get_script_address(pool: &sqlx::Pool<Sqlite>, network: Network, address: &Address) -> Option<Descriptor<DefiniteDescriptorKey>> {
+    get_script_addresses(pool, network).await
+        .into_iter()
+        .find(|(a, _)| a == address)
+        .map(|(_, descriptor)| descriptor)
+}
+
+/// Leaves of the tapscript tree in depth-first order, which is the order `--leaf` refers to.
+pub fn get_leaves(descriptor: &Descriptor<DefiniteDescriptorKey>) -> Vec::<(TapLeafHash, String)> {
+    match descriptor {
+        Descriptor::Tr(tr) => tr
+            .iter_scripts()
+            .map(|(_, ms)| (TapLeafHash::from_script(&ms.encode(), LeafVersion::TapScript), ms.to_string()))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Index and relative timelock of the first leaf with an `older(N)`, such as the recovery leaf
+/// of a `tr(hot,and_v(v:pk(recovery),older(N)))` address.
+pub fn get_recovery_leaf(descriptor: &Descriptor<DefiniteDescriptorKey>) -> Option<(usize, Sequence)> {
+    match descriptor {
+        Descriptor::Tr(tr) => tr
+            .iter_scripts()
+            .enumerate()
+            .find_map(|(index, (_, ms))| {
+                ms.iter().find_map(|node| match node.node {
+                    Terminal::Older(sequence) => Some((index, sequence)),
+                    _ => None,
+                })
+            }),
+        _ => None,
+    }
+}
+
+/// Builds an unsigned PSBT sending every output of the script `address` to `to_address`.
+/// The inputs carry `tap_internal_key`, `tap_merkle_root`, `tap_scripts` (with the control
+/// blocks) and `tap_key_origins` from the descriptor. The fee for `fee_rate` (sat/vB) assumes
+/// the most expensive way of satisfying the descriptor.
+pub fn create_sweep_psbt(client: &electrum_client::Client, address: &Address, descriptor: &Descriptor<DefiniteDescriptorKey>, to_address: &Address, fee_rate: u64, options: &TxOptions) -> Result<Psbt, Box<dyn std::error::Error>> {
+    let utxos = backend::get_script_list_unspent(client, address);
+
+    if utxos.is_empty() {
+        return Err(format!("No coins in {}", address).into());
+    }
+
+    let input_amount: u64 = utxos.iter().map(|utxo| utxo.value).sum();
+
+    let inputs: Vec<TxIn> = utxos
+        .iter()
+        .map(|utxo| {
+            let outpoint = OutPoint { txid: utxo.tx_hash, vout: utxo.tx_pos as u32 };
+            TxIn {
+                previous_output: outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: options.input_sequences.get(&outpoint).copied().unwrap_or(options.sequence),
+                witness: Witness::default(),
+            }
+        })
+        .collect();
+
+    let mut tx = Transaction {
+        version: 2,
+        lock_time: options.lock_time,
+        input: inputs,
+        output: vec![TxOut { value: 0, script_pubkey: to_address.script_pubkey() }],
+    };
+
+    // The segwit marker and flag add 2 weight units to the unsigned transaction.
+    let satisfaction_weight = descriptor.max_weight_to_satisfy()? as u64;
+    let weight = tx.weight().to_wu() + 2 + satisfaction_weight * tx.input.len() as u64;
+    let fees = (weight + 3) / 4 * fee_rate;
+
+    let amount = input_amount.checked_sub(fees).ok_or("Fees more than input amount!")?;
+
+    let dust_value = to_address.script_pubkey().dust_value().to_sat();
+    if amount < dust_value {
+        return Err(format!("Amount {} after fees is below the dust limit of {}", amount, dust_value).into());
+    }
+
+    tx.output[0].value = amount;
+
+    let mut psbt = Psbt::from_unsigned_tx(tx)?;
+
+    for (index, utxo) in utxos.iter().enumerate() {
+        psbt.inputs[index].witness_utxo = Some(TxOut { value: utxo.value, script_pubkey: address.script_pubkey() });
+        psbt.update_input_with_descriptor(index, descriptor)?;
+    }
+
+    Ok(psbt)
+}
@@ -0,0 +1,508 @@
+use std::{str::FromStr, collections::{BTreeMap, HashMap}, io::Read};
+
+use bitcoin::{Address, Network, Transaction, Witness, TxOut, bip32::{ExtendedPrivKey, KeySource}, psbt::{Psbt, self}, sighash::{TapSighashType, SighashCache, self, TapSighash}, taproot::{TapLeafHash, self}, secp256k1, key::TapTweak};
+use secp256k1_zkp::{Secp256k1, XOnlyPublicKey, SecretKey};
+use miniscript::psbt::PsbtExt;
+use serde_json::json;
+
+use crate::{airgap, wallet};
+
+/// Signs every key-path input of `psbt` whose internal key is in `secret_keys`.
+/// Returns the indexes of the inputs that were signed.
+pub fn sign_psbt(psbt: &mut Psbt, secret_keys: &HashMap<XOnlyPublicKey, SecretKey>) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let unsigned_tx = psbt.unsigned_tx.clone();
+    let mut sighash_cache = SighashCache::new(&unsigned_tx);
+
+    let prevouts = get_prevouts(psbt)?;
+
+    let mut signed_inputs = Vec::<usize>::new();
+
+    for (index, input) in psbt.inputs.iter_mut().enumerate() {
+        let internal_key = match input.tap_internal_key {
+            Some(internal_key) => internal_key,
+            None => continue,
+        };
+
+        let secret_key = match secret_keys.get(&internal_key) {
+            Some(secret_key) => secret_key,
+            None => continue,
+        };
+
+        let hash_ty = input
+            .sighash_type
+            .and_then(|psbt_sighash_type| psbt_sighash_type.taproot_hash_ty().ok())
+            .unwrap_or(TapSighashType::All);
+
+        let hash = sighash_cache.taproot_key_spend_signature_hash(
+            index,
+            &sighash::Prevouts::All(&prevouts),
+            hash_ty,
+        )?;
+
+        sign_psbt_taproot(
+            secret_key,
+            internal_key,
+            None,
+            input,
+            hash,
+            hash_ty,
+            &secp,
+        );
+
+        signed_inputs.push(index);
+    }
+
+    Ok(signed_inputs)
+}
+
+/// Signs the tapscript leaves of every input with the keys in `secret_keys`, either in all the
+/// leaves the `tap_key_origins` list for a key or only in `leaf_hash`.
+/// Returns the indexes of the inputs that got at least one signature.
+pub fn sign_psbt_script_path(psbt: &mut Psbt, secret_keys: &HashMap<XOnlyPublicKey, SecretKey>, leaf_hash: Option<TapLeafHash>) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let unsigned_tx = psbt.unsigned_tx.clone();
+    let mut sighash_cache = SighashCache::new(&unsigned_tx);
+
+    let prevouts = get_prevouts(psbt)?;
+
+    let mut signed_inputs = Vec::<usize>::new();
+
+    for (index, input) in psbt.inputs.iter_mut().enumerate() {
+        let hash_ty = input
+            .sighash_type
+            .and_then(|psbt_sighash_type| psbt_sighash_type.taproot_hash_ty().ok())
+            .unwrap_or(TapSighashType::All);
+
+        let mut signed = false;
+
+        let tap_key_origins = input.tap_key_origins.clone();
+
+        for (xonly_public_key, (leaf_hashes, _)) in &tap_key_origins {
+            let secret_key = match secret_keys.get(xonly_public_key) {
+                Some(secret_key) => secret_key,
+                None => continue,
+            };
+
+            for lh in leaf_hashes {
+                if leaf_hash.map_or(false, |chosen| chosen != *lh) {
+                    continue;
+                }
+
+                let hash = sighash_cache.taproot_script_spend_signature_hash(
+                    index,
+                    &sighash::Prevouts::All(&prevouts),
+                    *lh,
+                    hash_ty,
+                )?;
+
+                sign_psbt_taproot(
+                    secret_key,
+                    *xonly_public_key,
+                    Some(*lh),
+                    input,
+                    hash,
+                    hash_ty,
+                    &secp,
+                );
+
+                signed = true;
+            }
+        }
+
+        if signed {
+            signed_inputs.push(index);
+        }
+    }
+
+    Ok(signed_inputs)
+}
+
+/// Key-path sighash of input `index` and the sighash type it commits to, for signers that
+/// produce the signature themselves (e.g. MuSig2 sessions).
+pub fn key_spend_sighash(psbt: &Psbt, index: usize) -> Result<(TapSighash, TapSighashType), Box<dyn std::error::Error>> {
+    let input = psbt.inputs.get(index).ok_or_else(|| format!("No input {}", index))?;
+
+    let hash_ty = input
+        .sighash_type
+        .and_then(|psbt_sighash_type| psbt_sighash_type.taproot_hash_ty().ok())
+        .unwrap_or(TapSighashType::All);
+
+    let prevouts = get_prevouts(psbt)?;
+
+    let hash = SighashCache::new(&psbt.unsigned_tx).taproot_key_spend_signature_hash(
+        index,
+        &sighash::Prevouts::All(&prevouts),
+        hash_ty,
+    )?;
+
+    Ok((hash, hash_ty))
+}
+
+fn get_prevouts(psbt: &Psbt) -> Result<Vec<TxOut>, String> {
+    psbt.inputs
+        .iter()
+        .enumerate()
+        .map(|(index, input)| input.witness_utxo.clone().ok_or_else(|| format!("Input {} has no witness_utxo", index)))
+        .collect()
+}
+
+/// Derives the secret keys for the `tap_key_origins` of `psbt` that belong to `root`,
+/// i.e. whose fingerprint matches and whose derivation gives back the same public key.
+pub fn secret_keys_from_origins(psbt: &Psbt, root: &ExtendedPrivKey) -> HashMap<XOnlyPublicKey, SecretKey> {
+    let mut secret_keys = HashMap::new();
+
+    for input in &psbt.inputs {
+        secret_keys.extend(secret_keys_from_origins_map(&input.tap_key_origins, root));
+    }
+
+    secret_keys
+}
+
+fn secret_keys_from_origins_map(tap_key_origins: &BTreeMap<XOnlyPublicKey, (Vec<TapLeafHash>, KeySource)>, root: &ExtendedPrivKey) -> HashMap<XOnlyPublicKey, SecretKey> {
+    let secp = Secp256k1::new();
+
+    let fingerprint = root.fingerprint(&secp);
+
+    let mut secret_keys = HashMap::new();
+
+    for (xonly_public_key, (_, (key_fingerprint, derivation_path))) in tap_key_origins {
+        if *key_fingerprint != fingerprint {
+            continue;
+        }
+
+        let secret_key = match root.derive_priv(&secp, derivation_path) {
+            Ok(child) => child.private_key,
+            Err(_) => continue,
+        };
+
+        if secret_key.x_only_public_key(&secp).0 == *xonly_public_key {
+            secret_keys.insert(*xonly_public_key, secret_key);
+        }
+    }
+
+    secret_keys
+}
+
+/// Moves the key-path signature of every input into its final witness and clears the
+/// other fields, as the BIP174/BIP371 finalizer does.
+/// Inputs signed only through tapscript leaves are finalized by miniscript, which picks the
+/// cheapest leaf that has all its signatures and whose timelocks the transaction meets.
+pub fn finalize_psbt(psbt: &mut Psbt) -> Result<(), Box<dyn std::error::Error>> {
+    let secp = Secp256k1::verification_only();
+
+    for index in 0..psbt.inputs.len() {
+        let input = &psbt.inputs[index];
+
+        if input.final_script_witness.is_some() {
+            continue;
+        }
+
+        if input.tap_key_sig.is_none() && !input.tap_script_sigs.is_empty() {
+            psbt.finalize_inp_mut(&secp, index).map_err(|e| format!("Input {}: {}", index, e))?;
+            continue;
+        }
+
+        let input = &mut psbt.inputs[index];
+
+        let signature = input.tap_key_sig.ok_or_else(|| format!("Input {} is not signed", index))?;
+
+        let mut script_witness: Witness = Witness::new();
+        script_witness.push(signature.to_vec());
+        input.final_script_witness = Some(script_witness);
+
+        // Clear all the data fields as per the spec.
+        input.partial_sigs = BTreeMap::new();
+        input.sighash_type = None;
+        input.redeem_script = None;
+        input.witness_script = None;
+        input.bip32_derivation = BTreeMap::new();
+        input.tap_key_sig = None;
+        input.tap_script_sigs = BTreeMap::new();
+        input.tap_scripts = BTreeMap::new();
+        input.tap_key_origins = BTreeMap::new();
+        input.tap_internal_key = None;
+        input.tap_merkle_root = None;
+    }
+
+    Ok(())
+}
+
+/// Extracts the network transaction from a finalized PSBT.
+pub fn extract_tx(psbt: Psbt) -> Result<Transaction, Box<dyn std::error::Error>> {
+    if let Some(index) = psbt.inputs.iter().position(|input| input.final_script_witness.is_none() && input.final_script_sig.is_none()) {
+        return Err(format!("Input {} is not finalized", index).into());
+    }
+
+    Ok(psbt.extract_tx())
+}
+
+/// Merges copies of the same unsigned transaction signed by different parties (BIP174 combiner),
+/// including the BIP371 taproot fields and proprietary fields such as MuSig2 nonces and partial
+/// signatures. Returns the combined PSBT and the conflicts found, i.e. fields set to different
+/// values in different copies. The combiner picks one of the values arbitrarily, so a PSBT with
+/// conflicts should not be trusted.
+pub fn combine_psbts(psbts: Vec<Psbt>) -> Result<(Psbt, Vec<String>), Box<dyn std::error::Error>> {
+    let mut psbts = psbts.into_iter();
+
+    let mut combined = psbts.next().ok_or("No PSBT to combine")?;
+
+    let mut conflicts = Vec::<String>::new();
+
+    for (n, psbt) in psbts.enumerate() {
+        let copy = format!("PSBT {}", n + 1);
+
+        map_conflicts(&combined.proprietary, &psbt.proprietary, &copy, "global proprietary", &mut conflicts);
+        map_conflicts(&combined.unknown, &psbt.unknown, &copy, "global unknown", &mut conflicts);
+
+        for (index, (a, b)) in combined.inputs.iter().zip(psbt.inputs.iter()).enumerate() {
+            let location = format!("{} input {}", copy, index);
+
+            option_conflict(&a.witness_utxo, &b.witness_utxo, &location, "witness_utxo", &mut conflicts);
+            option_conflict(&a.sighash_type, &b.sighash_type, &location, "sighash_type", &mut conflicts);
+            map_conflicts(&a.partial_sigs, &b.partial_sigs, &location, "partial_sigs", &mut conflicts);
+            option_conflict(&a.tap_key_sig, &b.tap_key_sig, &location, "tap_key_sig", &mut conflicts);
+            map_conflicts(&a.tap_script_sigs, &b.tap_script_sigs, &location, "tap_script_sigs", &mut conflicts);
+            map_conflicts(&a.tap_scripts, &b.tap_scripts, &location, "tap_scripts", &mut conflicts);
+            map_conflicts(&a.tap_key_origins, &b.tap_key_origins, &location, "tap_key_origins", &mut conflicts);
+            option_conflict(&a.tap_internal_key, &b.tap_internal_key, &location, "tap_internal_key", &mut conflicts);
+            option_conflict(&a.tap_merkle_root, &b.tap_merkle_root, &location, "tap_merkle_root", &mut conflicts);
+            option_conflict(&a.final_script_witness, &b.final_script_witness, &location, "final_script_witness", &mut conflicts);
+            map_conflicts(&a.proprietary, &b.proprietary, &location, "proprietary", &mut conflicts);
+            map_conflicts(&a.unknown, &b.unknown, &location, "unknown", &mut conflicts);
+        }
+
+        for (index, (a, b)) in combined.outputs.iter().zip(psbt.outputs.iter()).enumerate() {
+            let location = format!("{} output {}", copy, index);
+
+            option_conflict(&a.tap_internal_key, &b.tap_internal_key, &location, "tap_internal_key", &mut conflicts);
+            option_conflict(&a.tap_tree, &b.tap_tree, &location, "tap_tree", &mut conflicts);
+            map_conflicts(&a.tap_key_origins, &b.tap_key_origins, &location, "tap_key_origins", &mut conflicts);
+            map_conflicts(&a.proprietary, &b.proprietary, &location, "proprietary", &mut conflicts);
+            map_conflicts(&a.unknown, &b.unknown, &location, "unknown", &mut conflicts);
+        }
+
+        combined.combine(psbt).map_err(|e| format!("{}: {}", copy, e))?;
+    }
+
+    Ok((combined, conflicts))
+}
+
+fn option_conflict<V: PartialEq>(a: &Option<V>, b: &Option<V>, location: &str, field: &str, conflicts: &mut Vec<String>) {
+    if let (Some(a), Some(b)) = (a, b) {
+        if a != b {
+            conflicts.push(format!("{}: conflicting {}", location, field));
+        }
+    }
+}
+
+fn map_conflicts<K: Ord + std::fmt::Debug, V: PartialEq>(a: &BTreeMap<K, V>, b: &BTreeMap<K, V>, location: &str, field: &str, conflicts: &mut Vec<String>) {
+    for (key, value) in b {
+        if let Some(existing) = a.get(key) {
+            if existing != value {
+                conflicts.push(format!("{}: conflicting {} for {:?}", location, field, key));
+            }
+        }
+    }
+}
+
+/// Describes the inputs, outputs and fee of `psbt` from the point of view of this wallet:
+/// which outputs are ours (and which of them are change) and which inputs we can sign.
+/// `wallet_keys` are the internal keys of the wallet addresses, `change_addresses` the subset of
+/// `wallet_addresses` used for change. Anything worth a second look before signing is listed
+/// under "warnings".
+pub fn decode_psbt(psbt: &Psbt, network: Network, root: &ExtendedPrivKey, wallet_keys: &[XOnlyPublicKey], wallet_addresses: &[Address], change_addresses: &[Address]) -> serde_json::Value {
+    let secret_keys = secret_keys_from_origins(psbt, root);
+
+    let mut warnings = Vec::<String>::new();
+
+    let mut inputs = Vec::<serde_json::Value>::new();
+    let mut input_amount: Option<u64> = Some(0);
+
+    for (index, (txin, input)) in psbt.unsigned_tx.input.iter().zip(psbt.inputs.iter()).enumerate() {
+        let witness_utxo = input.witness_utxo.as_ref();
+
+        match witness_utxo {
+            Some(utxo) => input_amount = input_amount.map(|amount| amount + utxo.value),
+            None => {
+                input_amount = None;
+                warnings.push(format!("Input {} has no witness_utxo, its amount and the fee can't be checked", index));
+            }
+        }
+
+        let sighash_type = input.sighash_type.map(|ty| ty.to_string());
+
+        match input.sighash_type.map(|ty| ty.taproot_hash_ty()) {
+            None | Some(Ok(TapSighashType::Default)) | Some(Ok(TapSighashType::All)) => {},
+            Some(Ok(ty)) => warnings.push(format!("Input {} uses the unusual sighash type {}", index, ty)),
+            Some(Err(_)) => warnings.push(format!("Input {} has an invalid taproot sighash type", index)),
+        }
+
+        let signable = input.tap_internal_key.map_or(false, |key| secret_keys.contains_key(&key) || wallet_keys.contains(&key));
+
+        let signed = input.tap_key_sig.is_some() || !input.tap_script_sigs.is_empty() || input.final_script_witness.is_some();
+
+        inputs.push(json!({
+            "outpoint": txin.previous_output.to_string(),
+            "sequence": txin.sequence.to_consensus_u32(),
+            "amount": witness_utxo.map(|utxo| utxo.value),
+            "address": witness_utxo.and_then(|utxo| Address::from_script(&utxo.script_pubkey, network).ok()).map(|a| a.to_string()),
+            "sighash_type": sighash_type,
+            "signable": signable,
+            "signed": signed,
+        }));
+    }
+
+    let mut outputs = Vec::<serde_json::Value>::new();
+
+    for (index, (txout, output)) in psbt.unsigned_tx.output.iter().zip(psbt.outputs.iter()).enumerate() {
+        let address = Address::from_script(&txout.script_pubkey, network).ok();
+
+        let derived_by_us = !secret_keys_from_origins_map(&output.tap_key_origins, root).is_empty();
+
+        let ours = derived_by_us || address.as_ref().map_or(false, |a| wallet_addresses.contains(a));
+        let change = address.as_ref().map_or(false, |a| change_addresses.contains(a));
+
+        if !ours {
+            warnings.push(format!("Output {} pays {} sats to {}, which is not a wallet address",
+                index,
+                txout.value,
+                address.as_ref().map(|a| a.to_string()).unwrap_or_else(|| txout.script_pubkey.to_hex_string())));
+        }
+
+        outputs.push(json!({
+            "address": address.map(|a| a.to_string()),
+            "script_pubkey": txout.script_pubkey.to_hex_string(),
+            "amount": txout.value,
+            "ours": ours,
+            "change": change,
+        }));
+    }
+
+    let output_amount: u64 = psbt.unsigned_tx.output.iter().map(|o| o.value).sum();
+    let fee = input_amount.and_then(|amount| amount.checked_sub(output_amount));
+
+    if input_amount.is_some() && fee.is_none() {
+        warnings.push("The outputs are worth more than the inputs".to_string());
+    }
+
+    // Assumes every input is a key-path spend.
+    let vsize = wallet::estimate_vsize(psbt.unsigned_tx.input.len(), &psbt.unsigned_tx.output);
+
+    json!({
+        "txid": psbt.unsigned_tx.txid().to_string(),
+        "version": psbt.unsigned_tx.version,
+        "locktime": psbt.unsigned_tx.lock_time.to_consensus_u32(),
+        "inputs": inputs,
+        "outputs": outputs,
+        "fee": fee,
+        "vsize": vsize,
+        "fee_rate": fee.map(|fee| fee as f64 / vsize as f64),
+        "warnings": warnings,
+    })
+}
+
+/// Reads a PSBT from `path` ("-" for stdin), in binary or base64 encoding, or as BC-UR frames.
+pub fn read_psbt(path: &str) -> Result<Psbt, Box<dyn std::error::Error>> {
+    let bytes = if path == "-" {
+        let mut bytes = Vec::new();
+        std::io::stdin().read_to_end(&mut bytes)?;
+        bytes
+    } else {
+        std::fs::read(path)?
+    };
+
+    if bytes.starts_with(b"psbt\xff") {
+        return Ok(Psbt::deserialize(&bytes)?);
+    }
+
+    let text = String::from_utf8(bytes)?;
+
+    if text.trim_start().to_lowercase().starts_with("ur:") {
+        return airgap::psbt_from_ur_frames(text.lines());
+    }
+
+    Ok(Psbt::from_str(text.trim())?)
+}
+
+/// Writes `psbt` to `path`, or prints it to stdout in base64 when there is no path.
+pub fn write_psbt(psbt: &Psbt, path: Option<&str>, binary: bool) -> Result<(), Box<dyn std::error::Error>> {
+    match path {
+        Some(path) if binary => std::fs::write(path, psbt.serialize())?,
+        Some(path) => std::fs::write(path, psbt.to_string())?,
+        None if binary => return Err("Binary PSBTs can only be written to a file".into()),
+        None => println!("{}", psbt),
+    }
+
+    Ok(())
+}
+
+fn sign_psbt_taproot(
+    secret_key: &SecretKey,
+    pubkey: XOnlyPublicKey,
+    leaf_hash: Option<TapLeafHash>,
+    psbt_input: &mut psbt::Input,
+    hash: TapSighash,
+    hash_ty: TapSighashType,
+    secp: &Secp256k1<secp256k1::All>,
+) {
+    let keypair = secp256k1::KeyPair::from_seckey_slice(secp, secret_key.as_ref()).unwrap();
+    let keypair = match leaf_hash {
+        None => keypair.tap_tweak(secp, psbt_input.tap_merkle_root).to_inner(),
+        Some(_) => keypair, // no tweak for script spend
+    };
+
+    let sig = secp.sign_schnorr(&hash.into(), &keypair);
+
+    let final_signature = taproot::Signature { sig, hash_ty };
+
+    if let Some(lh) = leaf_hash {
+        psbt_input.tap_script_sigs.insert((pubkey, lh), final_signature);
+    } else {
+        psbt_input.tap_key_sig = Some(final_signature);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bitcoin::{absolute, OutPoint, ScriptBuf, TxIn, hashes::Hash};
+    use secp256k1::Message;
+
+    fn signed_copy(unsigned_tx: &Transaction, secp: &Secp256k1<secp256k1::All>, keypair: &secp256k1::KeyPair, msg: [u8; 32]) -> Psbt {
+        let mut psbt = Psbt::from_unsigned_tx(unsigned_tx.clone()).unwrap();
+
+        let sig = secp.sign_schnorr(&Message::from_slice(&msg).unwrap(), keypair);
+        psbt.inputs[0].tap_key_sig = Some(taproot::Signature { sig, hash_ty: TapSighashType::Default });
+
+        psbt
+    }
+
+    #[test]
+    fn combine_reports_conflicting_tap_key_sig() {
+        let secp = Secp256k1::new();
+        let keypair = secp256k1::KeyPair::new(&secp, &mut rand::thread_rng());
+
+        let unsigned_tx = Transaction {
+            version: 2,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn { previous_output: OutPoint::new(Hash::all_zeros(), 0), ..Default::default() }],
+            output: vec![TxOut { value: 10_000, script_pubkey: ScriptBuf::new() }],
+        };
+
+        let first = signed_copy(&unsigned_tx, &secp, &keypair, [1u8; 32]);
+        let same = signed_copy(&unsigned_tx, &secp, &keypair, [1u8; 32]);
+        let other = signed_copy(&unsigned_tx, &secp, &keypair, [2u8; 32]);
+
+        let (_, conflicts) = combine_psbts(vec![first.clone(), same]).unwrap();
+        assert!(conflicts.is_empty());
+
+        let (combined, conflicts) = combine_psbts(vec![first.clone(), other]).unwrap();
+        assert_eq!(conflicts, vec!["PSBT 1 input 0: conflicting tap_key_sig".to_string()]);
+        // The first PSBT's signature is kept.
+        assert_eq!(combined.inputs[0].tap_key_sig, first.inputs[0].tap_key_sig);
+    }
+}
@@ -0,0 +1,778 @@
+use std::str::FromStr;
+
+use bitcoin::{Address, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness, absolute, bip32::{ChildNumber, DerivationPath}, hashes::Hash, psbt::Psbt, sighash::{Prevouts, SighashCache}, taproot};
+use secp256k1_zkp::{Secp256k1, SecretKey, PublicKey, XOnlyPublicKey, Message, Scalar};
+use serde::{Serialize, Deserialize};
+use sqlx::{Sqlite, Row};
+
+use crate::{addresses, backend, cosigner, signer, wallet};
+
+/// Path of the owner keys of statecoins, so that a seed backup can sign for them again.
+const OWNER_KEY_PATH: &str = "m/86h/0h/0h/6";
+
+/// Blocks until the first backup transaction of a deposit can be broadcast.
+const INITIAL_LOCKTIME_BLOCKS: u32 = 1000;
+
+/// Each transfer's backup transaction becomes valid this many blocks before the previous
+/// owner's, so the newest owner can always exit first.
+const LOCKTIME_DECREMENT: u32 = 10;
+
+/// Blocks the newest backup transaction must still be ahead of the tip for a transfer to be
+/// accepted, leaving time to withdraw or broadcast it.
+const MIN_LOCKTIME_MARGIN: u32 = 20;
+
+/// Highest fee rate (sat/vB) a received backup transaction may pay out of the statecoin.
+const MAX_BACKUP_FEE_RATE: u64 = 100;
+
+/// A coin held in a key shared with the statechain entity: P = O + S, where the owner key O
+/// changes on every transfer and the entity updates S so that P stays the same.
+pub struct Statecoin {
+    pub statechain_id: String,
+    pub secret_key: SecretKey,
+    pub public_key: PublicKey,
+    pub server_pubkey: PublicKey,
+    pub aggregate_pubkey: PublicKey,
+    pub address: Address,
+    pub outpoint: OutPoint,
+    pub amount: u64,
+    pub status: String,
+}
+
+pub struct BackupTx {
+    pub tx_n: u32,
+    pub tx: Transaction,
+    pub lock_time: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransferSenderRequest {
+    pub statechain_id: String,
+    pub challenge: String,
+    pub auth_sig: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransferSenderResponse {
+    pub x1: String,
+}
+
+/// Signed by the receiver's key, which becomes the owner key the entity authenticates.
+#[derive(Serialize, Deserialize)]
+pub struct TransferReceiverRequest {
+    pub statechain_id: String,
+    pub t2: String,
+    pub receiver_pubkey: String,
+    pub challenge: String,
+    pub auth_sig: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransferReceiverResponse {
+    pub server_pubkey: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct TransferBackupTx {
+    pub tx_n: u32,
+    pub tx: String,
+    pub lock_time: u32,
+}
+
+/// What the sender hands to the receiver. `t1` = o1 + x1 reveals the sender's key to whoever
+/// also learns the entity's x1, so the message has to travel over a private channel.
+#[derive(Serialize, Deserialize)]
+pub struct TransferMessage {
+    pub statechain_id: String,
+    pub t1: String,
+    pub receiver_pubkey: String,
+    pub aggregate_pubkey: String,
+    pub funding_txid: String,
+    pub funding_vout: u32,
+    pub amount: u64,
+    pub backup_txs: Vec<TransferBackupTx>,
+}
+
+async fn get_next_owner_index(pool: &sqlx::Pool<Sqlite>) -> u32 {
+    let query = "SELECT MAX(bip32_index) FROM (SELECT bip32_index FROM statecoins UNION ALL SELECT bip32_index FROM statecoin_transfer_keys)";
+
+    let row = sqlx::query(query)
+        .fetch_one(pool)
+        .await
+        .unwrap();
+
+    match row.get::<Option<u32>, _>(0) {
+        Some(index) => index + 1,
+        None => 0,
+    }
+}
+
+/// Next key of the owner key chain, with its index.
+async fn new_owner_key(pool: &sqlx::Pool<Sqlite>, network: Network) -> (u32, SecretKey, PublicKey) {
+    let secp = Secp256k1::new();
+
+    let index = get_next_owner_index(pool).await;
+
+    let root = addresses::get_root_key(pool, network).await;
+    let derivation_path = DerivationPath::from_str(OWNER_KEY_PATH).unwrap()
+        .child(ChildNumber::from_normal_idx(index).unwrap());
+
+    let secret_key = root.derive_priv(&secp, &derivation_path).unwrap().private_key;
+
+    (index, secret_key, secret_key.public_key(&secp))
+}
+
+/// Builds a transaction spending the statecoin to `to_address` and signs it with the entity.
+async fn create_signed_tx(statecoin: &Statecoin, to_address: &Address, fee_rate: u64, lock_time: absolute::LockTime, sequence: Sequence) -> Result<Transaction, Box<dyn std::error::Error>> {
+    let mut output = vec![TxOut { value: 0, script_pubkey: to_address.script_pubkey() }];
+
+    let fee = wallet::estimate_vsize(1, &output) * fee_rate;
+    output[0].value = statecoin.amount.checked_sub(fee).ok_or("Fees more than the statecoin amount!")?;
+
+    let tx = Transaction {
+        version: 2,
+        lock_time,
+        input: vec![TxIn {
+            previous_output: statecoin.outpoint,
+            script_sig: ScriptBuf::new(),
+            sequence,
+            witness: Witness::default(),
+        }],
+        output,
+    };
+
+    let mut psbt = Psbt::from_unsigned_tx(tx)?;
+    psbt.inputs[0].witness_utxo = Some(TxOut { value: statecoin.amount, script_pubkey: statecoin.address.script_pubkey() });
+    psbt.inputs[0].tap_internal_key = Some(statecoin.aggregate_pubkey.x_only_public_key().0);
+
+    let (hash, hash_ty) = signer::key_spend_sighash(&psbt, 0)?;
+    let msg = Message::from_slice(hash.as_byte_array())?;
+
+    let sig = cosigner::sign_with_server(&statecoin.statechain_id, &statecoin.secret_key, &statecoin.server_pubkey, msg).await?;
+    psbt.inputs[0].tap_key_sig = Some(taproot::Signature { sig, hash_ty });
+
+    signer::finalize_psbt(&mut psbt)?;
+    signer::extract_tx(psbt)
+}
+
+/// Backup transaction paying the statecoin to `backup_address` once the chain reaches `lock_time`.
+async fn create_backup_tx(statecoin: &Statecoin, backup_address: &Address, fee_rate: u64, lock_time: u32) -> Result<Transaction, Box<dyn std::error::Error>> {
+    let lock_time = absolute::LockTime::from_height(lock_time)?;
+
+    create_signed_tx(statecoin, backup_address, fee_rate, lock_time, Sequence::ENABLE_LOCKTIME_NO_RBF).await
+}
+
+/// Checks the key-path signature of the single input of `tx`, which spends `prevout`.
+fn verify_key_spend(tx: &Transaction, prevout: &TxOut) -> Result<(), Box<dyn std::error::Error>> {
+    let secp = Secp256k1::verification_only();
+
+    let signature = tx.input[0].witness.nth(0).ok_or("The transaction is not signed")?;
+    let signature = taproot::Signature::from_slice(signature)?;
+
+    let hash = SighashCache::new(tx).taproot_key_spend_signature_hash(0, &Prevouts::All(&[prevout.clone()]), signature.hash_ty)?;
+    let msg = Message::from_slice(hash.as_byte_array())?;
+
+    if !prevout.script_pubkey.is_v1_p2tr() {
+        return Err("The spent output is not P2TR".into());
+    }
+    let output_key = XOnlyPublicKey::from_slice(&prevout.script_pubkey.as_bytes()[2..34])?;
+
+    secp.verify_schnorr(&signature.sig, &msg, &output_key)?;
+
+    Ok(())
+}
+
+/// Gets a key from the entity, funds the shared address from the wallet with `amount`, and gets
+/// the backup transaction signed before the funding transaction is broadcast, so the coins can
+/// always be recovered without the entity. Returns the statecoin and its funding transaction.
+pub async fn deposit(pool: &sqlx::Pool<Sqlite>, client: &electrum_client::Client, network: Network, amount: u64, fees: u64, backup_fee_rate: u64, options: &wallet::TxOptions) -> Result<(Statecoin, wallet::WalletTx), Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let (bip32_index, secret_key, public_key) = new_owner_key(pool, network).await;
+
+    let (statechain_id, server_pubkey) = cosigner::new_key(&public_key).await?;
+
+    let aggregate_pubkey = cosigner::aggregate_pubkey(&public_key, &server_pubkey)?;
+    let address = Address::p2tr(&secp, aggregate_pubkey.x_only_public_key().0, None, network);
+
+    let recipients = vec![wallet::Recipient { address: address.clone(), amount, subtract_fee: false }];
+    let funding_tx = wallet::create_payment_tx(pool, client, network, &recipients, fees, options).await?;
+
+    let vout = funding_tx.tx.output
+        .iter()
+        .position(|output| output.script_pubkey == address.script_pubkey())
+        .ok_or("The funding transaction doesn't pay the shared address")?;
+
+    let statecoin = Statecoin {
+        statechain_id,
+        secret_key,
+        public_key,
+        server_pubkey,
+        aggregate_pubkey,
+        address,
+        outpoint: OutPoint { txid: funding_tx.tx.txid(), vout: vout as u32 },
+        amount,
+        status: "active".to_string(),
+    };
+
+    let (_, backup_address, _) = addresses::generate_new_key(pool, network, false).await;
+    let lock_time = backend::get_tip_height(client) + INITIAL_LOCKTIME_BLOCKS;
+
+    let backup_tx = create_backup_tx(&statecoin, &backup_address, backup_fee_rate, lock_time).await?;
+
+    insert_statecoin(pool, &statecoin, Some(bip32_index)).await;
+    insert_backup_tx(pool, &statecoin.statechain_id, &BackupTx { tx_n: 0, tx: backup_tx, lock_time }).await;
+
+    Ok((statecoin, funding_tx))
+}
+
+/// New owner key for receiving a statecoin, and the wallet address its backup transaction
+/// should pay. Both go to the sender.
+pub async fn new_transfer_address(pool: &sqlx::Pool<Sqlite>, network: Network) -> (PublicKey, Address) {
+    let (bip32_index, secret_key, public_key) = new_owner_key(pool, network).await;
+
+    let (_, backup_address, _) = addresses::generate_new_key(pool, network, false).await;
+
+    let query = "INSERT INTO statecoin_transfer_keys (bip32_index, client_seckey, client_pubkey, backup_address) VALUES ($1, $2, $3, $4)";
+
+    let _ = sqlx::query(query)
+        .bind(bip32_index)
+        .bind(&secret_key.secret_bytes().to_vec())
+        .bind(&public_key.serialize().to_vec())
+        .bind(&backup_address.to_string())
+        .execute(pool)
+        .await
+        .unwrap();
+
+    (public_key, backup_address)
+}
+
+/// Sends the statecoin to the owner of `receiver_pubkey`: signs a new backup transaction paying
+/// `backup_address` with an earlier locktime, and starts the key update with the entity.
+/// The returned message completes the transfer on the receiver's side.
+pub async fn transfer(pool: &sqlx::Pool<Sqlite>, client: &electrum_client::Client, network: Network, statechain_id: &str, receiver_pubkey: &str, backup_address: &Address, backup_fee_rate: u64) -> Result<TransferMessage, Box<dyn std::error::Error>> {
+    let statecoin = get_statecoin(pool, network, statechain_id).await.ok_or("Unknown statecoin")?;
+
+    if statecoin.status != "active" {
+        return Err(format!("The statecoin is {}", statecoin.status).into());
+    }
+
+    let receiver_pubkey = PublicKey::from_str(receiver_pubkey)?;
+
+    let mut backup_txs = get_backup_txs(pool, statechain_id).await;
+    let last = backup_txs.last().ok_or("The statecoin has no backup transaction")?;
+
+    let lock_time = last.lock_time.saturating_sub(LOCKTIME_DECREMENT);
+    let tx_n = last.tx_n + 1;
+
+    if lock_time < backend::get_tip_height(client) + MIN_LOCKTIME_MARGIN {
+        return Err("The backup locktime would expire too soon, withdraw the statecoin instead".into());
+    }
+
+    let backup_tx = create_backup_tx(&statecoin, backup_address, backup_fee_rate, lock_time).await?;
+
+    let (challenge, auth_sig) = cosigner::auth_sig("transfer/sender", statechain_id, &[], &statecoin.secret_key).await?;
+
+    let request = TransferSenderRequest {
+        statechain_id: statechain_id.to_string(),
+        challenge,
+        auth_sig,
+    };
+    let response: TransferSenderResponse = cosigner::post("transfer/sender", &request).await?;
+
+    let x1 = SecretKey::from_slice(&hex::decode(&response.x1)?)?;
+    let t1 = statecoin.secret_key.add_tweak(&Scalar::from(x1))?;
+
+    let backup_tx = BackupTx { tx_n, tx: backup_tx, lock_time };
+    insert_backup_tx(pool, statechain_id, &backup_tx).await;
+    backup_txs.push(backup_tx);
+
+    update_statecoin_status(pool, statechain_id, "transferred").await;
+
+    Ok(TransferMessage {
+        statechain_id: statechain_id.to_string(),
+        t1: hex::encode(t1.secret_bytes()),
+        receiver_pubkey: receiver_pubkey.to_string(),
+        aggregate_pubkey: statecoin.aggregate_pubkey.to_string(),
+        funding_txid: statecoin.outpoint.txid.to_string(),
+        funding_vout: statecoin.outpoint.vout,
+        amount: statecoin.amount,
+        backup_txs: backup_txs
+            .iter()
+            .map(|backup_tx| TransferBackupTx {
+                tx_n: backup_tx.tx_n,
+                tx: bitcoin::consensus::encode::serialize_hex(&backup_tx.tx),
+                lock_time: backup_tx.lock_time,
+            })
+            .collect(),
+    })
+}
+
+/// Checks the backup transactions of a transfer to us: each one spends `outpoint` alone, is
+/// signed for the shared key and has an earlier locktime than the one before, and the newest one
+/// pays the statecoin, less a fee of at most `MAX_BACKUP_FEE_RATE`, to `backup_address` alone,
+/// at least `MIN_LOCKTIME_MARGIN` blocks after `tip_height`.
+fn check_backup_txs(message: &TransferMessage, outpoint: OutPoint, backup_address: &Address, tip_height: u32) -> Result<Vec<BackupTx>, Box<dyn std::error::Error>> {
+    let aggregate_pubkey = PublicKey::from_str(&message.aggregate_pubkey)?;
+    let script_pubkey = ScriptBuf::new_v1_p2tr(&Secp256k1::verification_only(), aggregate_pubkey.x_only_public_key().0, None);
+
+    let prevout = TxOut { value: message.amount, script_pubkey };
+
+    let mut backup_txs = Vec::<BackupTx>::new();
+
+    for backup_tx in &message.backup_txs {
+        let tx: Transaction = bitcoin::consensus::encode::deserialize(&hex::decode(&backup_tx.tx)?)?;
+
+        if tx.input.len() != 1 || tx.input[0].previous_output != outpoint {
+            return Err(format!("Backup transaction {} doesn't spend the statecoin", backup_tx.tx_n).into());
+        }
+
+        if tx.lock_time != absolute::LockTime::from_height(backup_tx.lock_time)? {
+            return Err(format!("Backup transaction {} has a different locktime", backup_tx.tx_n).into());
+        }
+
+        if let Some(previous) = backup_txs.last() {
+            if backup_tx.lock_time >= previous.lock_time {
+                return Err(format!("Backup transaction {} doesn't have an earlier locktime", backup_tx.tx_n).into());
+            }
+        }
+
+        verify_key_spend(&tx, &prevout).map_err(|e| format!("Backup transaction {}: {}", backup_tx.tx_n, e))?;
+
+        backup_txs.push(BackupTx { tx_n: backup_tx.tx_n, tx, lock_time: backup_tx.lock_time });
+    }
+
+    let latest = backup_txs.last().ok_or("The transfer has no backup transaction")?;
+
+    match latest.tx.output.as_slice() {
+        [output] if output.script_pubkey == backup_address.script_pubkey() => {
+            let max_fee = wallet::estimate_vsize(1, &latest.tx.output) * MAX_BACKUP_FEE_RATE;
+
+            if output.value < message.amount.saturating_sub(max_fee) {
+                return Err(format!("The newest backup transaction pays {} of the {} statecoin in fees", message.amount - output.value, message.amount).into());
+            }
+        },
+        _ => return Err("The newest backup transaction doesn't pay only our backup address".into()),
+    }
+
+    if latest.lock_time < tip_height + MIN_LOCKTIME_MARGIN {
+        return Err("The newest backup transaction's locktime expires too soon".into());
+    }
+
+    Ok(backup_txs)
+}
+
+/// Completes a transfer: checks the coin is unspent and the backup transactions, see
+/// `check_backup_txs`, then finishes the key update with the entity and checks the shared key
+/// is still the same.
+pub async fn receive(pool: &sqlx::Pool<Sqlite>, client: &electrum_client::Client, network: Network, message: &TransferMessage) -> Result<Statecoin, Box<dyn std::error::Error>> {
+    let secp = Secp256k1::new();
+
+    let receiver_pubkey = PublicKey::from_str(&message.receiver_pubkey)?;
+
+    let row = sqlx::query("SELECT bip32_index, client_seckey, backup_address FROM statecoin_transfer_keys WHERE client_pubkey = $1")
+        .bind(&receiver_pubkey.serialize().to_vec())
+        .fetch_optional(pool)
+        .await
+        .unwrap()
+        .ok_or("The transfer is not addressed to a key of this wallet")?;
+
+    let bip32_index = row.get::<Option<u32>, _>("bip32_index");
+    let secret_key = SecretKey::from_slice(&row.get::<Vec<u8>, _>("client_seckey"))?;
+    let backup_address = Address::from_str(&row.get::<String, _>("backup_address"))?.require_network(network)?;
+
+    let aggregate_pubkey = PublicKey::from_str(&message.aggregate_pubkey)?;
+    let address = Address::p2tr(&secp, aggregate_pubkey.x_only_public_key().0, None, network);
+
+    let outpoint = OutPoint { txid: Txid::from_str(&message.funding_txid)?, vout: message.funding_vout };
+
+    let unspent = backend::get_script_list_unspent(client, &address)
+        .iter()
+        .any(|utxo| utxo.tx_hash == outpoint.txid && utxo.tx_pos as u32 == outpoint.vout && utxo.value == message.amount);
+
+    if !unspent {
+        return Err("The statecoin is not unspent on chain".into());
+    }
+
+    let backup_txs = check_backup_txs(message, outpoint, &backup_address, backend::get_tip_height(client))?;
+
+    // t2 = t1 - o2, and the entity sets s2 = s1 + t2 - x1 = s1 + o1 - o2.
+    let t1 = SecretKey::from_slice(&hex::decode(&message.t1)?)?;
+    let t2 = t1.add_tweak(&Scalar::from(secret_key.negate()))?;
+
+    let t2 = hex::encode(t2.secret_bytes());
+    let receiver_pubkey_hex = receiver_pubkey.to_string();
+
+    let (challenge, auth_sig) = cosigner::auth_sig("transfer/receiver", &message.statechain_id, &[t2.as_str(), receiver_pubkey_hex.as_str()], &secret_key).await?;
+
+    let request = TransferReceiverRequest {
+        statechain_id: message.statechain_id.clone(),
+        t2,
+        receiver_pubkey: receiver_pubkey_hex,
+        challenge,
+        auth_sig,
+    };
+    let response: TransferReceiverResponse = cosigner::post("transfer/receiver", &request).await?;
+
+    let server_pubkey = PublicKey::from_str(&response.server_pubkey)?;
+
+    if cosigner::aggregate_pubkey(&receiver_pubkey, &server_pubkey)? != aggregate_pubkey {
+        return Err("The key update doesn't give back the shared key".into());
+    }
+
+    let statecoin = Statecoin {
+        statechain_id: message.statechain_id.clone(),
+        secret_key,
+        public_key: receiver_pubkey,
+        server_pubkey,
+        aggregate_pubkey,
+        address,
+        outpoint,
+        amount: message.amount,
+        status: "active".to_string(),
+    };
+
+    insert_statecoin(pool, &statecoin, bip32_index).await;
+
+    for backup_tx in &backup_txs {
+        insert_backup_tx(pool, &statecoin.statechain_id, backup_tx).await;
+    }
+
+    Ok(statecoin)
+}
+
+/// Spends the statecoin to `to_address` with the entity's cooperation, without waiting for the
+/// backup transaction's locktime. The statecoin stays active until `set_withdrawn` is called
+/// once the transaction is broadcast.
+pub async fn withdraw(pool: &sqlx::Pool<Sqlite>, network: Network, statechain_id: &str, to_address: &Address, fee_rate: u64) -> Result<Transaction, Box<dyn std::error::Error>> {
+    let statecoin = get_statecoin(pool, network, statechain_id).await.ok_or("Unknown statecoin")?;
+
+    if statecoin.status != "active" {
+        return Err(format!("The statecoin is {}", statecoin.status).into());
+    }
+
+    create_signed_tx(&statecoin, to_address, fee_rate, absolute::LockTime::ZERO, Sequence::ENABLE_RBF_NO_LOCKTIME).await
+}
+
+/// Marks the statecoin withdrawn, once the withdrawal transaction has been broadcast.
+pub async fn set_withdrawn(pool: &sqlx::Pool<Sqlite>, statechain_id: &str) {
+    update_statecoin_status(pool, statechain_id, "withdrawn").await;
+}
+
+/// What `check_backups` found for a statecoin.
+pub struct BackupCheck {
+    pub statechain_id: String,
+    pub tx_n: u32,
+    pub txid: Txid,
+    pub lock_time: u32,
+    /// "waiting", "broadcast", "exited" (our backup is on chain), "spent" (spent by another
+    /// transaction) or the broadcast error.
+    pub status: String,
+}
+
+/// Looks at every statecoin we own and broadcasts its newest backup transaction once its
+/// locktime has matured, unless the coin has already been spent. Coins whose backup made it
+/// on chain are marked "exited", and those spent otherwise "spent".
+pub async fn check_backups(pool: &sqlx::Pool<Sqlite>, client: &electrum_client::Client, network: Network) -> Vec<BackupCheck> {
+    let tip_height = backend::get_tip_height(client);
+
+    let mut checks = Vec::<BackupCheck>::new();
+
+    for statecoin in get_statecoins(pool, network).await {
+        if statecoin.status != "active" {
+            continue;
+        }
+
+        let backup_tx = match get_backup_txs(pool, &statecoin.statechain_id).await.pop() {
+            Some(backup_tx) => backup_tx,
+            None => continue,
+        };
+
+        let txid = backup_tx.tx.txid();
+
+        let unspent = backend::get_script_list_unspent(client, &statecoin.address)
+            .iter()
+            .any(|utxo| utxo.tx_hash == statecoin.outpoint.txid && utxo.tx_pos as u32 == statecoin.outpoint.vout);
+
+        let status = if !unspent {
+            let exited = backend::get_address_history(client, &statecoin.address)
+                .iter()
+                .any(|history| history.tx_hash == txid);
+
+            let status = if exited { "exited" } else { "spent" };
+            update_statecoin_status(pool, &statecoin.statechain_id, status).await;
+            status.to_string()
+        } else if tip_height < backup_tx.lock_time {
+            // A transaction is final in the next block once its locktime is below that height.
+            "waiting".to_string()
+        } else {
+            let tx_bytes = bitcoin::consensus::encode::serialize(&backup_tx.tx);
+
+            match backend::try_transaction_broadcast_raw(client, &tx_bytes) {
+                Ok(_) => "broadcast".to_string(),
+                Err(e) => format!("broadcast failed: {}", e),
+            }
+        };
+
+        checks.push(BackupCheck {
+            statechain_id: statecoin.statechain_id,
+            tx_n: backup_tx.tx_n,
+            txid,
+            lock_time: backup_tx.lock_time,
+            status,
+        });
+    }
+
+    checks
+}
+
+async fn insert_statecoin(pool: &sqlx::Pool<Sqlite>, statecoin: &Statecoin, bip32_index: Option<u32>) {
+    let query = "INSERT INTO statecoins (statechain_id, bip32_index, client_seckey, client_pubkey, server_pubkey, aggregate_pubkey, p2tr_address, funding_txid, funding_vout, amount, status) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)";
+
+    let _ = sqlx::query(query)
+        .bind(&statecoin.statechain_id)
+        .bind(bip32_index)
+        .bind(&statecoin.secret_key.secret_bytes().to_vec())
+        .bind(&statecoin.public_key.serialize().to_vec())
+        .bind(&statecoin.server_pubkey.serialize().to_vec())
+        .bind(&statecoin.aggregate_pubkey.serialize().to_vec())
+        .bind(&statecoin.address.to_string())
+        .bind(&statecoin.outpoint.txid.to_string())
+        .bind(statecoin.outpoint.vout)
+        .bind(statecoin.amount as i64)
+        .bind(&statecoin.status)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+async fn update_statecoin_status(pool: &sqlx::Pool<Sqlite>, statechain_id: &str, status: &str) {
+    let query = "UPDATE statecoins SET status = $1 WHERE statechain_id = $2 AND status = 'active'";
+
+    let _ = sqlx::query(query)
+        .bind(status)
+        .bind(statechain_id)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+pub async fn get_statecoins(pool: &sqlx::Pool<Sqlite>, network: Network) -> Vec::<Statecoin> {
+    let query = "SELECT statechain_id, client_seckey, client_pubkey, server_pubkey, aggregate_pubkey, p2tr_address, funding_txid, funding_vout, amount, status FROM statecoins";
+
+    let rows = sqlx::query(query)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+
+    let mut statecoins = Vec::<Statecoin>::new();
+
+    for row in rows {
+        let p2tr_address = row.get::<String, _>("p2tr_address");
+        let address = Address::from_str(&p2tr_address).unwrap().require_network(network).unwrap();
+
+        let funding_txid = Txid::from_str(&row.get::<String, _>("funding_txid")).unwrap();
+
+        statecoins.push(Statecoin {
+            statechain_id: row.get::<String, _>("statechain_id"),
+            secret_key: SecretKey::from_slice(&row.get::<Vec<u8>, _>("client_seckey")).unwrap(),
+            public_key: PublicKey::from_slice(&row.get::<Vec<u8>, _>("client_pubkey")).unwrap(),
+            server_pubkey: PublicKey::from_slice(&row.get::<Vec<u8>, _>("server_pubkey")).unwrap(),
+            aggregate_pubkey: PublicKey::from_slice(&row.get::<Vec<u8>, _>("aggregate_pubkey")).unwrap(),
+            address,
+            outpoint: OutPoint { txid: funding_txid, vout: row.get::<u32, _>("funding_vout") },
+            amount: row.get::<i64, _>("amount") as u64,
+            status: row.get::<String, _>("status"),
+        });
+    }
+
+    statecoins
+}
+
+/// The statecoin we currently own or last owned with this id (a coin can come back to us).
+pub async fn get_statecoin(pool: &sqlx::Pool<Sqlite>, network: Network, statechain_id: &str) -> Option<Statecoin> {
+    let mut statecoins: Vec<_> = get_statecoins(pool, network).await
+        .into_iter()
+        .filter(|statecoin| statecoin.statechain_id == statechain_id)
+        .collect();
+
+    let active = statecoins.iter().position(|statecoin| statecoin.status == "active");
+
+    match active {
+        Some(index) => Some(statecoins.swap_remove(index)),
+        None => statecoins.pop(),
+    }
+}
+
+async fn insert_backup_tx(pool: &sqlx::Pool<Sqlite>, statechain_id: &str, backup_tx: &BackupTx) {
+    let query = "INSERT INTO statecoin_backup_txs (statechain_id, tx_n, tx, lock_time) VALUES ($1, $2, $3, $4)";
+
+    let _ = sqlx::query(query)
+        .bind(statechain_id)
+        .bind(backup_tx.tx_n)
+        .bind(bitcoin::consensus::encode::serialize(&backup_tx.tx))
+        .bind(backup_tx.lock_time)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+/// Backup transactions of the statecoin in signing order, i.e. from the latest locktime to the
+/// earliest.
+pub async fn get_backup_txs(pool: &sqlx::Pool<Sqlite>, statechain_id: &str) -> Vec::<BackupTx> {
+    let query = "SELECT DISTINCT tx_n, tx, lock_time FROM statecoin_backup_txs WHERE statechain_id = $1 ORDER BY tx_n";
+
+    let rows = sqlx::query(query)
+        .bind(statechain_id)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+
+    rows.iter()
+        .map(|row| BackupTx {
+            tx_n: row.get::<u32, _>("tx_n"),
+            tx: bitcoin::consensus::encode::deserialize(&row.get::<Vec<u8>, _>("tx")).unwrap(),
+            lock_time: row.get::<u32, _>("lock_time"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    const AMOUNT: u64 = 100_000;
+    const TIP_HEIGHT: u32 = 900;
+
+    fn regtest_address() -> Address {
+        let secp = Secp256k1::new();
+        let (_, public_key) = secp.generate_keypair(&mut rand::thread_rng());
+
+        Address::p2tr(&secp, public_key.x_only_public_key().0, None, Network::Regtest)
+    }
+
+    /// Backup transaction spending `outpoint`, key-path signed by `secret_key`, which stands in
+    /// for the shared key.
+    fn backup_tx(secret_key: &SecretKey, outpoint: OutPoint, tx_n: u32, lock_time: u32, output: Vec<TxOut>) -> TransferBackupTx {
+        let secp = Secp256k1::new();
+        let internal_key = secret_key.x_only_public_key(&secp).0;
+
+        let tx = Transaction {
+            version: 2,
+            lock_time: absolute::LockTime::from_height(lock_time).unwrap(),
+            input: vec![TxIn {
+                previous_output: outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_LOCKTIME_NO_RBF,
+                witness: Witness::default(),
+            }],
+            output,
+        };
+
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut { value: AMOUNT, script_pubkey: ScriptBuf::new_v1_p2tr(&secp, internal_key, None) });
+        psbt.inputs[0].tap_internal_key = Some(internal_key);
+
+        signer::sign_psbt(&mut psbt, &HashMap::from([(internal_key, *secret_key)])).unwrap();
+        signer::finalize_psbt(&mut psbt).unwrap();
+        let tx = signer::extract_tx(psbt).unwrap();
+
+        TransferBackupTx { tx_n, tx: bitcoin::consensus::encode::serialize_hex(&tx), lock_time }
+    }
+
+    fn transfer_message(secret_key: &SecretKey, outpoint: OutPoint, backup_txs: Vec<TransferBackupTx>) -> TransferMessage {
+        let secp = Secp256k1::new();
+
+        TransferMessage {
+            statechain_id: "statechain".to_string(),
+            t1: String::new(),
+            receiver_pubkey: String::new(),
+            aggregate_pubkey: secret_key.public_key(&secp).to_string(),
+            funding_txid: outpoint.txid.to_string(),
+            funding_vout: outpoint.vout,
+            amount: AMOUNT,
+            backup_txs,
+        }
+    }
+
+    fn check_error(message: &TransferMessage, outpoint: OutPoint, backup_address: &Address) -> String {
+        check_backup_txs(message, outpoint, backup_address, TIP_HEIGHT).err().unwrap().to_string()
+    }
+
+    #[test]
+    fn backup_txs_of_a_transfer_are_checked() {
+        let secret_key = SecretKey::new(&mut rand::thread_rng());
+        let outpoint = OutPoint { txid: Txid::from_byte_array([1u8; 32]), vout: 0 };
+
+        let (previous_address, backup_address) = (regtest_address(), regtest_address());
+        let pay = |address: &Address, value: u64| vec![TxOut { value, script_pubkey: address.script_pubkey() }];
+
+        let first = || backup_tx(&secret_key, outpoint, 0, 1000, pay(&previous_address, AMOUNT - 1000));
+
+        let message = transfer_message(&secret_key, outpoint, vec![first(), backup_tx(&secret_key, outpoint, 1, 990, pay(&backup_address, AMOUNT - 1000))]);
+        let backup_txs = check_backup_txs(&message, outpoint, &backup_address, TIP_HEIGHT).unwrap();
+        assert_eq!(backup_txs.iter().map(|backup_tx| backup_tx.lock_time).collect::<Vec<_>>(), vec![1000, 990]);
+
+        // A backup transaction spending another coin.
+        let other_outpoint = OutPoint { txid: Txid::from_byte_array([2u8; 32]), vout: 0 };
+        let message = transfer_message(&secret_key, outpoint, vec![first(), backup_tx(&secret_key, other_outpoint, 1, 990, pay(&backup_address, AMOUNT - 1000))]);
+        assert_eq!(check_error(&message, outpoint, &backup_address), "Backup transaction 1 doesn't spend the statecoin");
+
+        // The newest locktime must be earlier, so that the receiver can exit first.
+        let message = transfer_message(&secret_key, outpoint, vec![first(), backup_tx(&secret_key, outpoint, 1, 1000, pay(&backup_address, AMOUNT - 1000))]);
+        assert_eq!(check_error(&message, outpoint, &backup_address), "Backup transaction 1 doesn't have an earlier locktime");
+
+        // The locktime of the message must be the one of the transaction.
+        let mut mislabeled = backup_tx(&secret_key, outpoint, 1, 1005, pay(&backup_address, AMOUNT - 1000));
+        mislabeled.lock_time = 990;
+        let message = transfer_message(&secret_key, outpoint, vec![first(), mislabeled]);
+        assert_eq!(check_error(&message, outpoint, &backup_address), "Backup transaction 1 has a different locktime");
+
+        // Signed with a key other than the shared key.
+        let other_key = SecretKey::new(&mut rand::thread_rng());
+        let message = transfer_message(&secret_key, outpoint, vec![first(), backup_tx(&other_key, outpoint, 1, 990, pay(&backup_address, AMOUNT - 1000))]);
+        assert!(check_error(&message, outpoint, &backup_address).starts_with("Backup transaction 1: "));
+
+        // Most of the coin left to the miners.
+        let message = transfer_message(&secret_key, outpoint, vec![first(), backup_tx(&secret_key, outpoint, 1, 990, pay(&backup_address, AMOUNT - 50_000))]);
+        assert_eq!(check_error(&message, outpoint, &backup_address), "The newest backup transaction pays 50000 of the 100000 statecoin in fees");
+
+        // Paying another address, or another address too.
+        let message = transfer_message(&secret_key, outpoint, vec![first(), backup_tx(&secret_key, outpoint, 1, 990, pay(&previous_address, AMOUNT - 1000))]);
+        assert_eq!(check_error(&message, outpoint, &backup_address), "The newest backup transaction doesn't pay only our backup address");
+
+        let mut output = pay(&backup_address, AMOUNT - 11_000);
+        output.extend(pay(&previous_address, 10_000));
+        let message = transfer_message(&secret_key, outpoint, vec![first(), backup_tx(&secret_key, outpoint, 1, 990, output)]);
+        assert_eq!(check_error(&message, outpoint, &backup_address), "The newest backup transaction doesn't pay only our backup address");
+
+        // A locktime too close to the tip.
+        let message = transfer_message(&secret_key, outpoint, vec![first(), backup_tx(&secret_key, outpoint, 1, TIP_HEIGHT + MIN_LOCKTIME_MARGIN - 1, pay(&backup_address, AMOUNT - 1000))]);
+        assert_eq!(check_error(&message, outpoint, &backup_address), "The newest backup transaction's locktime expires too soon");
+
+        let message = transfer_message(&secret_key, outpoint, vec![]);
+        assert_eq!(check_error(&message, outpoint, &backup_address), "The transfer has no backup transaction");
+    }
+
+    /// The key update of a transfer, with the entity's side as `transfer_receiver` does it:
+    /// the shared key P = O + S must stay the same.
+    #[test]
+    fn transfer_keeps_the_shared_key() {
+        let secp = Secp256k1::new();
+
+        let (o1, owner1) = secp.generate_keypair(&mut rand::thread_rng());
+        let (s1, server1) = secp.generate_keypair(&mut rand::thread_rng());
+        let (o2, owner2) = secp.generate_keypair(&mut rand::thread_rng());
+        let x1 = SecretKey::new(&mut rand::thread_rng());
+
+        let t1 = o1.add_tweak(&Scalar::from(x1)).unwrap();
+        let t2 = t1.add_tweak(&Scalar::from(o2.negate())).unwrap();
+        let s2 = s1.add_tweak(&Scalar::from(t2)).unwrap().add_tweak(&Scalar::from(x1.negate())).unwrap();
+
+        let server2 = s2.public_key(&secp);
+
+        assert_eq!(cosigner::aggregate_pubkey(&owner1, &server1).unwrap(), cosigner::aggregate_pubkey(&owner2, &server2).unwrap());
+        assert_ne!(server1, server2);
+    }
+}
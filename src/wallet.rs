@@ -1,9 +1,13 @@
 use std::{str::FromStr, collections::{BTreeMap, HashMap}};
 
-use bitcoin::{Address, Network, Transaction, absolute, TxIn, OutPoint, ScriptBuf, Witness, psbt::{Psbt, Input, PsbtSighashType, self}, TxOut, bip32::{Fingerprint, DerivationPath}, Amount, sighash::{TapSighashType, SighashCache, self, TapSighash}, taproot::{TapLeafHash, self}, secp256k1, key::TapTweak};
-use secp256k1_zkp::{Secp256k1, XOnlyPublicKey, PublicKey, SecretKey};
+use bitcoin::{Address, Network, Transaction, Txid, absolute, TxIn, OutPoint, ScriptBuf, Witness, psbt::{Psbt, Input, PsbtSighashType}, TxOut, bip32::{ChildNumber, Fingerprint, DerivationPath}, Amount, key::TweakedPublicKey};
+use secp256k1_zkp::{XOnlyPublicKey, PublicKey, SecretKey};
+use rand::{Rng, seq::SliceRandom};
+use serde::Deserialize;
 use sqlx::{Sqlite, Row};
 
+use crate::{addresses, backend, scripts, signer};
+
 pub async fn get_all_addresses(pool: &sqlx::Pool<Sqlite>, network: Network) -> Vec::<Address>{
     let query = "SELECT p2tr_address FROM signer_data";
 
@@ -53,6 +57,33 @@ pub async fn get_all_addresses_info(pool: &sqlx::Pool<Sqlite>, network: Network)
     addresses
 }
 
+/// Secret keys of every wallet address, by x-only public key.
+pub async fn get_wallet_secret_keys(pool: &sqlx::Pool<Sqlite>, network: Network) -> HashMap<XOnlyPublicKey, SecretKey> {
+    get_all_addresses_info(pool, network).await
+        .into_iter()
+        .map(|address| (address.3, address.4))
+        .collect()
+}
+
+pub async fn get_change_addresses(pool: &sqlx::Pool<Sqlite>, network: Network) -> Vec::<Address>{
+    let query = "SELECT p2tr_address FROM signer_data WHERE is_change = 1";
+
+    let rows = sqlx::query(query)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+
+    let mut addresses = Vec::<Address>::new();
+
+    for row in rows {
+        let p2tr_address = row.get::<String, _>("p2tr_address");
+        let address = Address::from_str(&p2tr_address).unwrap().require_network(network).unwrap();
+        addresses.push(address);
+    }
+
+    addresses
+}
+
 pub struct AddressInfo {
     pub address: Address,
     pub secret_key: SecretKey,
@@ -69,53 +100,578 @@ pub struct AddressInfo {
     pub value: u64,
 }
 
-pub fn generate_p2tr_key_spend_tx(inputs_info: &Vec::<AddressInfo>, outputs: &Vec<TxOut>) -> Result<Transaction, Box<dyn std::error::Error>> {
-    let secp = Secp256k1::new();
+pub async fn get_list_unspent(pool: &sqlx::Pool<Sqlite>, client: &electrum_client::Client, network: Network) -> Vec::<AddressInfo> {
+    let addresses = get_all_addresses_info(pool, network).await;
+
+    let mut list_unspent = Vec::<AddressInfo>::new();
+
+    for address in addresses {
+        let address_utxos = backend::get_script_list_unspent(client, &address.0);
+
+        for utxo in address_utxos {
+            list_unspent.push(AddressInfo {
+                address: address.0.clone(),
+                secret_key: address.4.clone(),
+                xonly_public_key: address.3.clone(),
+                fingerprint: address.1.clone(),
+                derivation_path: address.2.clone(),
+                height: utxo.height,
+                tx_hash: utxo.tx_hash,
+                tx_pos: utxo.tx_pos,
+                value: utxo.value,
+            });
+        }
+    }
 
-    let mut tx_inputs = Vec::<bitcoin::TxIn>::new();
+    list_unspent
+}
 
-    let mut secret_keys = HashMap::new();
+/// Picks unspent outputs in ascending value order until they cover `target`.
+/// Returns `None` if the whole list is not enough.
+pub fn select_coins(mut list_unspent: Vec::<AddressInfo>, target: u64) -> Option<Vec::<AddressInfo>> {
+    list_unspent.sort_by(|a, b| a.value.cmp(&b.value));
 
-    for input in inputs_info {
-        secret_keys.insert(input.xonly_public_key, input.secret_key);
+    let mut previous_outputs = Vec::<AddressInfo>::new();
+    let mut input_amount: u64 = 0;
+
+    for utxo in list_unspent {
+        if input_amount >= target {
+            break;
+        }
+        input_amount += utxo.value;
+        previous_outputs.push(utxo);
+    }
+
+    if input_amount < target {
+        return None;
+    }
+
+    Some(previous_outputs)
+}
+
+pub struct Recipient {
+    pub address: Address,
+    pub amount: u64,
+    /// Whether this recipient pays (a share of) the transaction fee.
+    pub subtract_fee: bool,
+}
+
+/// Parses a recipient given as `address:amount` on the command line.
+pub fn parse_recipient(recipient: &str, network: Network) -> Result<Recipient, Box<dyn std::error::Error>> {
+    let (address, amount) = recipient
+        .rsplit_once(':')
+        .ok_or_else(|| format!("Invalid recipient '{}', expected address:amount", recipient))?;
+
+    let address = Address::from_str(address.trim())?.require_network(network)?;
+    let amount = amount.trim().parse::<u64>()?;
+
+    Ok(Recipient { address, amount, subtract_fee: false })
+}
+
+/// Reads recipients from a file. JSON files hold an array of
+/// `{"address": ..., "amount": ..., "subtract_fee": ...}` objects (`subtract_fee` is optional),
+/// anything else is read as CSV with one `address,amount[,subtract_fee]` entry per line and an
+/// optional header on the first line.
+pub fn parse_recipients_file(path: &str, network: Network) -> Result<Vec::<Recipient>, Box<dyn std::error::Error>> {
+    let content = std::fs::read_to_string(path)?;
+
+    parse_recipients(&content, network)
+}
+
+/// Parses the content of a recipients file, see `parse_recipients_file`.
+fn parse_recipients(content: &str, network: Network) -> Result<Vec::<Recipient>, Box<dyn std::error::Error>> {
+    if content.trim_start().starts_with('[') {
+        #[derive(Deserialize)]
+        struct JsonRecipient {
+            address: String,
+            amount: u64,
+            #[serde(default)]
+            subtract_fee: bool,
+        }
+
+        let recipients: Vec<JsonRecipient> = serde_json::from_str(content)?;
+        return recipients
+            .into_iter()
+            .map(|r| -> Result<Recipient, Box<dyn std::error::Error>> {
+                let address = Address::from_str(&r.address)?.require_network(network)?;
+                Ok(Recipient { address, amount: r.amount, subtract_fee: r.subtract_fee })
+            })
+            .collect();
+    }
+
+    let mut recipients = Vec::<Recipient>::new();
+
+    for (line_number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split(',').map(|f| f.trim());
+
+        let (address, amount) = match (fields.next(), fields.next()) {
+            (Some(address), Some(amount)) => (address, amount),
+            _ => return Err(format!("Invalid line '{}', expected address,amount", line).into()),
+        };
+
+        let amount = match amount.parse::<u64>() {
+            Ok(amount) => amount,
+            // Skip a header row such as "address,amount", which has no address either
+            Err(_) if line_number == 0 && Address::from_str(address).is_err() => continue,
+            Err(e) => return Err(format!("Invalid amount '{}' on line {}: {}", amount, line_number + 1, e).into()),
+        };
+
+        let subtract_fee = match fields.next() {
+            Some(flag) => matches!(flag.to_lowercase().as_str(), "1" | "true" | "yes"),
+            None => false,
+        };
+
+        let address = Address::from_str(address)?.require_network(network)?;
+        recipients.push(Recipient { address, amount, subtract_fee });
+    }
+
+    Ok(recipients)
+}
+
+/// Dust limit of a P2TR output such as the wallet's change, which is the same for every key.
+pub fn p2tr_dust_value() -> u64 {
+    let output_key = XOnlyPublicKey::from_str(scripts::UNSPENDABLE_KEY).unwrap();
+
+    ScriptBuf::new_v1_p2tr_tweaked(TweakedPublicKey::dangerous_assume_tweaked(output_key)).dust_value().to_sat()
+}
+
+/// Selects the inputs and builds the outputs of one transaction paying every recipient, with a
+/// single change output. The fee is added on top unless some recipients have `subtract_fee` set,
+/// in which case it is split evenly between them (the first one also pays the remainder).
+/// The change is dropped and left to the miners when it would be dust.
+/// Returns the inputs, the outputs and the position of the change output.
+pub async fn prepare_payment(pool: &sqlx::Pool<Sqlite>, client: &electrum_client::Client, network: Network, recipients: &[Recipient], fees: u64) -> Result<(Vec::<AddressInfo>, Vec<TxOut>, Option<usize>), Box<dyn std::error::Error>> {
+    let mut outputs = payment_outputs(recipients, fees)?;
+
+    let amount: u64 = outputs.iter().map(|o| o.value).sum();
+
+    let list_unspent = get_list_unspent(pool, client, network).await;
+
+    let previous_outputs = select_coins(list_unspent, amount + fees).ok_or("Not enough funds")?;
+
+    let input_amount: u64 = previous_outputs.iter().map(|s| s.value).sum();
+
+    let change_amount = input_amount
+        .checked_sub(amount)
+        .and_then(|x| x.checked_sub(fees))
+        .ok_or("Fees more than input amount!")?;
+
+    let mut change_index = None;
+
+    // The change key is only derived when there is change, so dropped dust doesn't use one up.
+    if change_amount >= p2tr_dust_value() {
+        let (_, change_address, _) = addresses::generate_new_key(pool, network, true).await;
+
+        outputs.push(TxOut { value: change_amount, script_pubkey: change_address.script_pubkey() });
+        change_index = Some(outputs.len() - 1);
+    }
+
+    Ok((previous_outputs, outputs, change_index))
+}
+
+/// Builds one output per recipient, taking the fee out of the recipients with `subtract_fee`
+/// set, see `prepare_payment`.
+fn payment_outputs(recipients: &[Recipient], fees: u64) -> Result<Vec<TxOut>, Box<dyn std::error::Error>> {
+    if recipients.is_empty() {
+        return Err("No recipients".into());
+    }
+
+    let payers = recipients.iter().filter(|r| r.subtract_fee).count() as u64;
+
+    let mut outputs = Vec::<TxOut>::new();
+
+    let mut remainder = if payers > 0 { fees % payers } else { 0 };
+
+    for recipient in recipients {
+        let mut value = recipient.amount;
+
+        if recipient.subtract_fee {
+            let share = fees / payers + remainder;
+            remainder = 0;
+
+            value = value
+                .checked_sub(share)
+                .ok_or_else(|| format!("Fee share {} is more than the amount {} to {}", share, value, recipient.address))?;
+        }
+
+        let dust_value = recipient.address.script_pubkey().dust_value().to_sat();
+        if value < dust_value {
+            return Err(format!("Amount {} to {} is below the dust limit of {}", value, recipient.address, dust_value).into());
+        }
+
+        outputs.push(TxOut { value, script_pubkey: recipient.address.script_pubkey() });
+    }
+
+    Ok(outputs)
+}
+
+/// Builds and signs one transaction paying every recipient, see `prepare_payment`.
+pub async fn create_payment_tx(pool: &sqlx::Pool<Sqlite>, client: &electrum_client::Client, network: Network, recipients: &[Recipient], fees: u64, options: &TxOptions) -> Result<WalletTx, Box<dyn std::error::Error>> {
+    let (inputs_info, outputs, change_index) = prepare_payment(pool, client, network, recipients, fees).await?;
+
+    generate_p2tr_key_spend_tx(&inputs_info, &outputs, change_index, options)
+}
+
+/// Estimates the virtual size of a transaction spending `num_inputs` P2TR outputs
+/// through the key path, using a 64-byte placeholder signature for each witness.
+pub fn estimate_vsize(num_inputs: usize, outputs: &[TxOut]) -> u64 {
+    let mut witness = Witness::new();
+    witness.push([0u8; 64]);
+
+    let tx = Transaction {
+        version: 2,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn { witness, ..Default::default() }; num_inputs],
+        output: outputs.to_vec(),
+    };
+
+    tx.vsize() as u64
+}
+
+/// BIP44-style account of a `m/purpose'/coin_type'/account'/...` derivation path.
+fn derivation_account(derivation_path: &str) -> Option<u32> {
+    let derivation_path = DerivationPath::from_str(derivation_path).ok()?;
+
+    match derivation_path.as_ref().get(2) {
+        Some(ChildNumber::Hardened { index }) => Some(*index),
+        _ => None,
+    }
+}
+
+/// Spends every confirmed output (optionally only those of `from_addresses` and of `account`)
+/// to `to_address` without change, taking the fee for `fee_rate` (sat/vB) out of the swept amount.
+pub async fn create_sweep_tx(pool: &sqlx::Pool<Sqlite>, client: &electrum_client::Client, network: Network, to_address: &Address, fee_rate: u64, from_addresses: &[Address], account: Option<u32>, options: &TxOptions) -> Result<WalletTx, Box<dyn std::error::Error>> {
+    let list_unspent: Vec<AddressInfo> = get_list_unspent(pool, client, network).await
+        .into_iter()
+        .filter(|utxo| utxo.height > 0)
+        .filter(|utxo| from_addresses.is_empty() || from_addresses.contains(&utxo.address))
+        .filter(|utxo| account.is_none() || derivation_account(&utxo.derivation_path) == account)
+        .collect();
+
+    if list_unspent.is_empty() {
+        return Err("No confirmed outputs to sweep".into());
+    }
+
+    let input_amount: u64 = list_unspent.iter().map(|s| s.value).sum();
+
+    let mut outputs = vec![TxOut { value: 0, script_pubkey: to_address.script_pubkey() }];
+
+    let fees = estimate_vsize(list_unspent.len(), &outputs) * fee_rate;
+
+    let amount = input_amount.checked_sub(fees).ok_or("Fees more than input amount!")?;
+
+    let dust_value = to_address.script_pubkey().dust_value().to_sat();
+    if amount < dust_value {
+        return Err(format!("Amount {} after fees is below the dust limit of {}", amount, dust_value).into());
+    }
+
+    outputs[0].value = amount;
+
+    generate_p2tr_key_spend_tx(&list_unspent, &outputs, None, options)
+}
+
+/// Rebuilds the wallet transaction `txid` at `fee_rate` (sat/vB), keeping its inputs and payments.
+/// The extra fee comes out of the change output; more inputs (and a change output, if there was
+/// none) are added when the change is not enough.
+pub async fn create_bump_fee_tx(pool: &sqlx::Pool<Sqlite>, client: &electrum_client::Client, network: Network, txid: &Txid, fee_rate: u64, options: &TxOptions) -> Result<WalletTx, Box<dyn std::error::Error>> {
+    let original_tx = backend::get_transaction(client, txid);
+
+    let addresses = get_all_addresses_info(pool, network).await;
+    let change_addresses = get_change_addresses(pool, network).await;
+
+    let mut inputs_info = Vec::<AddressInfo>::new();
+
+    for input in &original_tx.input {
+        let previous_tx = backend::get_transaction(client, &input.previous_output.txid);
+        let previous_output = previous_tx.output
+            .get(input.previous_output.vout as usize)
+            .ok_or("Previous output not found")?;
+
+        let address = addresses
+            .iter()
+            .find(|a| a.0.script_pubkey() == previous_output.script_pubkey)
+            .ok_or_else(|| format!("Input {} is not owned by this wallet", input.previous_output))?;
+
+        // A confirmed transaction can't be replaced any more.
+        let confirmed = backend::get_address_history(client, &address.0)
+            .iter()
+            .any(|history| history.tx_hash == *txid && history.height > 0);
+
+        if confirmed {
+            return Err("The transaction is already confirmed".into());
+        }
+
+        inputs_info.push(AddressInfo {
+            address: address.0.clone(),
+            secret_key: address.4.clone(),
+            xonly_public_key: address.3.clone(),
+            fingerprint: address.1.clone(),
+            derivation_path: address.2.clone(),
+            height: 0,
+            tx_hash: input.previous_output.txid,
+            tx_pos: input.previous_output.vout as usize,
+            value: previous_output.value,
+        });
+    }
+
+    let input_amount: u64 = inputs_info.iter().map(|s| s.value).sum();
+    let output_amount: u64 = original_tx.output.iter().map(|o| o.value).sum();
+    let original_fee = input_amount.checked_sub(output_amount).ok_or("Outputs are more than inputs")?;
+
+    let mut outputs = original_tx.output.clone();
+
+    let mut change_script_pubkey = outputs
+        .iter()
+        .position(|o| change_addresses.iter().any(|a| a.script_pubkey() == o.script_pubkey))
+        .map(|index| outputs.remove(index).script_pubkey);
+
+    let payment_amount: u64 = outputs.iter().map(|o| o.value).sum();
+
+    // Outputs of the transaction being replaced can't fund its replacement, and BIP125 rule 2
+    // only allows new inputs that are confirmed.
+    let mut list_unspent: Vec<AddressInfo> = get_list_unspent(pool, client, network).await
+        .into_iter()
+        .filter(|utxo| utxo.tx_hash != *txid && utxo.height > 0)
+        .collect();
+    list_unspent.sort_by(|a, b| b.value.cmp(&a.value));
+    let mut list_unspent = list_unspent.into_iter();
+
+    loop {
+        let mut estimate_outputs = outputs.clone();
+        if let Some(script_pubkey) = &change_script_pubkey {
+            estimate_outputs.push(TxOut { value: 0, script_pubkey: script_pubkey.clone() });
+        }
+
+        let vsize = estimate_vsize(inputs_info.len(), &estimate_outputs);
+
+        // BIP125 rule 4: the replacement must also pay for its own relay at 1 sat/vB.
+        let fees = std::cmp::max(vsize * fee_rate, original_fee + vsize);
+
+        let input_amount: u64 = inputs_info.iter().map(|s| s.value).sum();
+
+        if let Some(change_amount) = input_amount.checked_sub(payment_amount + fees) {
+            let mut change_index = None;
+
+            if let Some(script_pubkey) = change_script_pubkey {
+                if change_amount >= script_pubkey.dust_value().to_sat() {
+                    outputs.push(TxOut { value: change_amount, script_pubkey });
+                    change_index = Some(outputs.len() - 1);
+                }
+            }
+
+            return generate_p2tr_key_spend_tx(&inputs_info, &outputs, change_index, options);
+        }
+
+        let utxo = list_unspent.next().ok_or("Not enough funds to bump the fee")?;
+        inputs_info.push(utxo);
+
+        if change_script_pubkey.is_none() {
+            let (_, change_address, _) = addresses::generate_new_key(pool, network, true).await;
+            change_script_pubkey = Some(change_address.script_pubkey());
+        }
+    }
+}
+
+/// Builds a child transaction spending the wallet outputs of the unconfirmed transaction `txid`
+/// back to the wallet, paying enough fee for the parent and child together to reach `fee_rate`
+/// (sat/vB). Other wallet coins are added when those outputs can't cover the fee.
+pub async fn create_cpfp_tx(pool: &sqlx::Pool<Sqlite>, client: &electrum_client::Client, network: Network, txid: &Txid, fee_rate: u64, options: &TxOptions) -> Result<WalletTx, Box<dyn std::error::Error>> {
+    let parent_tx = backend::get_transaction(client, txid);
+
+    let mut parent_input_amount: u64 = 0;
+    for input in &parent_tx.input {
+        let previous_tx = backend::get_transaction(client, &input.previous_output.txid);
+        let previous_output = previous_tx.output
+            .get(input.previous_output.vout as usize)
+            .ok_or("Previous output not found")?;
+        parent_input_amount += previous_output.value;
+    }
+
+    let parent_output_amount: u64 = parent_tx.output.iter().map(|o| o.value).sum();
+    let parent_fee = parent_input_amount.checked_sub(parent_output_amount).ok_or("Outputs are more than inputs")?;
+    let parent_vsize = parent_tx.vsize() as u64;
+
+    let (mut inputs_info, mut list_unspent): (Vec<AddressInfo>, Vec<AddressInfo>) = get_list_unspent(pool, client, network).await
+        .into_iter()
+        .partition(|utxo| utxo.tx_hash == *txid);
+
+    if inputs_info.is_empty() {
+        return Err("The transaction has no unspent output owned by this wallet".into());
+    }
+
+    if inputs_info.iter().any(|utxo| utxo.height > 0) {
+        return Err("The transaction is already confirmed".into());
+    }
+
+    list_unspent.retain(|utxo| utxo.height > 0);
+    list_unspent.sort_by(|a, b| b.value.cmp(&a.value));
+    let mut list_unspent = list_unspent.into_iter();
+
+    let (_, change_address, _) = addresses::generate_new_key(pool, network, true).await;
+
+    loop {
+        let mut outputs = vec![TxOut { value: 0, script_pubkey: change_address.script_pubkey() }];
+
+        let child_vsize = estimate_vsize(inputs_info.len(), &outputs);
+
+        // The child pays for the whole package, but never less than the minimum relay fee for itself.
+        let fees = std::cmp::max(
+            (fee_rate * (parent_vsize + child_vsize)).saturating_sub(parent_fee),
+            child_vsize,
+        );
+
+        let input_amount: u64 = inputs_info.iter().map(|s| s.value).sum();
+
+        if let Some(amount) = input_amount.checked_sub(fees) {
+            if amount >= change_address.script_pubkey().dust_value().to_sat() {
+                outputs[0].value = amount;
+
+                return generate_p2tr_key_spend_tx(&inputs_info, &outputs, Some(0), options);
+            }
+        }
+
+        let utxo = list_unspent.next().ok_or("Not enough funds to pay for the child transaction")?;
+        inputs_info.push(utxo);
+    }
+}
+
+pub async fn insert_replacement(pool: &sqlx::Pool<Sqlite>, original_txid: &Txid, replacement_txid: &Txid, fee_rate: u64, fee: u64) {
+    let query = "INSERT INTO tx_replacements (original_txid, replacement_txid, fee_rate, fee) VALUES ($1, $2, $3, $4)";
+
+    let _ = sqlx::query(query)
+        .bind(original_txid.to_string())
+        .bind(replacement_txid.to_string())
+        .bind(fee_rate as i64)
+        .bind(fee as i64)
+        .execute(pool)
+        .await
+        .unwrap();
+}
+
+/// Locktime and nSequence settings for the transactions built by the wallet.
+pub struct TxOptions {
+    pub lock_time: absolute::LockTime,
+    /// nSequence of every input not listed in `input_sequences`.
+    pub sequence: bitcoin::Sequence,
+    pub input_sequences: HashMap<OutPoint, bitcoin::Sequence>,
+    pub ordering: TxOrdering,
+}
+
+impl Default for TxOptions {
+    fn default() -> Self {
+        TxOptions {
+            lock_time: absolute::LockTime::ZERO,
+            sequence: bitcoin::Sequence::ENABLE_RBF_NO_LOCKTIME, // Signal RBF (BIP125).
+            input_sequences: HashMap::new(),
+            ordering: TxOrdering::Shuffle,
+        }
+    }
+}
+
+/// How inputs and outputs are ordered, so that the change can't be told apart by its position.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum TxOrdering {
+    /// Random order
+    Shuffle,
+    /// Lexicographic order (BIP69)
+    Bip69,
+    /// Keep the order in which they were added
+    Unordered,
+}
+
+/// A signed wallet transaction, with its fee and the position of its change output.
+pub struct WalletTx {
+    pub tx: Transaction,
+    pub fee: u64,
+    pub change_index: Option<usize>,
+}
+
+/// Anti-fee-sniping locktime, as done by Bitcoin Core: the current tip height, moved up to
+/// 100 blocks back one time in ten so that delayed transactions don't stand out.
+pub fn anti_fee_sniping_lock_time(tip_height: u32) -> absolute::LockTime {
+    let mut rng = rand::thread_rng();
+
+    let mut height = tip_height;
+    if rng.gen_range(0..10) == 0 {
+        height = height.saturating_sub(rng.gen_range(0..100));
+    }
+
+    absolute::LockTime::from_height(height).unwrap_or(absolute::LockTime::ZERO)
+}
+
+/// Builds the unsigned PSBT spending `inputs_info` through the key path, with the BIP371
+/// `tap_internal_key` and `tap_key_origins` fields and the `witness_utxo` of every input.
+/// Inputs and outputs are ordered according to `options`; the position of the change output
+/// after ordering is returned along with the PSBT.
+pub fn create_p2tr_key_spend_psbt(inputs_info: &Vec::<AddressInfo>, outputs: &Vec<TxOut>, change_index: Option<usize>, options: &TxOptions) -> Result<(Psbt, Option<usize>), Box<dyn std::error::Error>> {
+    let mut ordered_inputs: Vec<&AddressInfo> = inputs_info.iter().collect();
+    let mut ordered_outputs: Vec<(usize, &TxOut)> = outputs.iter().enumerate().collect();
+
+    match options.ordering {
+        TxOrdering::Shuffle => {
+            let mut rng = rand::thread_rng();
+            ordered_inputs.shuffle(&mut rng);
+            ordered_outputs.shuffle(&mut rng);
+        },
+        TxOrdering::Bip69 => {
+            // The txid string is in reversed byte order, as BIP69 requires.
+            ordered_inputs.sort_by(|a, b| a.tx_hash.to_string().cmp(&b.tx_hash.to_string()).then(a.tx_pos.cmp(&b.tx_pos)));
+            ordered_outputs.sort_by(|(_, a), (_, b)| a.value.cmp(&b.value).then(a.script_pubkey.as_bytes().cmp(b.script_pubkey.as_bytes())));
+        },
+        TxOrdering::Unordered => {},
     }
 
+    let change_index = change_index.and_then(|index| ordered_outputs.iter().position(|(i, _)| *i == index));
+
+    let inputs_info = &ordered_inputs;
+    let outputs: Vec<TxOut> = ordered_outputs.into_iter().map(|(_, output)| output.clone()).collect();
+
+    let mut tx_inputs = Vec::<bitcoin::TxIn>::new();
+
     for input in inputs_info {
         let input_utxo = OutPoint { txid: input.tx_hash, vout: input.tx_pos as u32 };
+        let sequence = options.input_sequences.get(&input_utxo).copied().unwrap_or(options.sequence);
         let input = TxIn {
             previous_output: input_utxo,
             script_sig: ScriptBuf::new(),
-            sequence: bitcoin::Sequence(0xFFFFFFFF), // Ignore nSequence.
+            sequence,
             witness: Witness::default(),
         };
         tx_inputs.push(input);
     }
 
+    if options.lock_time != absolute::LockTime::ZERO && tx_inputs.iter().all(|input| !input.sequence.enables_absolute_lock_time()) {
+        return Err("Locktime is ignored when every input has a final nSequence".into());
+    }
+
     let tx1 = Transaction {
         version: 2,
-        lock_time: absolute::LockTime::ZERO,
+        lock_time: options.lock_time,
         input: tx_inputs,
-        output: outputs.clone(),
+        output: outputs,
     };
-    let mut psbt = Psbt::from_unsigned_tx(tx1).unwrap();
+    let mut psbt = Psbt::from_unsigned_tx(tx1)?;
 
-    let mut origins = BTreeMap::new();
-    for input in inputs_info {
+    let mut psbt_inputs = Vec::<Input>::new();
+
+    for input_info in inputs_info {
+        let mut origins = BTreeMap::new();
         origins.insert(
-            input.xonly_public_key,
+            input_info.xonly_public_key,
             (
                 vec![],
                 (
-                    Fingerprint::from_str(&input.fingerprint).unwrap(),
-                    DerivationPath::from_str(&input.derivation_path).unwrap(),
+                    Fingerprint::from_str(&input_info.fingerprint)?,
+                    DerivationPath::from_str(&input_info.derivation_path)?,
                 ),
             ),
         );
-    }
 
-    let mut psbt_inputs = Vec::<Input>::new();
-
-    for input_info in inputs_info {
         let mut input = Input {
             witness_utxo: {
                 let script_pubkey = input_info.address.script_pubkey();
@@ -123,7 +679,7 @@ pub fn generate_p2tr_key_spend_tx(inputs_info: &Vec::<AddressInfo>, outputs: &Ve
     
                 Some(TxOut { value: amount.to_sat(), script_pubkey })
             },
-            tap_key_origins: origins.clone(),
+            tap_key_origins: origins,
             ..Default::default()
         };
         let ty = PsbtSighashType::from_str("SIGHASH_ALL").unwrap();
@@ -134,59 +690,23 @@ pub fn generate_p2tr_key_spend_tx(inputs_info: &Vec::<AddressInfo>, outputs: &Ve
 
     psbt.inputs = psbt_inputs;
 
+    Ok((psbt, change_index))
+}
+
+pub fn generate_p2tr_key_spend_tx(inputs_info: &Vec::<AddressInfo>, outputs: &Vec<TxOut>, change_index: Option<usize>, options: &TxOptions) -> Result<WalletTx, Box<dyn std::error::Error>> {
+    let (mut psbt, change_index) = create_p2tr_key_spend_psbt(inputs_info, outputs, change_index, options)?;
+
+    let mut secret_keys = HashMap::new();
+
+    for input in inputs_info {
+        secret_keys.insert(input.xonly_public_key, input.secret_key);
+    }
+
     // SIGNER
-    let unsigned_tx = psbt.unsigned_tx.clone();
-    psbt.inputs.iter_mut().enumerate().try_for_each::<_, Result<(), Box<dyn std::error::Error>>>(
-        |(vout, input)| {
-
-            let hash_ty = input
-                .sighash_type
-                .and_then(|psbt_sighash_type| psbt_sighash_type.taproot_hash_ty().ok())
-                .unwrap_or(TapSighashType::All);
-
-            let hash = SighashCache::new(&unsigned_tx).taproot_key_spend_signature_hash(
-                vout,
-                &sighash::Prevouts::All(&[TxOut {
-                    value: input.witness_utxo.as_ref().unwrap().value,
-                    script_pubkey: input.witness_utxo.as_ref().unwrap().script_pubkey.clone(),
-                }]),
-                hash_ty,
-            ).unwrap();
-
-            let (_, (_, derivation_path)) = input
-                .tap_key_origins
-                .get(&input.tap_internal_key.ok_or("Internal key missing in PSBT")?)
-                .ok_or("Missing taproot key origin").unwrap();
-
-            let secret_key = secret_keys.get(&input.tap_internal_key.ok_or("Internal key missing in PSBT")?).unwrap();
-
-            sign_psbt_taproot(
-                &secret_key,
-                input.tap_internal_key.unwrap(),
-                None,
-                input,
-                hash,
-                hash_ty,
-                &secp,
-            );
-
-            Ok(())
-        },
-    ).unwrap();
+    signer::sign_psbt(&mut psbt, &secret_keys)?;
 
     // FINALIZER
-    psbt.inputs.iter_mut().for_each(|input| {
-        let mut script_witness: Witness = Witness::new();
-        script_witness.push(input.tap_key_sig.unwrap().to_vec());
-        input.final_script_witness = Some(script_witness);
-
-        // Clear all the data fields as per the spec.
-        input.partial_sigs = BTreeMap::new();
-        input.sighash_type = None;
-        input.redeem_script = None;
-        input.witness_script = None;
-        input.bip32_derivation = BTreeMap::new();
-    });
+    signer::finalize_psbt(&mut psbt)?;
 
     let tx = psbt.extract_tx();
     
@@ -205,33 +725,140 @@ pub fn generate_p2tr_key_spend_tx(inputs_info: &Vec::<AddressInfo>, outputs: &Ve
         .expect("failed to verify transaction");
     }
 
-    Ok(tx)
+    let input_amount: u64 = inputs_info.iter().map(|s| s.value).sum();
+    let output_amount: u64 = tx.output.iter().map(|o| o.value).sum();
+    let fee = input_amount.checked_sub(output_amount).ok_or("Outputs are more than inputs")?;
+
+    Ok(WalletTx { tx, fee, change_index })
 
 }
 
-fn sign_psbt_taproot(
-    secret_key: &SecretKey,
-    pubkey: XOnlyPublicKey,
-    leaf_hash: Option<TapLeafHash>,
-    psbt_input: &mut psbt::Input,
-    hash: TapSighash,
-    hash_ty: TapSighashType,
-    secp: &Secp256k1<secp256k1::All>,
-) {
-    let keypair = secp256k1::KeyPair::from_seckey_slice(secp, secret_key.as_ref()).unwrap();
-    let keypair = match leaf_hash {
-        None => keypair.tap_tweak(secp, psbt_input.tap_merkle_root).to_inner(),
-        Some(_) => keypair, // no tweak for script spend
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let sig = secp.sign_schnorr(&hash.into(), &keypair);
+    use bitcoin::hashes::Hash;
+    use secp256k1_zkp::Secp256k1;
 
-    let final_signature = taproot::Signature { sig, hash_ty };
+    fn regtest_address() -> Address {
+        let secp = Secp256k1::new();
+        let (_, public_key) = secp.generate_keypair(&mut rand::thread_rng());
 
-    if let Some(lh) = leaf_hash {
-        psbt_input.tap_script_sigs.insert((pubkey, lh), final_signature);
-    } else {
-        psbt_input.tap_key_sig = Some(final_signature);
+        Address::p2tr(&secp, public_key.x_only_public_key().0, None, Network::Regtest)
     }
-}
 
+    fn recipient(amount: u64, subtract_fee: bool) -> Recipient {
+        Recipient { address: regtest_address(), amount, subtract_fee }
+    }
+
+    #[test]
+    fn csv_header_only_on_the_first_line() {
+        let (first, second) = (regtest_address(), regtest_address());
+
+        let content = format!("address,amount\n{},10000\n{},20000,true\n", first, second);
+        let recipients = parse_recipients(&content, Network::Regtest).unwrap();
+
+        assert_eq!(recipients.len(), 2);
+        assert_eq!((&recipients[0].address, recipients[0].amount, recipients[0].subtract_fee), (&first, 10000, false));
+        assert_eq!((&recipients[1].address, recipients[1].amount, recipients[1].subtract_fee), (&second, 20000, true));
+
+        // Without a header the first line is a recipient like the others.
+        let content = format!("{},10000\n", first);
+        assert_eq!(parse_recipients(&content, Network::Regtest).unwrap().len(), 1);
+
+        // A header-like row anywhere else is an error, not a skipped line.
+        let content = format!("{},10000\naddress,amount\n", first);
+        let error = parse_recipients(&content, Network::Regtest).err().unwrap();
+        assert_eq!(error.to_string(), "Invalid amount 'amount' on line 2: invalid digit found in string");
+
+        // A bad amount on the first line is an error when the address is valid.
+        let content = format!("{},ten\n", first);
+        assert!(parse_recipients(&content, Network::Regtest).is_err());
+    }
+
+    #[test]
+    fn fee_remainder_goes_to_the_first_payer() {
+        let recipients = vec![
+            recipient(10_000, false),
+            recipient(20_000, true),
+            recipient(30_000, true),
+            recipient(40_000, true),
+        ];
+
+        // 1000 split three ways is 333 each, plus the remainder of 1 for the first payer.
+        let outputs = payment_outputs(&recipients, 1000).unwrap();
+
+        let values: Vec<u64> = outputs.iter().map(|o| o.value).collect();
+        assert_eq!(values, vec![10_000, 20_000 - 334, 30_000 - 333, 40_000 - 333]);
+
+        for (output, recipient) in outputs.iter().zip(&recipients) {
+            assert_eq!(output.script_pubkey, recipient.address.script_pubkey());
+        }
+
+        // Without payers the fee is added on top.
+        let recipients = vec![recipient(10_000, false)];
+        assert_eq!(payment_outputs(&recipients, 1000).unwrap()[0].value, 10_000);
+
+        let recipients = vec![recipient(1000, true)];
+        assert!(payment_outputs(&recipients, 1000).is_err());
+    }
+
+    #[test]
+    fn bip69_ordering() {
+        let secp = Secp256k1::new();
+
+        let input = |txid: u8, tx_pos: usize| {
+            let (secret_key, public_key) = secp.generate_keypair(&mut rand::thread_rng());
+            let xonly_public_key = public_key.x_only_public_key().0;
+
+            AddressInfo {
+                address: Address::p2tr(&secp, xonly_public_key, None, Network::Regtest),
+                secret_key,
+                xonly_public_key,
+                fingerprint: "00000000".to_string(),
+                derivation_path: "m/86h/1h/0h/0/0".to_string(),
+                height: 1,
+                tx_hash: Txid::from_byte_array([txid; 32]),
+                tx_pos,
+                value: 50_000,
+            }
+        };
+
+        let inputs_info = vec![input(0xbb, 0), input(0xaa, 1), input(0xaa, 0)];
+
+        let (low, high) = {
+            let (a, b) = (regtest_address().script_pubkey(), regtest_address().script_pubkey());
+            if a.as_bytes() < b.as_bytes() { (a, b) } else { (b, a) }
+        };
+
+        let outputs = vec![
+            TxOut { value: 5000, script_pubkey: high.clone() },
+            TxOut { value: 3000, script_pubkey: high.clone() },
+            TxOut { value: 5000, script_pubkey: low.clone() },
+        ];
+
+        let options = TxOptions { ordering: TxOrdering::Bip69, ..Default::default() };
+
+        let (psbt, change_index) = create_p2tr_key_spend_psbt(&inputs_info, &outputs, Some(0), &options).unwrap();
+
+        let previous_outputs: Vec<OutPoint> = psbt.unsigned_tx.input.iter().map(|input| input.previous_output).collect();
+        assert_eq!(previous_outputs, vec![
+            OutPoint::new(Txid::from_byte_array([0xaa; 32]), 0),
+            OutPoint::new(Txid::from_byte_array([0xaa; 32]), 1),
+            OutPoint::new(Txid::from_byte_array([0xbb; 32]), 0),
+        ]);
+
+        // The PSBT inputs follow the transaction inputs.
+        assert_eq!(psbt.inputs[0].tap_internal_key, Some(inputs_info[2].xonly_public_key));
+        assert_eq!(psbt.inputs[2].tap_internal_key, Some(inputs_info[0].xonly_public_key));
+
+        assert_eq!(psbt.unsigned_tx.output, vec![
+            TxOut { value: 3000, script_pubkey: high.clone() },
+            TxOut { value: 5000, script_pubkey: low },
+            TxOut { value: 5000, script_pubkey: high },
+        ]);
+
+        // The change output was the first one, now it is the last.
+        assert_eq!(change_index, Some(2));
+    }
+}